@@ -1,31 +1,23 @@
-struct Uniforms {
-    modelViewProjectionMat: mat4x4<f32>
-};
-
-@group(0) @binding(0) var<uniform> uniforms: Uniforms;
-
-struct VertexInput {
-    @location(0) position: vec3<f32>,
-    @location(1) color: vec3<f32>,
-};
-
-struct VertexOutput {
-    @builtin(position) position: vec4<f32>,
-    @location(0) color: vec4<f32>,
-}
+#import fathom::camera
+#import fathom::transforms
+#import fathom::mesh
+#import fathom::material
 
 @vertex
 fn vertex_main(vertex_in: VertexInput) -> VertexOutput {
     var vertex_out: VertexOutput;
-    vertex_out.position = uniforms.modelViewProjectionMat * vec4<f32>(vertex_in.position, 1.0);
-    vertex_out.color = vec4<f32>(vertex_in.color, 1.0);
+    vertex_out.position = camera.viewProjectionMat * model_to_world(vertex_in.position);
+    vertex_out.color = model.tint;
+#ifdef VERTEX_COLOR
+    vertex_out.color *= vec4<f32>(vertex_in.color, 1.0);
+#endif
+#ifdef VERTEX_UV_0
+    vertex_out.uv = vertex_in.uv;
+#endif
     return vertex_out;
 }
 
 @fragment
 fn fragment_main(vertex_in: VertexOutput) -> @location(0) vec4<f32> {
-    return vertex_in.color;
+    return vertex_in.color * textureSample(diffuse_texture, diffuse_sampler, vertex_in.uv);
 }
-
-
-
@@ -1,13 +1,4 @@
-struct VertexInput {
-    @location(0) position: vec2<f32>,
-    @location(1) color: vec3<f32>,
-};
-
-struct VertexOutput {
-    @builtin(position) position: vec4<f32>,
-    @location(0) color: vec4<f32>,
-};
-
+#import fathom::mesh2d
 
 @vertex
 fn vertex_main(vertex_in: VertexInput) -> VertexOutput{
@@ -20,4 +11,4 @@ fn vertex_main(vertex_in: VertexInput) -> VertexOutput{
 @fragment
 fn fragment_main(vertex_in: VertexOutput) -> @location(0) vec4<f32> {
     return vertex_in.color;
-}
\ No newline at end of file
+}
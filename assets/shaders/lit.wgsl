@@ -0,0 +1,43 @@
+// Blinn-Phong lit material. The diffuse color comes from the material's properties and diffuse
+// texture rather than the vertex colors. Meshes without normals only receive ambient light
+#import fathom::camera
+#import fathom::transforms
+#import fathom::mesh
+#import fathom::material
+#import fathom::lighting
+
+@vertex
+fn vertex_main(vertex_in: VertexInput) -> VertexOutput {
+    var vertex_out: VertexOutput;
+    let world_position = model_to_world(vertex_in.position);
+    vertex_out.position = camera.viewProjectionMat * world_position;
+    vertex_out.world_position = world_position.xyz;
+    vertex_out.color = model.tint;
+#ifdef VERTEX_UV_0
+    vertex_out.uv = vertex_in.uv;
+#endif
+#ifdef VERTEX_NORMAL
+    vertex_out.world_normal = normal_to_world(vertex_in.normal);
+#endif
+    return vertex_out;
+}
+
+@fragment
+fn fragment_main(vertex_in: VertexOutput) -> @location(0) vec4<f32> {
+    let base_color = vertex_in.color * textureSample(diffuse_texture, diffuse_sampler, vertex_in.uv);
+    let diffuse_color = material.diffuse_color.rgb * base_color.rgb;
+    var color = lights.ambient_color.rgb * material.ambient_color.rgb * diffuse_color + material.emissive_color.rgb;
+#ifdef VERTEX_NORMAL
+    let normal = normalize(vertex_in.world_normal);
+    let view_direction = normalize(camera.position.xyz - vertex_in.world_position);
+    color += apply_lights(
+        vertex_in.world_position,
+        normal,
+        view_direction,
+        diffuse_color,
+        material.specular_color.rgb,
+        material.specular_color.w,
+    );
+#endif
+    return vec4<f32>(color, base_color.a * material.diffuse_color.a);
+}
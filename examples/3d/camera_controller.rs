@@ -1,12 +1,15 @@
 use bevy::prelude::*;
-use winit::event::{ElementState, KeyEvent};
-use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::keyboard::KeyCode;
 use fathom::app::{schedule, FathomApplication};
-use fathom::assets::shaders::Shader;
-use fathom::input::InputEvent;
+use fathom::input::KeyboardState;
+use fathom::time::Time;
 use fathom::renderer::camera::Camera;
 use fathom::renderer::mesh::Mesh;
 use fathom::renderer::vertex::Vertex;
+use fathom::transform::Transform;
+
+/// Units per second
+const CAMERA_SPEED: f32 = 3.0;
 
 fn main() {
     let mut app = FathomApplication::with_3d_renderer();
@@ -19,12 +22,8 @@ fn main() {
 
 fn startup(
     mut commands: Commands,
-    asset_server: Res<AssetServer>,
 ) {
-    let shader_handle: Handle<Shader> = asset_server.load("shaders/default.wgsl");
     commands.spawn(Mesh::with_indices(
-        shader_handle.clone(),
-        shader_handle.clone(),
         vec![
             Vertex { position: [-1.0, -1.0,  1.0], color: [1.0, 0.0, 1.0] },
             Vertex { position: [ 1.0, -1.0,  1.0], color: [1.0, 0.0, 1.0] },
@@ -45,32 +44,30 @@ fn startup(
         ]
     ));
 
-    commands.spawn(Camera {
-        transform: Mat4::look_at_rh(Vec3::new(5.0, 0.0, 5.0), Vec3::ZERO, Vec3::Y).inverse()
-    });
+    commands.spawn((
+        Camera::default(),
+        Transform::from_xyz(5.0, 0.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
 
 }
 
 fn update(
-    mut camera_query: Query<&mut Camera>,
-    mut input_events: EventReader<InputEvent>
+    mut camera_query: Query<&mut Transform, With<Camera>>,
+    keyboard_state: Res<KeyboardState>,
+    time: Res<Time>,
 ) {
-    let mut camera = camera_query.single_mut();
-    for InputEvent::Keyboard(KeyEvent { physical_key,  state, ..}) in input_events.read() {
-        match (physical_key, state) {
-            (PhysicalKey::Code(KeyCode::KeyW), ElementState::Pressed) => {
-                camera.transform.w_axis.z -= 0.5;
-            },
-            (PhysicalKey::Code(KeyCode::KeyS), ElementState::Pressed) => {
-                camera.transform.w_axis.z += 0.5;
-            },
-            (PhysicalKey::Code(KeyCode::KeyA), ElementState::Pressed) => {
-                camera.transform.w_axis.x -= 0.5;
-            },
-            (PhysicalKey::Code(KeyCode::KeyD), ElementState::Pressed) => {
-                camera.transform.w_axis.x += 0.5;
-            },
-            _ => ()
-        }
+    let mut camera_transform = camera_query.single_mut();
+    let distance = CAMERA_SPEED * time.delta_secs();
+    if keyboard_state.pressed(KeyCode::KeyW) {
+        camera_transform.translation.z -= distance;
+    }
+    if keyboard_state.pressed(KeyCode::KeyS) {
+        camera_transform.translation.z += distance;
     }
-}
\ No newline at end of file
+    if keyboard_state.pressed(KeyCode::KeyA) {
+        camera_transform.translation.x -= distance;
+    }
+    if keyboard_state.pressed(KeyCode::KeyD) {
+        camera_transform.translation.x += distance;
+    }
+}
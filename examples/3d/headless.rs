@@ -0,0 +1,35 @@
+use bevy::prelude::*;
+use fathom::app::{schedule, FathomApplication, HeadlessSettings};
+use fathom::renderer::camera::Camera;
+use fathom::renderer::mesh::Mesh;
+use fathom::renderer::vertex::Vertex;
+use fathom::transform::Transform;
+
+fn main() {
+    env_logger::builder().filter_level(log::LevelFilter::Debug).init();
+    let mut app = FathomApplication::headless_with_3d_renderer(HeadlessSettings {
+        frame_count: Some(10),
+        ..default()
+    });
+
+    app.add_systems(schedule::Startup, startup);
+
+    let _ = app.run();
+}
+
+fn startup(mut commands: Commands) {
+    commands.spawn(Mesh::with_indices(
+        vec![
+            Vertex { position: [-1.0, -1.0, 0.0], color: [1.0, 0.0, 0.0] },
+            Vertex { position: [ 1.0, -1.0, 0.0], color: [0.0, 1.0, 0.0] },
+            Vertex { position: [ 1.0,  1.0, 0.0], color: [0.0, 0.0, 1.0] },
+            Vertex { position: [-1.0,  1.0, 0.0], color: [1.0, 1.0, 1.0] },
+        ],
+        vec![0, 1, 2, 2, 3, 0],
+    ));
+
+    commands.spawn((
+        Camera::default(),
+        Transform::from_xyz(0.0, 0.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+}
@@ -4,6 +4,7 @@ use fathom::assets::shaders::Shader;
 use fathom::renderer::camera::Camera;
 use fathom::renderer::mesh::Mesh;
 use fathom::renderer::vertex::Vertex;
+use fathom::transform::Transform;
 
 fn main() {
     env_logger::builder().filter_level(log::LevelFilter::Trace).init();
@@ -40,8 +41,9 @@ fn startup(
         ]
     ));
 
-    commands.spawn(Camera {
-        transform: Mat4::look_at_rh(Vec3::new(5.0, 0.0, 5.0), Vec3::ZERO, Vec3::Y).inverse()
-    });
+    commands.spawn((
+        Camera::default(),
+        Transform::from_xyz(5.0, 0.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
 
 }
\ No newline at end of file
@@ -0,0 +1,91 @@
+use bevy::prelude::*;
+use fathom::app::{schedule, FathomApplication};
+use fathom::renderer::camera::{Camera, CameraClear, RenderLayers};
+use fathom::renderer::mesh::Mesh;
+use fathom::renderer::vertex::Vertex;
+use fathom::renderer::viewport::Viewport;
+use fathom::time::Time;
+use fathom::transform::Transform;
+
+/// Radians per second
+const ROTATION_SPEED: f32 = 1.0;
+
+#[derive(Component)]
+struct Spinning;
+
+fn main() {
+    let mut app = FathomApplication::with_3d_renderer();
+
+    app.add_systems(schedule::Startup, startup);
+    app.add_systems(schedule::Update, spin);
+
+    let _ = app.run();
+}
+
+fn cube(color: [f32; 3]) -> Mesh {
+    Mesh::with_indices(
+        vec![
+            Vertex { position: [-1.0, -1.0,  1.0], color },
+            Vertex { position: [ 1.0, -1.0,  1.0], color },
+            Vertex { position: [ 1.0,  1.0,  1.0], color },
+            Vertex { position: [-1.0,  1.0,  1.0], color },
+            Vertex { position: [-1.0, -1.0, -1.0], color: [0.0, 0.0, 0.0] },
+            Vertex { position: [ 1.0, -1.0, -1.0], color: [0.0, 0.0, 0.0] },
+            Vertex { position: [ 1.0,  1.0, -1.0], color: [0.0, 0.0, 0.0] },
+            Vertex { position: [-1.0,  1.0, -1.0], color: [0.0, 0.0, 0.0] },
+        ],
+        vec![
+            0, 1, 2, 2, 3, 0,
+            4, 5, 6, 6, 7, 4,
+            4, 0, 3, 3, 7, 4,
+            1, 5, 6, 6, 2, 1,
+            3, 2, 6, 6, 7, 3,
+            4, 5, 1, 1, 0, 4,
+        ],
+    )
+}
+
+fn startup(mut commands: Commands) {
+    commands.spawn((cube([1.0, 0.0, 1.0]), Spinning));
+    // Only seen by the picture-in-picture camera
+    commands.spawn((
+        cube([0.0, 1.0, 1.0]),
+        RenderLayers::layer(1),
+        Transform::from_xyz(0.0, 3.0, 0.0).with_scale(Vec3::splat(0.5)),
+    ));
+
+    // Left and right halves of the window
+    commands.spawn((
+        Camera {
+            viewport: Some(Viewport::new(0.0, 0.0, 0.5, 1.0)),
+            ..default()
+        },
+        Transform::from_xyz(5.0, 0.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+    commands.spawn((
+        Camera {
+            viewport: Some(Viewport::new(0.5, 0.0, 0.5, 1.0)),
+            clear: CameraClear::Color(wgpu::Color { r: 0.05, g: 0.05, b: 0.2, a: 1.0 }),
+            ..default()
+        },
+        Transform::from_xyz(-5.0, 2.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+
+    // Drawn last, over the top right corner of the other two
+    commands.spawn((
+        Camera {
+            viewport: Some(Viewport::new(0.7, 0.05, 0.25, 0.25)),
+            order: 1,
+            clear: CameraClear::Color(wgpu::Color { r: 0.2, g: 0.05, b: 0.05, a: 1.0 }),
+            layers: RenderLayers::layer(0).with(1),
+            ..default()
+        },
+        Transform::from_xyz(0.0, 8.0, 0.1).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+}
+
+fn spin(mut spinning: Query<&mut Transform, With<Spinning>>, time: Res<Time>) {
+    for mut transform in &mut spinning {
+        transform.rotate(Quat::from_rotation_y(ROTATION_SPEED * time.delta_secs()));
+    }
+}
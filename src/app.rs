@@ -3,36 +3,54 @@ use bevy::ecs::event::{event_update_condition, event_update_system, EventUpdates
 use bevy::ecs::schedule::ScheduleLabel;
 use bevy::prelude::*;
 use winit::application::ApplicationHandler;
-use winit::event::{WindowEvent};
+use winit::event::{DeviceEvent, DeviceId, WindowEvent};
 use winit::event_loop::ActiveEventLoop;
 use log::info;
-use crate::app::schedule::{Render, PreRender, Initialization};
+use crate::app::schedule::{Render, PreRender, Initialization, First};
 use crate::assets::{initialize_asset_server, tick_task_pools};
 use crate::FathomDefaultPlugins;
-use crate::input::InputEvent;
-use crate::renderer::{add_default_2d_render_resources, add_default_render_resources, initialize_render_resources, initialize_renderer, pre_render, default_3d_render_pass, render2d, Fathom3DRenderPlugin, Fathom2DRenderPlugin};
+use crate::input::{update_input_state, InputEvent, KeyboardState, MouseState};
+use crate::renderer::{add_default_2d_render_resources, add_default_render_resources, initialize_render_resources, initialize_renderer, default_3d_render_pass, render2d, Fathom3DRenderPlugin, Fathom2DRenderPlugin};
 use crate::renderer::mesh::{setup_on_add_hook_for_mesh, setup_on_add_hook_for_mesh2d};
 
 pub struct FathomApplication;
 
 impl FathomApplication {
     pub fn new() -> App {
+        Self::build(None)
+    }
+
+    /// Creates an application without a window, for running on CI or servers. The renderer draws
+    /// into an offscreen texture instead of a window surface
+    pub fn headless(settings: HeadlessSettings) -> App {
+        Self::build(Some(settings))
+    }
+
+    fn build(headless: Option<HeadlessSettings>) -> App {
         let mut app = App::empty();
         app.main_mut().update_schedule = Some(schedule::Main.intern());
         app.init_resource::<AppTypeRegistry>();
-        app.add_systems(
-            First,
-            event_update_system
-                .in_set(EventUpdates)
-                .run_if(event_update_condition),
-        );
         app.add_event::<AppExit>();
         app.add_event::<InputEvent>();
+        app.add_event::<WindowResized>();
+        app.init_resource::<KeyboardState>();
+        app.init_resource::<MouseState>();
 
         // Disable the Fathom3DRenderPlugin because this function should return a barebones
         // fathom application but don't want to modify FathomDefaultPlugins itself
         app.add_plugins(
-            FathomDefaultPlugins.build()
+            FathomDefaultPlugins { headless }.build()
+        );
+
+        // These are added after FathomDefaultPlugins because it creates the First schedule
+        app.add_systems(
+            First,
+            (
+                event_update_system
+                    .in_set(EventUpdates)
+                    .run_if(event_update_condition),
+                update_input_state.after(EventUpdates),
+            )
         );
 
         app
@@ -49,12 +67,57 @@ impl FathomApplication {
         app.add_plugins(Fathom2DRenderPlugin);
         app
     }
+
+    pub fn headless_with_3d_renderer(settings: HeadlessSettings) -> App {
+        let mut app = Self::headless(settings);
+        app.add_plugins(Fathom3DRenderPlugin);
+        app
+    }
+
+    pub fn headless_with_2d_renderer(settings: HeadlessSettings) -> App {
+        let mut app = Self::headless(settings);
+        app.add_plugins(Fathom2DRenderPlugin);
+        app
+    }
+}
+
+/// Configures an application created with [`FathomApplication::headless`]
+#[derive(Resource, Clone, Debug)]
+pub struct HeadlessSettings {
+    /// Size of the offscreen texture that is rendered to in place of a window
+    pub width: u32,
+    pub height: u32,
+    /// Number of frames to run before exiting. `None` runs until an [`AppExit`] event is sent
+    pub frame_count: Option<u32>,
+    /// Only use a software adapter, even when a GPU is available. A software adapter is always
+    /// used when no GPU is available
+    pub force_fallback_adapter: bool,
+}
+
+impl Default for HeadlessSettings {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 600,
+            frame_count: Some(1),
+            force_fallback_adapter: false,
+        }
+    }
 }
 
 pub struct WinitApplicationState {
     app: App,
 }
 
+/// Sent whenever the physical size of the window changes, either because it was resized or because
+/// its scale factor changed (e.g. it was moved to a display with a different DPI)
+#[derive(Event, Debug, Clone, Copy)]
+pub struct WindowResized {
+    pub width: u32,
+    pub height: u32,
+    pub scale_factor: f64,
+}
+
 #[derive(Resource)]
 pub struct WindowState {
     window: Arc<winit::window::Window>,
@@ -87,13 +150,56 @@ impl ApplicationHandler for WinitApplicationState {
             WindowEvent::RedrawRequested => {
                 self.app.update();
             }
+            WindowEvent::Resized(size) => {
+                let scale_factor = self.app.world().resource::<WindowState>().window().scale_factor();
+                self.app.world_mut().send_event(WindowResized {
+                    width: size.width,
+                    height: size.height,
+                    scale_factor,
+                });
+            }
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                let size = self.app.world().resource::<WindowState>().window().inner_size();
+                self.app.world_mut().send_event(WindowResized {
+                    width: size.width,
+                    height: size.height,
+                    scale_factor,
+                });
+            }
             WindowEvent::KeyboardInput { event: key_event, ..} => {
                 self.app.world_mut().send_event(InputEvent::Keyboard(key_event));
             }
+            WindowEvent::MouseInput { button, state, .. } => {
+                self.app.world_mut().send_event(InputEvent::MouseButton { button, state });
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.app.world_mut().send_event(InputEvent::CursorMoved { position });
+            }
+            WindowEvent::CursorEntered { .. } => {
+                self.app.world_mut().send_event(InputEvent::CursorEntered);
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.app.world_mut().send_event(InputEvent::CursorLeft);
+            }
+            WindowEvent::MouseWheel { delta, phase, .. } => {
+                self.app.world_mut().send_event(InputEvent::MouseWheel { delta, phase });
+            }
+            WindowEvent::Touch(touch) => {
+                self.app.world_mut().send_event(InputEvent::Touch(touch));
+            }
+            WindowEvent::Focused(false) => {
+                self.app.world_mut().send_event(InputEvent::FocusLost);
+            }
             _ => ()
         };
     }
 
+    fn device_event(&mut self, _event_loop: &ActiveEventLoop, _device_id: DeviceId, event: DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta } = event {
+            self.app.world_mut().send_event(InputEvent::MouseMotion { delta });
+        }
+    }
+
     fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
         let window = self.app.world().resource::<WindowState>().window();
         window.request_redraw();
@@ -143,6 +249,16 @@ pub mod schedule {
     #[derive(ScheduleLabel, Clone, Debug, Eq, PartialEq, Hash)]
     pub struct First;
 
+    /// This schedule runs FixedUpdate zero or more times, depending on how much time has passed
+    /// since the last frame. See [`crate::time::FixedTime`]
+    #[derive(ScheduleLabel, Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct FixedMain;
+
+    /// This schedule gets run at a fixed rate, independent of the frame rate. Gameplay systems that
+    /// need to be deterministic (e.g. physics) should go here
+    #[derive(ScheduleLabel, Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct FixedUpdate;
+
     /// This schedule gets run before the render schedule. Systems that do not impact rendering should go here
     #[derive(ScheduleLabel, Clone, Debug, Eq, PartialEq, Hash)]
     pub struct Update;
@@ -155,6 +271,12 @@ pub mod schedule {
     #[derive(ScheduleLabel, Clone, Debug, Eq, PartialEq, Hash)]
     pub struct Render;
 
+    /// This schedule gets run by Render once for every camera, in the cameras' order. Systems that
+    /// draw what a camera sees go here and read the camera from
+    /// [`CurrentCamera`](crate::renderer::camera::CurrentCamera)
+    #[derive(ScheduleLabel, Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct RenderCamera;
+
     /// This schedule is always run last
     #[derive(ScheduleLabel, Clone, Debug, Eq, PartialEq, Hash)]
     pub struct Last;
@@ -172,6 +294,7 @@ pub mod schedule {
             Self {
                 non_startup_labels: vec![
                     First.intern(),
+                    FixedMain.intern(),
                     Update.intern(),
                     PreRender.intern(),
                     Render.intern(),
@@ -0,0 +1,113 @@
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::asset::io::Reader;
+use ::image::imageops::FilterType;
+use ::image::{DynamicImage, ImageBuffer, Pixel};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::renderer::texture::{Image, ImageSampler};
+
+#[derive(Default)]
+pub struct ImageAssetLoader;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageLoaderSettings {
+    /// Whether the image stores sRGB colors. Should be false for data such as normal maps.
+    /// Ignored for HDR images, which are always linear
+    pub is_srgb: bool,
+    /// Generates the full mip chain when the image is loaded
+    pub generate_mipmaps: bool,
+    pub sampler: ImageSampler,
+}
+
+impl Default for ImageLoaderSettings {
+    fn default() -> Self {
+        Self {
+            is_srgb: true,
+            generate_mipmaps: true,
+            sampler: ImageSampler::default(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ImageAssetLoaderError {
+    #[error("Error occurred while reading image: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to decode image: {0}")]
+    Decode(#[from] ::image::ImageError),
+}
+
+impl AssetLoader for ImageAssetLoader {
+    type Asset = Image;
+    type Settings = ImageLoaderSettings;
+    type Error = ImageAssetLoaderError;
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        settings: &ImageLoaderSettings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        log::debug!("Loading image using ImageAssetLoader, asset path={:?}", load_context.path());
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let decoded = ::image::load_from_memory(&bytes)?;
+        let (width, height) = (decoded.width(), decoded.height());
+
+        let image = match decoded {
+            // HDR images are uploaded as half floats, since 32 bit float textures can not be
+            // filtered without an optional device feature
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                let mip_levels = mip_chain(decoded.into_rgba32f(), settings.generate_mipmaps);
+                let data = mip_levels.iter()
+                    .flat_map(|mip_level| mip_level.as_raw())
+                    .flat_map(|channel| half::f16::from_f32(*channel).to_le_bytes())
+                    .collect();
+                Image {
+                    mip_level_count: mip_levels.len() as u32,
+                    ..Image::new(width, height, wgpu::TextureFormat::Rgba16Float, data)
+                }
+            }
+            _ => {
+                let format = if settings.is_srgb {
+                    wgpu::TextureFormat::Rgba8UnormSrgb
+                } else {
+                    wgpu::TextureFormat::Rgba8Unorm
+                };
+                let mip_levels = mip_chain(decoded.into_rgba8(), settings.generate_mipmaps);
+                let data = mip_levels.iter()
+                    .flat_map(|mip_level| mip_level.as_raw())
+                    .copied()
+                    .collect();
+                Image {
+                    mip_level_count: mip_levels.len() as u32,
+                    ..Image::new(width, height, format, data)
+                }
+            }
+        };
+
+        Ok(image.with_sampler(settings.sampler))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["png", "jpg", "jpeg", "hdr"]
+    }
+}
+
+/// Returns the image followed by each mip level down to 1x1, each half the size of the previous
+fn mip_chain<P>(image: ImageBuffer<P, Vec<P::Subpixel>>, generate_mipmaps: bool) -> Vec<ImageBuffer<P, Vec<P::Subpixel>>>
+where
+    P: Pixel + 'static,
+{
+    let mut mip_levels = vec![image];
+    if !generate_mipmaps {
+        return mip_levels;
+    }
+
+    while let Some(previous) = mip_levels.last().filter(|previous| previous.width() > 1 || previous.height() > 1) {
+        let width = (previous.width() / 2).max(1);
+        let height = (previous.height() / 2).max(1);
+        let mip_level = ::image::imageops::resize(previous, width, height, FilterType::Triangle);
+        mip_levels.push(mip_level);
+    }
+    mip_levels
+}
@@ -1,10 +1,155 @@
+use bevy::asset::AssetPath;
 use bevy::prelude::*;
+use crate::assets::shaders::preprocessor::ShaderDef;
 use crate::assets::shaders::Shader;
-use crate::renderer::pipeline::PipelineId;
+use crate::renderer::texture::Image;
+use crate::renderer::vertex::VertexLayout;
 
+/// The shaders, fixed function state and surface parameters a mesh is drawn with. A render
+/// pipeline is created for each distinct combination of shaders and pipeline state the first time
+/// a material needs it, so materials that only differ in their properties share a pipeline
 #[derive(Asset, TypePath)]
 pub struct Material {
     pub vertex_shader: Handle<Shader>,
     pub fragment_shader: Handle<Shader>,
-    pub material_pipeline_id: PipelineId
-}
\ No newline at end of file
+    pub vertex_layout: VertexLayout,
+    /// `None` replaces the color target instead of blending with it
+    pub blend: Option<wgpu::BlendState>,
+    pub cull_mode: Option<wgpu::Face>,
+    /// Whether fragments are tested against the depth buffer. 2D materials must disable this since
+    /// 2D passes do not have a depth buffer
+    pub depth_test: bool,
+    pub depth_write: bool,
+    /// Defs both shaders are composed with, checked by `#ifdef` in the shader source
+    pub shader_defs: Vec<ShaderDef>,
+    /// Bound at [`MATERIAL_BIND_GROUP_INDEX`](crate::renderer::material::MATERIAL_BIND_GROUP_INDEX)
+    /// along with its sampler. Materials without a texture are bound with a 1x1 white texture
+    pub diffuse_texture: Option<Handle<Image>>,
+    pub properties: MaterialProperties,
+}
+
+impl Material {
+    /// A 3D material with opaque blending, no culling and depth testing enabled
+    pub fn new(vertex_shader: Handle<Shader>, fragment_shader: Handle<Shader>) -> Self {
+        Self {
+            vertex_shader,
+            fragment_shader,
+            vertex_layout: VertexLayout::Vertex3D,
+            blend: None,
+            cull_mode: None,
+            depth_test: true,
+            depth_write: true,
+            shader_defs: Vec::new(),
+            diffuse_texture: None,
+            properties: Default::default(),
+        }
+    }
+
+    /// A 3D material lit by the scene's lights with Blinn-Phong shading, using the material's
+    /// properties as its surface parameters. `lit_shader` is usually
+    /// [`DEFAULT_LIT_SHADER`](crate::assets::shaders::DEFAULT_LIT_SHADER). Meshes drawn with it need
+    /// normals to receive light other than ambient light
+    pub fn lit(lit_shader: Handle<Shader>) -> Self {
+        Self::new(lit_shader.clone(), lit_shader)
+    }
+
+    /// A 2D material, which uses the 2D vertex layout and does not use the depth buffer
+    pub fn new_2d(vertex_shader: Handle<Shader>, fragment_shader: Handle<Shader>) -> Self {
+        Self {
+            vertex_layout: VertexLayout::Vertex2D,
+            depth_test: false,
+            depth_write: false,
+            ..Self::new(vertex_shader, fragment_shader)
+        }
+    }
+
+    pub fn with_vertex_layout(mut self, vertex_layout: VertexLayout) -> Self {
+        self.vertex_layout = vertex_layout;
+        self
+    }
+
+    pub fn with_blend(mut self, blend: wgpu::BlendState) -> Self {
+        self.blend = Some(blend);
+        self
+    }
+
+    pub fn with_cull_mode(mut self, cull_mode: wgpu::Face) -> Self {
+        self.cull_mode = Some(cull_mode);
+        self
+    }
+
+    pub fn with_depth(mut self, depth_test: bool, depth_write: bool) -> Self {
+        self.depth_test = depth_test;
+        self.depth_write = depth_write;
+        self
+    }
+
+    pub fn with_shader_def(mut self, shader_def: ShaderDef) -> Self {
+        self.shader_defs.push(shader_def);
+        self
+    }
+
+    pub fn with_diffuse_texture(mut self, diffuse_texture: Handle<Image>) -> Self {
+        self.diffuse_texture = Some(diffuse_texture);
+        self
+    }
+
+    pub fn with_properties(mut self, properties: MaterialProperties) -> Self {
+        self.properties = properties;
+        self
+    }
+}
+
+/// Phong surface parameters as authored in an MTL material library
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialProperties {
+    /// `Ka`
+    pub ambient_color: [f32; 3],
+    /// `Kd`
+    pub diffuse_color: [f32; 3],
+    /// `Ks`
+    pub specular_color: [f32; 3],
+    /// `Ke`
+    pub emissive_color: [f32; 3],
+    /// `Ns`, the specular exponent
+    pub shininess: f32,
+    /// `Ni`, the index of refraction
+    pub optical_density: f32,
+    /// `d` (or `1 - Tr`), where 1.0 is fully opaque
+    pub dissolve: f32,
+    /// `illum`
+    pub illumination_model: u32,
+    /// `map_Ka`
+    pub ambient_texture: Option<AssetPath<'static>>,
+    /// `map_Kd`
+    pub diffuse_texture: Option<AssetPath<'static>>,
+    /// `map_Ks`
+    pub specular_texture: Option<AssetPath<'static>>,
+    /// `map_Ns`
+    pub shininess_texture: Option<AssetPath<'static>>,
+    /// `map_d`
+    pub dissolve_texture: Option<AssetPath<'static>>,
+    /// `map_Bump`, `bump` or `norm`
+    pub normal_texture: Option<AssetPath<'static>>,
+}
+
+impl Default for MaterialProperties {
+    fn default() -> Self {
+        Self {
+            ambient_color: [1.0, 1.0, 1.0],
+            diffuse_color: [0.8, 0.8, 0.8],
+            specular_color: [0.5, 0.5, 0.5],
+            emissive_color: [0.0, 0.0, 0.0],
+            shininess: 250.0,
+            optical_density: 1.45,
+            dissolve: 1.0,
+            illumination_model: 2,
+            ambient_texture: None,
+            diffuse_texture: None,
+            specular_texture: None,
+            shininess_texture: None,
+            dissolve_texture: None,
+            normal_texture: None,
+        }
+    }
+}
@@ -1,19 +1,29 @@
-use bevy::asset::{AssetEvents, AssetLoadFailedEvent, AssetServerMode, TrackAssets};
+use bevy::asset::{handle_internal_asset_events, AssetEvents, AssetLoadFailedEvent, AssetServerMode, TrackAssets};
 use bevy::asset::io::{AssetSourceBuilders};
 use bevy::ecs::event::EventRegistry;
 use bevy::prelude::*;
 use bevy::tasks::{AsyncComputeTaskPool, ComputeTaskPool, IoTaskPool, TaskPoolBuilder};
 use crate::app::schedule;
+use crate::assets::images::ImageAssetLoader;
 use crate::assets::materials::Material;
+use crate::assets::mtl::{MaterialLibrary, MtlAssetLoader};
+use crate::assets::obj::{ObjAssetLoader, ObjScene};
 use crate::assets::shaders::{Shader, ShaderAssetLoader};
+use crate::renderer::mesh::Mesh;
+use crate::renderer::texture::Image;
 
 const DEFAULT_ASSETS_PATH: &str = "assets";
 const DEFAULT_IO_THREADS_COUNT: usize = 2;
 const DEFAULT_ASYNC_COMPUTE_THREADS_COUNT: usize = 2;
 const DEFAULT_COMPUTE_THREADS_COUNT: usize = 2;
+/// Assets are reloaded when their files change if the `file_watcher` feature is enabled
+const WATCH_FOR_CHANGES: bool = cfg!(feature = "file_watcher");
 
 pub mod shaders;
+pub mod images;
 pub mod materials;
+pub mod mtl;
+pub mod obj;
 
 
 pub fn initialize_asset_server(world: &mut World) {
@@ -21,11 +31,11 @@ pub fn initialize_asset_server(world: &mut World) {
 
     let mut builders: AssetSourceBuilders = Default::default();
     builders.init_default_source(DEFAULT_ASSETS_PATH, None);
-    let asset_sources = builders.build_sources(false, false);
+    let asset_sources = builders.build_sources(WATCH_FOR_CHANGES, false);
     let asset_server = AssetServer::new(
         asset_sources,
         AssetServerMode::Unprocessed,
-        false
+        WATCH_FOR_CHANGES
     );
 
     let shader_assets = Assets::<Shader>::default();
@@ -33,19 +43,14 @@ pub fn initialize_asset_server(world: &mut World) {
     asset_server.register_asset(&shader_assets);
     asset_server.register_loader(shader_asset_loader);
 
-    asset_server.register_asset(&Assets::<Material>::default());
-
     world.insert_resource(asset_server);
     world.insert_resource(shader_assets);
 
     EventRegistry::register_event::<AssetEvent<Shader>>(world);
     EventRegistry::register_event::<AssetLoadFailedEvent<Shader>>(world);
 
-    EventRegistry::register_event::<AssetEvent<Material>>(world);
-
     let registry = world.resource_mut::<AppTypeRegistry>();
     registry.write().register::<Handle<Shader>>();
-    registry.write().register::<Handle<Material>>();
 
     let mut schedules = world.resource_mut::<Schedules>();
     schedules.add_systems(
@@ -59,9 +64,45 @@ pub fn initialize_asset_server(world: &mut World) {
         Assets::<Shader>::track_assets.in_set(TrackAssets)
     );
 
+    init_asset::<Image>(world);
+    init_asset::<Material>(world);
+    init_asset::<MaterialLibrary>(world);
+    init_asset::<Mesh>(world);
+    init_asset::<ObjScene>(world);
+    world.resource::<AssetServer>().register_loader(ImageAssetLoader);
+    world.resource::<AssetServer>().register_loader(MtlAssetLoader);
+    world.resource::<AssetServer>().register_loader(ObjAssetLoader);
+
+    // Moves assets that finished loading on the task pools into their Assets<A> collections
+    world.resource_mut::<Schedules>().add_systems(schedule::First, handle_internal_asset_events);
+
     tick_task_pools();
 }
 
+/// Registers an asset type with the [`AssetServer`], inserts its [`Assets`] collection and adds the
+/// systems that emit its [`AssetEvent`]s. The [`AssetServer`] resource must already exist
+pub fn init_asset<A: Asset>(world: &mut World) {
+    let assets = Assets::<A>::default();
+    world.resource::<AssetServer>().register_asset(&assets);
+    world.insert_resource(assets);
+
+    EventRegistry::register_event::<AssetEvent<A>>(world);
+    EventRegistry::register_event::<AssetLoadFailedEvent<A>>(world);
+    world.resource::<AppTypeRegistry>().write().register::<Handle<A>>();
+
+    let mut schedules = world.resource_mut::<Schedules>();
+    schedules.add_systems(
+        schedule::Last,
+        Assets::<A>::asset_events
+            .run_if(asset_events_condition::<A>)
+            .in_set(AssetEvents)
+    );
+    schedules.add_systems(
+        schedule::Last,
+        Assets::<A>::track_assets.in_set(TrackAssets)
+    );
+}
+
 pub fn create_task_pools() {
     let io_threads = DEFAULT_IO_THREADS_COUNT;
     let async_compute_threads = DEFAULT_ASYNC_COMPUTE_THREADS_COUNT;
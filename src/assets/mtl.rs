@@ -0,0 +1,285 @@
+use std::path::Path;
+use bevy::asset::{AssetLoader, AssetPath, LoadContext};
+use bevy::asset::io::Reader;
+use bevy::prelude::{Asset, Handle, TypePath};
+use bevy::utils::HashMap;
+use thiserror::Error;
+use crate::assets::materials::{Material, MaterialProperties};
+use crate::assets::shaders::{Shader, DEFAULT_LIT_SHADER};
+
+/// A loaded MTL material library. Each `newmtl` entry is added as a labeled [`Material`] sub-asset,
+/// so a single material can be loaded directly with `"scene.mtl#Material"`
+#[derive(Asset, TypePath)]
+pub struct MaterialLibrary {
+    pub materials: HashMap<String, Handle<Material>>,
+}
+
+#[derive(Default)]
+pub struct MtlAssetLoader;
+
+#[derive(Debug, Error)]
+pub enum MtlAssetLoaderError {
+    #[error("Error occurred while reading mtl file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Mtl file is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Error parsing mtl file on line {line}: {message}")]
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl AssetLoader for MtlAssetLoader {
+    type Asset = MaterialLibrary;
+    type Settings = ();
+    type Error = MtlAssetLoaderError;
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        log::debug!("Loading mtl using MtlAssetLoader, asset path={:?}", load_context.path());
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let base_path = load_context.path().parent().unwrap_or(Path::new("")).to_path_buf();
+        let parsed_materials = parse_mtl(&String::from_utf8(bytes)?, &base_path)?;
+
+        let lit_shader: Handle<Shader> = load_context.load(DEFAULT_LIT_SHADER);
+        let mut materials = HashMap::new();
+        for (name, properties) in parsed_materials {
+            log::debug!("Adding mtl material as labeled material asset, label={}", name);
+            let mut material = Material::lit(lit_shader.clone());
+            if let Some(diffuse_texture) = &properties.diffuse_texture {
+                material = material.with_diffuse_texture(load_context.load(diffuse_texture.clone()));
+            }
+            let material = load_context.add_labeled_asset(name.clone(), material.with_properties(properties));
+            materials.insert(name, material);
+        }
+
+        Ok(MaterialLibrary {
+            materials,
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mtl"]
+    }
+}
+
+/// Parses the materials in an MTL file. Texture map paths are resolved relative to `base_path`
+pub(crate) fn parse_mtl(content: &str, base_path: &Path) -> Result<Vec<(String, MaterialProperties)>, MtlAssetLoaderError> {
+    let mut materials: Vec<(String, MaterialProperties)> = Vec::new();
+
+    for (line_index, line) in content.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = strip_comment(line).trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            if arguments.is_empty() {
+                return Err(parse_error(line_number, "newmtl requires a material name".to_string()));
+            }
+            materials.push((arguments.join(" "), MaterialProperties::default()));
+            continue;
+        }
+
+        let Some((_, properties)) = materials.last_mut() else {
+            return Err(parse_error(line_number, format!("'{}' appears before any newmtl statement", keyword)));
+        };
+
+        match keyword {
+            "Ka" => properties.ambient_color = parse_color(&arguments, line_number)?,
+            "Kd" => properties.diffuse_color = parse_color(&arguments, line_number)?,
+            "Ks" => properties.specular_color = parse_color(&arguments, line_number)?,
+            "Ke" => properties.emissive_color = parse_color(&arguments, line_number)?,
+            "Ns" => properties.shininess = parse_float(&arguments, line_number)?,
+            "Ni" => properties.optical_density = parse_float(&arguments, line_number)?,
+            "d" => properties.dissolve = parse_float(&arguments, line_number)?,
+            "Tr" => properties.dissolve = 1.0 - parse_float(&arguments, line_number)?,
+            "illum" => {
+                properties.illumination_model = arguments.first()
+                    .and_then(|argument| argument.parse().ok())
+                    .ok_or_else(|| parse_error(line_number, "illum requires an integer illumination model".to_string()))?;
+            }
+            "map_Ka" => properties.ambient_texture = Some(parse_texture_path(&arguments, base_path, line_number)?),
+            "map_Kd" => properties.diffuse_texture = Some(parse_texture_path(&arguments, base_path, line_number)?),
+            "map_Ks" => properties.specular_texture = Some(parse_texture_path(&arguments, base_path, line_number)?),
+            "map_Ns" => properties.shininess_texture = Some(parse_texture_path(&arguments, base_path, line_number)?),
+            "map_d" => properties.dissolve_texture = Some(parse_texture_path(&arguments, base_path, line_number)?),
+            "map_Bump" | "map_bump" | "bump" | "norm" => {
+                properties.normal_texture = Some(parse_texture_path(&arguments, base_path, line_number)?)
+            }
+            _ => {
+                log::trace!("Ignoring unsupported mtl statement on line {}: {}", line_number, keyword);
+            }
+        }
+    }
+
+    Ok(materials)
+}
+
+/// Removes a trailing `# comment` from an OBJ or MTL line. A `#` only starts a comment at the
+/// start of a token, so file names containing `#` are kept
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let comment_start = line.char_indices()
+        .find(|(index, character)| *character == '#'
+            && line[..*index].chars().next_back().map_or(true, char::is_whitespace))
+        .map(|(index, _)| index);
+    match comment_start {
+        Some(index) => &line[..index],
+        None => line,
+    }
+}
+
+fn parse_float(arguments: &[&str], line_number: usize) -> Result<f32, MtlAssetLoaderError> {
+    let argument = arguments.first()
+        .ok_or_else(|| parse_error(line_number, "expected a value".to_string()))?;
+    argument.parse::<f32>()
+        .map_err(|_| parse_error(line_number, format!("'{}' is not a valid number", argument)))
+}
+
+fn parse_color(arguments: &[&str], line_number: usize) -> Result<[f32; 3], MtlAssetLoaderError> {
+    let values = arguments.iter()
+        .map(|argument| argument.parse::<f32>()
+            .map_err(|_| parse_error(line_number, format!("'{}' is not a valid number", argument))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match values.as_slice() {
+        // A single value is shorthand for a grey color
+        [value] => Ok([*value, *value, *value]),
+        [r, g, b] => Ok([*r, *g, *b]),
+        _ => Err(parse_error(line_number, format!("expected 1 or 3 color values, found {}", values.len()))),
+    }
+}
+
+/// Texture statements may be preceded by options such as `-s 1 1 1`, the file name is always last
+fn parse_texture_path(arguments: &[&str], base_path: &Path, line_number: usize) -> Result<AssetPath<'static>, MtlAssetLoaderError> {
+    let file_name = arguments.last()
+        .ok_or_else(|| parse_error(line_number, "texture map requires a file name".to_string()))?;
+    Ok(AssetPath::from(base_path.join(file_name)))
+}
+
+fn parse_error(line: usize, message: String) -> MtlAssetLoaderError {
+    MtlAssetLoaderError::Parse {
+        line,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_single_material(content: &str) -> MaterialProperties {
+        let mut materials = parse_mtl(content, Path::new("models")).unwrap();
+        assert_eq!(materials.len(), 1);
+        materials.remove(0).1
+    }
+
+    fn assert_parse_error(content: &str, expected_line: usize) {
+        match parse_mtl(content, Path::new("models")) {
+            Err(MtlAssetLoaderError::Parse { line, .. }) => assert_eq!(line, expected_line),
+            Err(error) => panic!("expected a parse error on line {}, got {}", expected_line, error),
+            Ok(_) => panic!("expected a parse error on line {}", expected_line),
+        }
+    }
+
+    fn texture_path(path: &str) -> Option<AssetPath<'static>> {
+        Some(AssetPath::from(Path::new("models").join(path)))
+    }
+
+    #[test]
+    fn parses_materials_in_order() {
+        let materials = parse_mtl("\
+newmtl Red
+Kd 1 0 0
+newmtl Dark Grey
+Kd 0.2", Path::new("models")).unwrap();
+
+        let materials: Vec<(&str, [f32; 3])> = materials.iter()
+            .map(|(name, properties)| (name.as_str(), properties.diffuse_color))
+            .collect();
+        assert_eq!(materials, [("Red", [1.0, 0.0, 0.0]), ("Dark Grey", [0.2, 0.2, 0.2])]);
+    }
+
+    #[test]
+    fn parses_properties() {
+        let properties = parse_single_material("\
+newmtl Material
+Ka 0.1 0.2 0.3
+Ks 0.5
+Ke 0 0 1
+Ns 96
+Ni 1.5
+Tr 0.25
+illum 2");
+
+        assert_eq!(properties.ambient_color, [0.1, 0.2, 0.3]);
+        assert_eq!(properties.specular_color, [0.5, 0.5, 0.5]);
+        assert_eq!(properties.emissive_color, [0.0, 0.0, 1.0]);
+        assert_eq!(properties.shininess, 96.0);
+        assert_eq!(properties.optical_density, 1.5);
+        assert_eq!(properties.dissolve, 0.75);
+        assert_eq!(properties.illumination_model, 2);
+    }
+
+    #[test]
+    fn texture_maps_skip_their_options() {
+        let properties = parse_single_material("\
+newmtl Material
+map_Kd -s 2 2 1 -o 0.5 0.5 0 -blendu off textures/wood.png
+map_Bump -bm 0.5 textures/wood_normal.png
+map_d alpha.png");
+
+        assert_eq!(properties.diffuse_texture, texture_path("textures/wood.png"));
+        assert_eq!(properties.normal_texture, texture_path("textures/wood_normal.png"));
+        assert_eq!(properties.dissolve_texture, texture_path("alpha.png"));
+        assert_eq!(properties.specular_texture, None);
+    }
+
+    #[test]
+    fn texture_file_names_keep_hashes() {
+        let properties = parse_single_material("\
+newmtl Material # comment
+map_Kd wood#2.png # the second wood texture");
+
+        assert_eq!(properties.diffuse_texture, texture_path("wood#2.png"));
+    }
+
+    #[test]
+    fn strips_comments_at_the_start_of_a_token() {
+        assert_eq!(strip_comment("# comment"), "");
+        assert_eq!(strip_comment("Kd 1 0 0 # red"), "Kd 1 0 0 ");
+        assert_eq!(strip_comment("Kd 1 0 0\t#red"), "Kd 1 0 0\t");
+        assert_eq!(strip_comment("map_Kd a#b.png"), "map_Kd a#b.png");
+    }
+
+    #[test]
+    fn statements_before_newmtl_fail() {
+        assert_parse_error("# materials\nKd 1 0 0\nnewmtl Material", 2);
+    }
+
+    #[test]
+    fn invalid_statements_fail_on_their_line() {
+        assert_parse_error("newmtl", 1);
+        assert_parse_error("newmtl Material\nKd 1 0", 2);
+        assert_parse_error("newmtl Material\nKd 1 red 0", 2);
+        assert_parse_error("newmtl Material\n\nNs", 3);
+        assert_parse_error("newmtl Material\nd opaque", 2);
+        assert_parse_error("newmtl Material\nillum 1.5", 2);
+        assert_parse_error("newmtl Material\nmap_Kd", 2);
+    }
+
+    #[test]
+    fn ignores_unsupported_statements() {
+        let properties = parse_single_material("newmtl Material\nPr 0.5\nKd 0 1 0");
+
+        assert_eq!(properties.diffuse_color, [0.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,602 @@
+use std::path::Path;
+use bevy::asset::{AssetLoader, LoadContext, RecursiveDependencyLoadState};
+use bevy::asset::io::Reader;
+use bevy::prelude::{Added, Asset, AssetServer, Assets, Commands, Component, Entity, Handle, Query, Res, TypePath, Without};
+use bevy::utils::{HashMap, HashSet};
+use thiserror::Error;
+use crate::assets::materials::Material;
+use crate::assets::mtl::{strip_comment, MaterialLibrary};
+use crate::renderer::material::MeshMaterial;
+use crate::renderer::mesh::{Mesh, MeshHandle};
+use crate::renderer::vertex::Vertex;
+
+/// Color given to vertices that do not specify one with the `v x y z r g b` extension
+const DEFAULT_VERTEX_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+const DEFAULT_OBJECT_NAME: &str = "default";
+
+/// A loaded Wavefront OBJ file. Each object (`o`) or group (`g`) in the file is added as a labeled
+/// [`Mesh`] sub-asset, so a single object can be loaded directly with `"scene.obj#Cube"`
+#[derive(Asset, TypePath)]
+pub struct ObjScene {
+    pub objects: Vec<ObjObject>,
+    /// The `mtllib` files referenced by this OBJ file, in the order they are referenced. They are
+    /// dependencies of the scene, so they have loaded once the scene and its dependencies have
+    pub material_libraries: Vec<Handle<MaterialLibrary>>,
+}
+
+impl ObjScene {
+    /// The [`Material`] selected with `usemtl` for `object`, from the first of the scene's material
+    /// libraries that defines it. Returns `None` if the object has no material or the libraries
+    /// have not loaded yet
+    pub fn material(&self, object: &ObjObject, libraries: &Assets<MaterialLibrary>) -> Option<Handle<Material>> {
+        let name = object.material.as_ref()?;
+        self.material_libraries.iter()
+            .filter_map(|library| libraries.get(library))
+            .find_map(|library| library.materials.get(name))
+            .cloned()
+    }
+}
+
+pub struct ObjObject {
+    pub name: String,
+    pub mesh: Handle<Mesh>,
+    /// Name of the material selected with `usemtl` for this object, if any. It is inserted as a
+    /// [`MeshMaterial`] by [`insert_obj_materials`], or can be looked up with [`ObjScene::material`]
+    pub material: Option<String>,
+}
+
+/// Keeps the [`ObjScene`] of an entity's [`MeshHandle`] loaded until its material is resolved
+#[derive(Component)]
+pub struct PendingObjMaterial(Handle<ObjScene>);
+
+/// Inserts the [`MeshMaterial`] selected with `usemtl` on entities whose [`MeshHandle`] is an object
+/// of an OBJ file (e.g. `"scene.obj#Cube"`), once the scene's material libraries have loaded.
+/// Entities that already have a [`MeshMaterial`] keep it
+pub fn insert_obj_materials(
+    mut commands: Commands,
+    new_mesh_handles: Query<(Entity, &MeshHandle), (Added<MeshHandle>, Without<MeshMaterial>)>,
+    pending_materials: Query<(Entity, &MeshHandle, &PendingObjMaterial), Without<MeshMaterial>>,
+    asset_server: Res<AssetServer>,
+    scenes: Res<Assets<ObjScene>>,
+    libraries: Res<Assets<MaterialLibrary>>,
+) {
+    for (entity, mesh_handle) in &new_mesh_handles {
+        let Some(path) = mesh_handle.0.path() else {
+            continue;
+        };
+        let is_obj_object = path.label().is_some() && path.path().extension().is_some_and(|extension| extension == "obj");
+        if is_obj_object {
+            let scene = asset_server.load(path.without_label().into_owned());
+            commands.entity(entity).insert(PendingObjMaterial(scene));
+        }
+    }
+
+    for (entity, mesh_handle, pending_material) in &pending_materials {
+        let scene_id = pending_material.0.id();
+        if matches!(asset_server.recursive_dependency_load_state(scene_id), RecursiveDependencyLoadState::NotLoaded | RecursiveDependencyLoadState::Loading) {
+            continue;
+        }
+
+        // A library that failed to load only loses its own materials, the others still resolve
+        let material = scenes.get(scene_id).and_then(|scene| {
+            let object = scene.objects.iter().find(|object| object.mesh == mesh_handle.0)?;
+            scene.material(object, &libraries)
+        });
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.remove::<PendingObjMaterial>();
+        if let Some(material) = material {
+            log::debug!("Obj material loaded, inserting mesh material for entity_id={}", entity);
+            entity_commands.insert(MeshMaterial::from(material));
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ObjAssetLoader;
+
+#[derive(Debug, Error)]
+pub enum ObjAssetLoaderError {
+    #[error("Error occurred while reading obj file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Obj file is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Error parsing obj file on line {line}: {message}")]
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl AssetLoader for ObjAssetLoader {
+    type Asset = ObjScene;
+    type Settings = ();
+    type Error = ObjAssetLoaderError;
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        log::debug!("Loading obj using ObjAssetLoader, asset path={:?}", load_context.path());
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let parsed_obj = parse_obj(&String::from_utf8(bytes)?)?;
+
+        // Only loaded as dependencies, the materials are parsed once by the MtlAssetLoader
+        let base_path = load_context.path().parent().unwrap_or(Path::new("")).to_path_buf();
+        let material_libraries: Vec<Handle<MaterialLibrary>> = parsed_obj.material_libraries.iter()
+            .map(|material_library| load_context.load(base_path.join(material_library)))
+            .collect();
+
+        let mut objects = Vec::with_capacity(parsed_obj.objects.len());
+        let mut used_labels = HashSet::new();
+        for parsed_object in parsed_obj.objects {
+            let label = unique_label(&parsed_object.name, &mut used_labels);
+            log::debug!("Adding obj object as labeled mesh asset, label={}", label);
+            let mesh = load_context.add_labeled_asset(label.clone(), parsed_object.mesh);
+            objects.push(ObjObject {
+                name: label,
+                mesh,
+                material: parsed_object.material,
+            });
+        }
+
+        Ok(ObjScene {
+            objects,
+            material_libraries,
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["obj"]
+    }
+}
+
+/// Returns `name` if no earlier object uses it as its label, otherwise the first free `"{name}.{n}"`.
+/// Objects split by `usemtl` are named `"{name}.{material}"` before they get here, so every name goes
+/// through the same check and a split can never share a label with a repeated object name
+fn unique_label(name: &str, used_labels: &mut HashSet<String>) -> String {
+    let mut label = name.to_string();
+    let mut suffix = 1;
+    while used_labels.contains(&label) {
+        label = format!("{}.{}", name, suffix);
+        suffix += 1;
+    }
+    used_labels.insert(label.clone());
+    label
+}
+
+pub(crate) struct ParsedObj {
+    pub(crate) objects: Vec<ParsedObjObject>,
+    pub(crate) material_libraries: Vec<String>,
+}
+
+pub(crate) struct ParsedObjObject {
+    pub(crate) name: String,
+    pub(crate) material: Option<String>,
+    pub(crate) mesh: Mesh,
+}
+
+/// Index of a single face corner into the position, texture coordinate and normal lists
+type FaceVertex = (usize, Option<usize>, Option<usize>);
+
+/// Accumulates the faces of the object currently being parsed and de-duplicates identical
+/// position/uv/normal combinations into a single indexed vertex
+struct ObjectBuilder {
+    name: String,
+    material: Option<String>,
+    vertices: Vec<Vertex>,
+    normals: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    has_normals: bool,
+    has_uvs: bool,
+    indices: Vec<u32>,
+    vertex_lookup: HashMap<FaceVertex, u32>,
+}
+
+impl ObjectBuilder {
+    fn new(name: String, material: Option<String>) -> Self {
+        Self {
+            name,
+            material,
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            has_normals: false,
+            has_uvs: false,
+            indices: Vec::new(),
+            vertex_lookup: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    fn vertex_index(
+        &mut self,
+        face_vertex: FaceVertex,
+        positions: &[([f32; 3], Option<[f32; 3]>)],
+        texture_coordinates: &[[f32; 2]],
+        normals: &[[f32; 3]],
+    ) -> u32 {
+        if let Some(index) = self.vertex_lookup.get(&face_vertex) {
+            return *index;
+        }
+
+        let (position_index, uv_index, normal_index) = face_vertex;
+        let (position, color) = positions[position_index];
+        self.vertices.push(Vertex { position, color: color.unwrap_or(DEFAULT_VERTEX_COLOR) });
+        self.uvs.push(uv_index.map(|index| texture_coordinates[index]).unwrap_or_default());
+        self.normals.push(normal_index.map(|index| normals[index]).unwrap_or_default());
+        self.has_uvs |= uv_index.is_some();
+        self.has_normals |= normal_index.is_some();
+
+        let index = (self.vertices.len() - 1) as u32;
+        self.vertex_lookup.insert(face_vertex, index);
+        index
+    }
+
+    fn build(self) -> ParsedObjObject {
+        let mut mesh = Mesh::with_indices(self.vertices, self.indices);
+        if self.has_normals {
+            mesh = mesh.with_normals(self.normals);
+        }
+        if self.has_uvs {
+            mesh = mesh.with_uvs(self.uvs);
+        }
+
+        ParsedObjObject {
+            name: self.name,
+            material: self.material,
+            mesh,
+        }
+    }
+}
+
+pub(crate) fn parse_obj(content: &str) -> Result<ParsedObj, ObjAssetLoaderError> {
+    let mut positions: Vec<([f32; 3], Option<[f32; 3]>)> = Vec::new();
+    let mut texture_coordinates: Vec<[f32; 2]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+    let mut material_libraries = Vec::new();
+    let mut objects = Vec::new();
+    let mut current = ObjectBuilder::new(DEFAULT_OBJECT_NAME.to_string(), None);
+
+    for (line_index, line) in content.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = strip_comment(line).trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => {
+                let values = parse_floats(&arguments, line_number)?;
+                match values.len() {
+                    3 | 4 => positions.push(([values[0], values[1], values[2]], None)),
+                    6 => positions.push((
+                        [values[0], values[1], values[2]],
+                        Some([values[3], values[4], values[5]])
+                    )),
+                    count => return Err(parse_error(line_number, format!("expected 3, 4 or 6 values for a vertex, found {}", count))),
+                }
+            }
+            "vt" => {
+                let values = parse_floats(&arguments, line_number)?;
+                if values.is_empty() || values.len() > 3 {
+                    return Err(parse_error(line_number, format!("expected 1 to 3 values for a texture coordinate, found {}", values.len())));
+                }
+                // OBJ texture coordinates have their origin in the bottom left while wgpu uses the top left
+                let v = values.get(1).copied().unwrap_or(0.0);
+                texture_coordinates.push([values[0], 1.0 - v]);
+            }
+            "vn" => {
+                let values = parse_floats(&arguments, line_number)?;
+                if values.len() != 3 {
+                    return Err(parse_error(line_number, format!("expected 3 values for a normal, found {}", values.len())));
+                }
+                normals.push([values[0], values[1], values[2]]);
+            }
+            "f" => {
+                if arguments.len() < 3 {
+                    return Err(parse_error(line_number, format!("a face needs at least 3 vertices, found {}", arguments.len())));
+                }
+                let face_vertices = arguments.iter()
+                    .map(|argument| parse_face_vertex(
+                        argument,
+                        line_number,
+                        positions.len(),
+                        texture_coordinates.len(),
+                        normals.len()
+                    ))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let face_indices: Vec<u32> = face_vertices.into_iter()
+                    .map(|face_vertex| current.vertex_index(face_vertex, &positions, &texture_coordinates, &normals))
+                    .collect();
+
+                // Triangulate n-gons as a fan around the first vertex. This is correct for the
+                // convex polygons exported by Blender and other DCC tools
+                for i in 1..face_indices.len() - 1 {
+                    current.indices.extend_from_slice(&[face_indices[0], face_indices[i], face_indices[i + 1]]);
+                }
+            }
+            "o" | "g" => {
+                let name = if arguments.is_empty() {
+                    DEFAULT_OBJECT_NAME.to_string()
+                } else {
+                    arguments.join(" ")
+                };
+                let material = current.material.clone();
+                let previous = std::mem::replace(&mut current, ObjectBuilder::new(name, material));
+                if !previous.is_empty() {
+                    objects.push(previous.build());
+                }
+            }
+            "usemtl" => {
+                let material = arguments.join(" ");
+                if current.is_empty() {
+                    current.material = Some(material);
+                } else if current.material.as_deref() != Some(material.as_str()) {
+                    // Switching materials part way through an object splits it into a new mesh so each
+                    // mesh only ever uses a single material
+                    let name = format!("{}.{}", current.name, material);
+                    let previous = std::mem::replace(&mut current, ObjectBuilder::new(name, Some(material)));
+                    objects.push(previous.build());
+                }
+            }
+            "mtllib" => {
+                material_libraries.extend(arguments.iter().map(|library| library.to_string()));
+            }
+            _ => {
+                log::trace!("Ignoring unsupported obj statement on line {}: {}", line_number, keyword);
+            }
+        }
+    }
+
+    if !current.is_empty() {
+        objects.push(current.build());
+    }
+
+    Ok(ParsedObj {
+        objects,
+        material_libraries,
+    })
+}
+
+fn parse_floats(arguments: &[&str], line_number: usize) -> Result<Vec<f32>, ObjAssetLoaderError> {
+    arguments.iter()
+        .map(|argument| argument.parse::<f32>()
+            .map_err(|_| parse_error(line_number, format!("'{}' is not a valid number", argument))))
+        .collect()
+}
+
+fn parse_face_vertex(
+    argument: &str,
+    line_number: usize,
+    position_count: usize,
+    texture_coordinate_count: usize,
+    normal_count: usize,
+) -> Result<FaceVertex, ObjAssetLoaderError> {
+    let mut parts = argument.split('/');
+    let position = parts.next()
+        .filter(|part| !part.is_empty())
+        .ok_or_else(|| parse_error(line_number, format!("face vertex '{}' has no position index", argument)))?;
+    let position = resolve_index(position, position_count, line_number)?;
+
+    let texture_coordinate = match parts.next() {
+        Some(part) if !part.is_empty() => Some(resolve_index(part, texture_coordinate_count, line_number)?),
+        _ => None,
+    };
+    let normal = match parts.next() {
+        Some(part) if !part.is_empty() => Some(resolve_index(part, normal_count, line_number)?),
+        _ => None,
+    };
+
+    Ok((position, texture_coordinate, normal))
+}
+
+/// Converts a 1-based (or negative, relative to the end of the list) OBJ index into a 0-based index
+fn resolve_index(index: &str, count: usize, line_number: usize) -> Result<usize, ObjAssetLoaderError> {
+    let index: i64 = index.parse()
+        .map_err(|_| parse_error(line_number, format!("'{}' is not a valid index", index)))?;
+    let resolved = match index {
+        0 => None,
+        index if index > 0 => Some(index as usize - 1),
+        index => (count as i64 + index).try_into().ok(),
+    };
+
+    resolved
+        .filter(|resolved| *resolved < count)
+        .ok_or_else(|| parse_error(line_number, format!("index {} is out of range, only {} elements are defined", index, count)))
+}
+
+fn parse_error(line: usize, message: String) -> ObjAssetLoaderError {
+    ObjAssetLoaderError::Parse {
+        line,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::vertex::{MeshVertexAttribute, VertexAttributeValues};
+
+    fn parse_single_object(content: &str) -> Mesh {
+        let mut parsed = parse_obj(content).unwrap();
+        assert_eq!(parsed.objects.len(), 1);
+        parsed.objects.remove(0).mesh
+    }
+
+    fn assert_parse_error(content: &str, expected_line: usize) {
+        match parse_obj(content) {
+            Err(ObjAssetLoaderError::Parse { line, .. }) => assert_eq!(line, expected_line),
+            Err(error) => panic!("expected a parse error on line {}, got {}", expected_line, error),
+            Ok(_) => panic!("expected a parse error on line {}", expected_line),
+        }
+    }
+
+    #[test]
+    fn resolves_negative_indices_relative_to_the_elements_defined_so_far() {
+        let mesh = parse_single_object("\
+v 0 0 0
+v 1 0 0
+v 0 1 0
+f -3 -2 -1
+v 1 1 0
+f -4 -1 -2");
+
+        assert_eq!(mesh.positions().unwrap(), [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 1.0, 0.0]]);
+        assert_eq!(mesh.indices().unwrap(), [0, 1, 2, 0, 3, 2]);
+    }
+
+    #[test]
+    fn out_of_range_indices_fail() {
+        assert_parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4", 4);
+        assert_parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -4 -2 -1", 4);
+        assert_parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2", 4);
+        assert_parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nf 1/1 2/2 3/1", 5);
+    }
+
+    #[test]
+    fn reads_position_uv_and_normal_triplets() {
+        let mesh = parse_single_object("\
+v 0 0 0
+v 1 0 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 0 1
+vn 0 0 1
+f 1/1/1 2/2/1 3/3/1");
+
+        assert_eq!(mesh.num_vertices(), 3);
+        // Flipped vertically, since OBJ texture coordinates start at the bottom left
+        assert_eq!(mesh.uvs().unwrap(), [[0.0, 1.0], [1.0, 1.0], [0.0, 0.0]]);
+        assert_eq!(mesh.normals().unwrap(), [[0.0, 0.0, 1.0]; 3]);
+    }
+
+    #[test]
+    fn position_and_normal_pairs_have_no_uvs() {
+        let mesh = parse_single_object("\
+v 0 0 0
+v 1 0 0
+v 0 1 0
+vn 0 0 1
+f 1//1 2//1 3//1");
+
+        assert!(mesh.uvs().is_none());
+        assert_eq!(mesh.normals().unwrap(), [[0.0, 0.0, 1.0]; 3]);
+    }
+
+    #[test]
+    fn shares_identical_face_vertices() {
+        let mesh = parse_single_object("\
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vn 0 0 1
+vn 0 0 -1
+f 1//1 2//1 3//1
+f 1//1 3//1 4//1
+f 1//2 3//2 2//2");
+
+        // The last face uses a different normal, so its corners are separate vertices
+        assert_eq!(mesh.num_vertices(), 7);
+        assert_eq!(mesh.indices().unwrap(), [0, 1, 2, 0, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn triangulates_polygons_as_a_fan() {
+        let mesh = parse_single_object("\
+v 0 0 0
+v 1 0 0
+v 2 1 0
+v 1 2 0
+v 0 1 0
+f 1 2 3 4 5");
+
+        assert_eq!(mesh.indices().unwrap(), [0, 1, 2, 0, 2, 3, 0, 3, 4]);
+    }
+
+    #[test]
+    fn faces_need_three_vertices() {
+        assert_parse_error("v 0 0 0\nv 1 0 0\n\nf 1 2", 4);
+    }
+
+    #[test]
+    fn invalid_statements_fail_on_their_line() {
+        assert_parse_error("v 0 0", 1);
+        assert_parse_error("v 0 0 0\nvt a b", 2);
+        assert_parse_error("# comment\nvn 0 1", 2);
+        assert_parse_error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 x 3", 4);
+    }
+
+    #[test]
+    fn reads_vertex_colors() {
+        let mesh = parse_single_object("\
+v 0 0 0 1 0 0
+v 1 0 0
+v 0 1 0 0 0 1
+f 1 2 3");
+
+        let Some(VertexAttributeValues::Float32x3(colors)) = mesh.attribute(MeshVertexAttribute::COLOR) else {
+            panic!("expected vertex colors");
+        };
+        assert_eq!(colors, &[[1.0, 0.0, 0.0], DEFAULT_VERTEX_COLOR, [0.0, 0.0, 1.0]]);
+    }
+
+    #[test]
+    fn splits_objects_and_materials() {
+        let parsed = parse_obj("\
+mtllib scene.mtl # the materials
+v 0 0 0
+v 1 0 0
+v 0 1 0
+o First
+usemtl Red
+f 1 2 3
+usemtl Blue
+f 1 2 3
+o Second
+f 3 2 1").unwrap();
+
+        assert_eq!(parsed.material_libraries, ["scene.mtl"]);
+        let objects: Vec<(&str, Option<&str>)> = parsed.objects.iter()
+            .map(|object| (object.name.as_str(), object.material.as_deref()))
+            .collect();
+        assert_eq!(objects, [("First", Some("Red")), ("First.Blue", Some("Blue")), ("Second", Some("Blue"))]);
+    }
+
+    #[test]
+    fn material_splits_and_repeated_names_get_unique_labels() {
+        let parsed = parse_obj("\
+v 0 0 0
+v 1 0 0
+v 0 1 0
+o Cube
+usemtl 0
+f 1 2 3
+usemtl 1
+f 1 2 3
+o Cube
+f 3 2 1").unwrap();
+
+        let mut used_labels = HashSet::new();
+        let labels: Vec<String> = parsed.objects.iter()
+            .map(|object| unique_label(&object.name, &mut used_labels))
+            .collect();
+        assert_eq!(labels, ["Cube", "Cube.1", "Cube.2"]);
+    }
+
+    #[test]
+    fn keeps_hashes_inside_file_names() {
+        let parsed = parse_obj("mtllib materials#1.mtl #comment").unwrap();
+
+        assert_eq!(parsed.material_libraries, ["materials#1.mtl"]);
+    }
+}
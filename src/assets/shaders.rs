@@ -1,57 +1,400 @@
 use bevy::asset::{AssetLoader, LoadContext};
 use bevy::asset::io::Reader;
-use bevy::prelude::{Asset, Handle, Resource, TypePath};
-use bevy::utils::HashMap;
+use bevy::prelude::{Asset, AssetId, Assets, Handle, Resource, TypePath};
+use bevy::utils::{HashMap, HashSet};
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::num::NonZeroU64;
 use thiserror::Error;
+use crate::assets::shaders::preprocessor::{
+    parse_import, ComposedShader, ShaderDef, ShaderImport, ShaderPreprocessorError, ShaderSource,
+};
 
 pub const DEFAULT_3D_SHADER: &'static str = "shaders/default.wgsl";
 pub const DEFAULT_2D_SHADER: &'static str = "shaders/default_2d.wgsl";
+/// Blinn-Phong shader lit by the scene's lights, used by [`Material::lit`](crate::assets::materials::Material::lit)
+pub const DEFAULT_LIT_SHADER: &'static str = "shaders/lit.wgsl";
+
+pub mod preprocessor;
 
 pub type ShaderName = String;
 pub type ShaderPath = String;
+
+/// A compiled shader is specific to the shader defs it was composed with
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ShaderModuleKey {
+    pub shader: Handle<Shader>,
+    pub shader_defs: Vec<ShaderDef>,
+}
+
 #[derive(Resource)]
 pub struct ShadersState {
-    pub(crate) loaded_shader_modules: HashMap<Handle<Shader>, wgpu::ShaderModule>,
+    pub(crate) loaded_shader_modules: HashMap<ShaderModuleKey, wgpu::ShaderModule>,
+    /// Modules that failed to compile. They are not retried until the shader is reloaded
+    pub(crate) failed_shader_modules: HashSet<ShaderModuleKey>,
     pub shader_handles: Vec<Handle<Shader>>,
 }
 
+impl ShadersState {
+    /// Compiles the shader with the given shader defs into a [`wgpu::ShaderModule`] if it has not
+    /// been compiled yet. Returns false if the shader asset has not finished loading or does not
+    /// compile
+    pub(crate) fn ensure_shader_module(
+        &mut self,
+        device: &wgpu::Device,
+        module_key: &ShaderModuleKey,
+        shader_assets: &Assets<Shader>,
+    ) -> bool {
+        if self.loaded_shader_modules.contains_key(module_key) {
+            return true;
+        }
+        if self.failed_shader_modules.contains(module_key) {
+            return false;
+        }
+
+        let Some(shader) = shader_assets.get(&module_key.shader) else {
+            return false;
+        };
+
+        log::debug!("Creating shader module for shader={:?} | shader_defs={:?}", module_key.shader, module_key.shader_defs);
+        match shader.create_shader_module(device, &module_key.shader_defs) {
+            Ok(shader_module) => {
+                self.loaded_shader_modules.insert(module_key.clone(), shader_module);
+                true
+            }
+            Err(error) => {
+                log::error!("Unable to compile shader={:?}: {}", module_key.shader, error);
+                self.failed_shader_modules.insert(module_key.clone());
+                false
+            }
+        }
+    }
+
+    /// Recompiles every module of a shader that has already been compiled. A module whose new
+    /// source fails to compile keeps its previous version. Returns true if any module was replaced
+    pub(crate) fn reload_shader_module(
+        &mut self,
+        device: &wgpu::Device,
+        shader_id: AssetId<Shader>,
+        shader_assets: &Assets<Shader>,
+    ) -> bool {
+        self.failed_shader_modules.retain(|module_key| module_key.shader.id() != shader_id);
+        let Some(shader) = shader_assets.get(shader_id) else {
+            return false;
+        };
+
+        let module_keys: Vec<ShaderModuleKey> = self.loaded_shader_modules.keys()
+            .filter(|module_key| module_key.shader.id() == shader_id)
+            .cloned()
+            .collect();
+        let mut reloaded = false;
+        for module_key in module_keys {
+            log::info!("Reloading shader module for shader={:?} | shader_defs={:?}", module_key.shader, module_key.shader_defs);
+            match shader.create_shader_module(device, &module_key.shader_defs) {
+                Ok(shader_module) => {
+                    self.loaded_shader_modules.insert(module_key, shader_module);
+                    reloaded = true;
+                }
+                Err(error) => {
+                    log::error!("Keeping previous shader module, reloaded shader={:?} failed to compile: {}", module_key.shader, error);
+                }
+            }
+        }
+        reloaded
+    }
+}
+
+/// A WGSL shader. Imports are resolved when the shader is loaded, shader defs are applied when a
+/// [`wgpu::ShaderModule`] is created from it, so one shader asset can be compiled with different
+/// defs for different materials
 #[derive(Asset, TypePath)]
 pub struct Shader {
-    pub(crate) shader_content: String,
+    pub(crate) source: ShaderSource,
+    entry_points: Vec<ShaderEntryPoint>,
+    bind_groups: BTreeMap<u32, Vec<wgpu::BindGroupLayoutEntry>>,
+}
+
+impl Shader {
+    /// Composes the shader with the given defs, validates it and creates the module
+    pub fn create_shader_module(
+        &self,
+        device: &wgpu::Device,
+        shader_defs: &[ShaderDef],
+    ) -> Result<wgpu::ShaderModule, ShaderAssetLoaderError> {
+        let composed = self.source.compose(shader_defs)?;
+        validate_wgsl(&composed)?;
+
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(self.source.path()),
+            source: wgpu::ShaderSource::Wgsl(composed.source.into()),
+        });
+        if let Some(error) = pollster::block_on(device.pop_error_scope()) {
+            return Err(ShaderAssetLoaderError::Device(error.to_string()));
+        }
+        Ok(shader_module)
+    }
+
+    pub fn entry_points(&self) -> &[ShaderEntryPoint] {
+        &self.entry_points
+    }
+
+    pub fn entry_point(&self, name: &str) -> Option<&ShaderEntryPoint> {
+        self.entry_points.iter().find(|entry_point| entry_point.name == name)
+    }
+
+    /// The bind group indices the shader declares resources in, in ascending order
+    pub fn bind_group_indices(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bind_groups.keys().copied()
+    }
+
+    /// The layout entries of the resources declared in bind group `group`, sorted by binding.
+    /// Each entry is visible to the stages of the entry points that use it
+    pub fn bind_group_layout_entries(&self, group: u32) -> Option<&[wgpu::BindGroupLayoutEntry]> {
+        self.bind_groups.get(&group).map(Vec::as_slice)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderEntryPoint {
+    pub name: String,
+    pub stage: wgpu::ShaderStages,
 }
 
 #[derive(Default)]
 pub struct ShaderAssetLoader;
 
+/// The shader defs a shader is validated and reflected with when it is loaded. Materials can
+/// compile the shader with other defs later
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ShaderLoaderSettings {
+    pub shader_defs: Vec<ShaderDef>,
+}
+
 #[derive(Debug, Error)]
 pub enum ShaderAssetLoaderError {
-    #[error("Error occurred while loading shader")]
-    CouldNotLoad
+    #[error("Error occurred while reading shader: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Shader is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Error preprocessing shader {0}")]
+    Preprocessor(#[from] ShaderPreprocessorError),
+    #[error("Unable to import shader {path}: {message}")]
+    Import {
+        path: String,
+        message: String,
+    },
+    /// The WGSL failed to parse or validate. `line` and `column` are 1-based and are 0 when naga
+    /// could not attribute the error to a location. `report` is the full diagnostic with the
+    /// offending source highlighted
+    #[error("Invalid shader {path}:{line}:{column}: {message}")]
+    Invalid {
+        path: String,
+        line: u32,
+        column: u32,
+        message: String,
+        report: String,
+    },
+    #[error("Device rejected shader: {0}")]
+    Device(String),
 }
 
 impl AssetLoader for ShaderAssetLoader {
     type Asset = Shader;
-    type Settings = ();
+    type Settings = ShaderLoaderSettings;
     type Error = ShaderAssetLoaderError;
     async fn load(
         &self,
         reader: &mut dyn Reader,
-        _settings: &(),
+        settings: &ShaderLoaderSettings,
         load_context: &mut LoadContext<'_>,
     ) -> Result<Self::Asset, Self::Error> {
         log::debug!("Loading shader using ShaderAssetLoader, asset path={:?}", load_context.path());
         let mut bytes = Vec::new();
-        if let Ok(_) = reader.read_to_end(&mut bytes).await {
-            let custom_asset = Shader {
-                shader_content: String::from_utf8(bytes).unwrap(),
+        reader.read_to_end(&mut bytes).await?;
+        let content = String::from_utf8(bytes)?;
+        let path = load_context.path().display().to_string();
+
+        let mut source = ShaderSource::new(path.clone());
+        for (line_index, line) in content.lines().enumerate() {
+            let line_number = line_index as u32 + 1;
+            let import_error = |kind| ShaderPreprocessorError {
+                path: path.clone(),
+                line: line_number,
+                kind,
             };
-            return Ok(custom_asset)
+            match parse_import(line) {
+                None => source.push_line(line, line_number),
+                Some(Ok(ShaderImport::Builtin(name))) => {
+                    source.append_import(&ShaderSource::from_builtin(&name).map_err(import_error)?);
+                }
+                Some(Ok(ShaderImport::Asset(import_path))) => {
+                    // Importing through the load context makes the import a dependency, so this
+                    // shader is reloaded when the imported file changes
+                    let import_path = load_context.asset_path().resolve_embed(&import_path)
+                        .map_err(|error| ShaderAssetLoaderError::Import {
+                            path: import_path.clone(),
+                            message: error.to_string(),
+                        })?;
+                    let imported = load_context.loader().immediate().load::<Shader>(import_path.clone()).await
+                        .map_err(|error| ShaderAssetLoaderError::Import {
+                            path: import_path.to_string(),
+                            message: error.to_string(),
+                        })?;
+                    source.append_import(&imported.get().source);
+                }
+                Some(Err(kind)) => return Err(import_error(kind).into()),
+            }
         }
 
-        Err(ShaderAssetLoaderError::CouldNotLoad)
+        let composed = source.compose(&settings.shader_defs)?;
+        let (module, module_info) = validate_wgsl(&composed)?;
+        Ok(Shader {
+            source,
+            entry_points: reflect_entry_points(&module),
+            bind_groups: reflect_bind_groups(&module, &module_info),
+        })
     }
 
     fn extensions(&self) -> &[&str] {
         &["wgsl"]
     }
-}
\ No newline at end of file
+}
+
+/// Parses and validates composed WGSL. Error locations point at the file and line the offending
+/// code came from rather than at the composed source
+fn validate_wgsl(composed: &ComposedShader) -> Result<(naga::Module, naga::valid::ModuleInfo), ShaderAssetLoaderError> {
+    let invalid = |location: Option<naga::SourceLocation>, message: String, report: String| {
+        let origin = location.and_then(|location| composed.origin(location.line_number));
+        ShaderAssetLoaderError::Invalid {
+            path: origin.map_or_else(|| composed.path().to_string(), |(path, _)| path.to_string()),
+            line: origin.map_or(0, |(_, line)| line),
+            column: location.map_or(0, |location| location.line_position),
+            message,
+            report,
+        }
+    };
+
+    let module = naga::front::wgsl::parse_str(&composed.source).map_err(|error| invalid(
+        error.location(&composed.source),
+        error.message().to_string(),
+        error.emit_to_string_with_path(&composed.source, composed.path()),
+    ))?;
+
+    // wgpu validates the module again against the device's actual capabilities when the shader
+    // module is created, so everything is allowed here
+    let module_info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|error| invalid(
+            error.location(&composed.source),
+            error.to_string(),
+            error.emit_to_string_with_path(&composed.source, composed.path()),
+        ))?;
+
+    Ok((module, module_info))
+}
+
+fn shader_stage(stage: naga::ShaderStage) -> wgpu::ShaderStages {
+    match stage {
+        naga::ShaderStage::Vertex => wgpu::ShaderStages::VERTEX,
+        naga::ShaderStage::Fragment => wgpu::ShaderStages::FRAGMENT,
+        naga::ShaderStage::Compute => wgpu::ShaderStages::COMPUTE,
+    }
+}
+
+fn reflect_entry_points(module: &naga::Module) -> Vec<ShaderEntryPoint> {
+    module.entry_points.iter()
+        .map(|entry_point| ShaderEntryPoint {
+            name: entry_point.name.clone(),
+            stage: shader_stage(entry_point.stage),
+        })
+        .collect()
+}
+
+/// Builds a bind group layout entry for every resource the module declares. Resources that are
+/// not used by any entry point are visible to no stage. Storage textures and binding arrays are
+/// not reflected
+fn reflect_bind_groups(
+    module: &naga::Module,
+    module_info: &naga::valid::ModuleInfo,
+) -> BTreeMap<u32, Vec<wgpu::BindGroupLayoutEntry>> {
+    let mut bind_groups: BTreeMap<u32, Vec<wgpu::BindGroupLayoutEntry>> = BTreeMap::new();
+    for (handle, global) in module.global_variables.iter() {
+        let Some(resource_binding) = &global.binding else {
+            continue;
+        };
+        let Some(ty) = reflect_binding_type(module, global) else {
+            log::debug!("Not reflecting unsupported binding {:?} of group {}", global.name, resource_binding.group);
+            continue;
+        };
+
+        let visibility = module.entry_points.iter().enumerate()
+            .filter(|(index, _)| !module_info.get_entry_point(*index)[handle].is_empty())
+            .fold(wgpu::ShaderStages::NONE, |visibility, (_, entry_point)| visibility | shader_stage(entry_point.stage));
+
+        bind_groups.entry(resource_binding.group).or_default().push(wgpu::BindGroupLayoutEntry {
+            binding: resource_binding.binding,
+            visibility,
+            ty,
+            count: None,
+        });
+    }
+
+    for entries in bind_groups.values_mut() {
+        entries.sort_by_key(|entry| entry.binding);
+    }
+    bind_groups
+}
+
+fn reflect_binding_type(module: &naga::Module, global: &naga::GlobalVariable) -> Option<wgpu::BindingType> {
+    let inner = &module.types[global.ty].inner;
+    match global.space {
+        naga::AddressSpace::Uniform => Some(wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: NonZeroU64::new(inner.size(module.to_ctx()) as u64),
+        }),
+        naga::AddressSpace::Storage { access } => Some(wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage {
+                read_only: !access.contains(naga::StorageAccess::STORE),
+            },
+            has_dynamic_offset: false,
+            // Storage buffers may end in a runtime sized array
+            min_binding_size: None,
+        }),
+        naga::AddressSpace::Handle => match *inner {
+            naga::TypeInner::Sampler { comparison } => Some(wgpu::BindingType::Sampler(if comparison {
+                wgpu::SamplerBindingType::Comparison
+            } else {
+                wgpu::SamplerBindingType::Filtering
+            })),
+            naga::TypeInner::Image { dim, arrayed, class } => {
+                let view_dimension = match (dim, arrayed) {
+                    (naga::ImageDimension::D1, _) => wgpu::TextureViewDimension::D1,
+                    (naga::ImageDimension::D2, false) => wgpu::TextureViewDimension::D2,
+                    (naga::ImageDimension::D2, true) => wgpu::TextureViewDimension::D2Array,
+                    (naga::ImageDimension::D3, _) => wgpu::TextureViewDimension::D3,
+                    (naga::ImageDimension::Cube, false) => wgpu::TextureViewDimension::Cube,
+                    (naga::ImageDimension::Cube, true) => wgpu::TextureViewDimension::CubeArray,
+                };
+                let (sample_type, multisampled) = match class {
+                    naga::ImageClass::Sampled { kind, multi } => (match kind {
+                        naga::ScalarKind::Sint => wgpu::TextureSampleType::Sint,
+                        naga::ScalarKind::Uint => wgpu::TextureSampleType::Uint,
+                        _ => wgpu::TextureSampleType::Float { filterable: !multi },
+                    }, multi),
+                    naga::ImageClass::Depth { multi } => (wgpu::TextureSampleType::Depth, multi),
+                    naga::ImageClass::Storage { .. } => return None,
+                };
+                Some(wgpu::BindingType::Texture {
+                    sample_type,
+                    view_dimension,
+                    multisampled,
+                })
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,22 @@
+// The shadow cascades of every directional light, fit to this camera's view. Sized for
+// MAX_DIRECTIONAL_LIGHTS lights of MAX_CASCADES cascades from the fathom::lights module
+struct CameraShadows {
+    // Far distance from the camera of each cascade, one vector per directional light
+    cascade_splits: array<vec4<f32>, 4>,
+    // MAX_CASCADES view-projections per directional light
+    cascade_view_projections: array<mat4x4<f32>, 16>,
+    // The first layer of this camera's cascades in the directional shadow maps
+    first_layer: u32,
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+};
+
+struct CameraUniforms {
+    viewProjectionMat: mat4x4<f32>,
+    viewMat: mat4x4<f32>,
+    position: vec4<f32>,
+    shadows: CameraShadows,
+};
+
+@group(0) @binding(0) var<uniform> camera: CameraUniforms;
@@ -0,0 +1,63 @@
+#import fathom::lights
+#import fathom::shadows
+
+// Blinn-Phong contribution of a single light. All directions are normalized and point away from
+// the surface
+fn blinn_phong(
+    normal: vec3<f32>,
+    light_direction: vec3<f32>,
+    view_direction: vec3<f32>,
+    light_color: vec3<f32>,
+    diffuse_color: vec3<f32>,
+    specular_color: vec3<f32>,
+    shininess: f32,
+) -> vec3<f32> {
+    let diffuse = max(dot(normal, light_direction), 0.0) * diffuse_color;
+    let half_direction = normalize(light_direction + view_direction);
+    let specular = pow(max(dot(normal, half_direction), 0.0), shininess) * specular_color;
+    return (diffuse + specular) * light_color;
+}
+
+// Smoothly reaches zero at the light's range
+fn distance_attenuation(distance: f32, range: f32) -> f32 {
+    let falloff = saturate(1.0 - pow(distance / range, 4.0));
+    return falloff * falloff / (distance * distance + 1.0);
+}
+
+// The light reflected towards the camera by every light in the scene, excluding ambient light
+fn apply_lights(
+    world_position: vec3<f32>,
+    normal: vec3<f32>,
+    view_direction: vec3<f32>,
+    diffuse_color: vec3<f32>,
+    specular_color: vec3<f32>,
+    shininess: f32,
+) -> vec3<f32> {
+    var color = vec3<f32>(0.0);
+
+    for (var index = 0u; index < min(lights.counts.x, MAX_DIRECTIONAL_LIGHTS); index++) {
+        let light = lights.directional_lights[index];
+        let shadow = directional_light_shadow(index, world_position, normal);
+        color += shadow * blinn_phong(normal, light.direction.xyz, view_direction, light.color.rgb, diffuse_color, specular_color, shininess);
+    }
+
+    for (var index = 0u; index < min(lights.counts.y, MAX_POINT_LIGHTS); index++) {
+        let light = lights.point_lights[index];
+        let to_light = light.position.xyz - world_position;
+        let attenuation = distance_attenuation(length(to_light), light.position.w);
+        color += attenuation * blinn_phong(normal, normalize(to_light), view_direction, light.color.rgb, diffuse_color, specular_color, shininess);
+    }
+
+    for (var index = 0u; index < min(lights.counts.z, MAX_SPOT_LIGHTS); index++) {
+        let light = lights.spot_lights[index];
+        let to_light = light.position.xyz - world_position;
+        let light_direction = normalize(to_light);
+        let cos_angle = dot(-light_direction, light.direction.xyz);
+        let cone = smoothstep(light.direction.w, max(light.color.w, light.direction.w + 0.0001), cos_angle);
+        let shadow = spot_light_shadow(light, world_position, normal);
+        let attenuation = shadow * cone * distance_attenuation(length(to_light), light.position.w);
+        color += attenuation * blinn_phong(normal, light_direction, view_direction, light.color.rgb, diffuse_color, specular_color, shininess);
+    }
+
+    return color;
+}
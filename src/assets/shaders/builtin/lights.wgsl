@@ -0,0 +1,57 @@
+const MAX_DIRECTIONAL_LIGHTS: u32 = 4u;
+const MAX_POINT_LIGHTS: u32 = 64u;
+const MAX_SPOT_LIGHTS: u32 = 16u;
+const MAX_CASCADES: u32 = 4u;
+
+// How a light's shadow map is sampled. A layer count of zero means the light casts no shadows
+struct ShadowParams {
+    depth_bias: f32,
+    normal_bias: f32,
+    // The light's shadow map resolution divided by the size of the shadow map array
+    uv_scale: f32,
+    first_layer: u32,
+    layer_count: u32,
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+};
+
+struct DirectionalLight {
+    // The direction towards the light
+    direction: vec4<f32>,
+    color: vec4<f32>,
+    // The first layer is relative to the camera's first layer, since every camera has its own
+    // cascades
+    shadow: ShadowParams,
+};
+
+struct PointLight {
+    // The range is stored in w
+    position: vec4<f32>,
+    color: vec4<f32>,
+};
+
+struct SpotLight {
+    // The range is stored in w
+    position: vec4<f32>,
+    // The direction the light shines in, with the cosine of the outer angle in w
+    direction: vec4<f32>,
+    // The cosine of the inner angle is stored in w
+    color: vec4<f32>,
+    shadow: ShadowParams,
+    view_projection: mat4x4<f32>,
+};
+
+struct Lights {
+    ambient_color: vec4<f32>,
+    // Number of directional, point and spot lights
+    counts: vec4<u32>,
+    directional_lights: array<DirectionalLight, MAX_DIRECTIONAL_LIGHTS>,
+    point_lights: array<PointLight, MAX_POINT_LIGHTS>,
+    spot_lights: array<SpotLight, MAX_SPOT_LIGHTS>,
+};
+
+@group(3) @binding(0) var<uniform> lights: Lights;
+@group(3) @binding(1) var directional_shadow_maps: texture_depth_2d_array;
+@group(3) @binding(2) var spot_shadow_maps: texture_depth_2d_array;
+@group(3) @binding(3) var shadow_sampler: sampler_comparison;
@@ -0,0 +1,12 @@
+struct MaterialUniforms {
+    ambient_color: vec4<f32>,
+    // The dissolve is stored in alpha
+    diffuse_color: vec4<f32>,
+    // The shininess is stored in w
+    specular_color: vec4<f32>,
+    emissive_color: vec4<f32>,
+};
+
+@group(2) @binding(0) var diffuse_texture: texture_2d<f32>;
+@group(2) @binding(1) var diffuse_sampler: sampler;
+@group(2) @binding(2) var<uniform> material: MaterialUniforms;
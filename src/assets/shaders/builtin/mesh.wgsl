@@ -0,0 +1,29 @@
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+#ifdef VERTEX_COLOR
+    @location(1) color: vec3<f32>,
+#endif
+#ifdef VERTEX_UV_0
+    @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_NORMAL
+    @location(3) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_TANGENT
+    @location(4) tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLOR_1
+    @location(5) color_1: vec4<f32>,
+#endif
+#ifdef VERTEX_UV_1
+    @location(6) uv_1: vec2<f32>,
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) world_position: vec3<f32>,
+    @location(3) world_normal: vec3<f32>,
+};
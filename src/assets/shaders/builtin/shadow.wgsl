@@ -0,0 +1,9 @@
+// Depth only pass rendering shadow casters into a shadow map
+#import fathom::transforms
+
+@group(0) @binding(0) var<uniform> light_view_projection: mat4x4<f32>;
+
+@vertex
+fn vertex_main(@location(0) position: vec3<f32>) -> @builtin(position) vec4<f32> {
+    return light_view_projection * model_to_world(position);
+}
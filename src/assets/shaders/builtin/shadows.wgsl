@@ -0,0 +1,64 @@
+#import fathom::camera
+#import fathom::lights
+
+// Percentage-closer filtering over the 3x3 texels around the position in light clip space.
+// Returns 1.0 when fully lit and 0.0 when fully in shadow
+fn sample_shadow_map_pcf(
+    shadow_maps: texture_depth_2d_array,
+    clip_position: vec4<f32>,
+    shadow: ShadowParams,
+    layer: u32,
+) -> f32 {
+    let ndc = clip_position.xyz / clip_position.w;
+    // Positions outside of the light's view are never shadowed
+    if any(abs(ndc.xy) > vec2<f32>(1.0)) || ndc.z > 1.0 {
+        return 1.0;
+    }
+
+    // Each light only renders into the top left of its layer, at its own resolution
+    let uv = (ndc.xy * vec2<f32>(0.5, -0.5) + 0.5) * shadow.uv_scale;
+    let texel_size = 1.0 / vec2<f32>(textureDimensions(shadow_maps));
+    let depth = ndc.z - shadow.depth_bias;
+    var visibility = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel_size;
+            visibility += textureSampleCompareLevel(shadow_maps, shadow_sampler, uv + offset, layer, depth);
+        }
+    }
+    return visibility / 9.0;
+}
+
+// Shadow factor of a directional light, sampled from the cascade covering the position's view depth.
+// Takes the light's index since its cascades are fit to each camera and indexed in the camera uniform
+fn directional_light_shadow(light_index: u32, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let light = lights.directional_lights[light_index];
+    let cascade_count = min(light.shadow.layer_count, MAX_CASCADES);
+    if cascade_count == 0u {
+        return 1.0;
+    }
+
+    let view_depth = -(camera.viewMat * vec4<f32>(world_position, 1.0)).z;
+    var cascade = 0u;
+    while cascade < cascade_count && view_depth > camera.shadows.cascade_splits[light_index][cascade] {
+        cascade++;
+    }
+    if cascade == cascade_count {
+        return 1.0;
+    }
+
+    let biased_position = world_position + normal * light.shadow.normal_bias;
+    let clip_position = camera.shadows.cascade_view_projections[light_index * MAX_CASCADES + cascade] * vec4<f32>(biased_position, 1.0);
+    let layer = camera.shadows.first_layer + light.shadow.first_layer + cascade;
+    return sample_shadow_map_pcf(directional_shadow_maps, clip_position, light.shadow, layer);
+}
+
+fn spot_light_shadow(light: SpotLight, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if light.shadow.layer_count == 0u {
+        return 1.0;
+    }
+
+    let biased_position = world_position + normal * light.shadow.normal_bias;
+    let clip_position = light.view_projection * vec4<f32>(biased_position, 1.0);
+    return sample_shadow_map_pcf(spot_shadow_maps, clip_position, light.shadow, light.shadow.first_layer);
+}
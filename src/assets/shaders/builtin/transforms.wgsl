@@ -0,0 +1,15 @@
+struct ModelUniforms {
+    modelMat: mat4x4<f32>,
+    normalMat: mat3x3<f32>,
+    tint: vec4<f32>,
+};
+
+@group(1) @binding(0) var<uniform> model: ModelUniforms;
+
+fn model_to_world(position: vec3<f32>) -> vec4<f32> {
+    return model.modelMat * vec4<f32>(position, 1.0);
+}
+
+fn normal_to_world(normal: vec3<f32>) -> vec3<f32> {
+    return normalize(model.normalMat * normal);
+}
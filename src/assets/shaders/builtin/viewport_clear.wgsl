@@ -0,0 +1,14 @@
+// Clears a camera's viewport. A load op clear ignores the viewport, so a triangle covering it is
+// drawn instead, writing the blend constant as its color and the far plane as its depth
+
+@vertex
+fn vertex_main(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4<f32> {
+    let corner = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4<f32>(corner * 2.0 - 1.0, 1.0, 1.0);
+}
+
+@fragment
+fn fragment_main() -> @location(0) vec4<f32> {
+    // Multiplied by the blend constant, which is set to the clear color
+    return vec4<f32>(1.0);
+}
@@ -0,0 +1,502 @@
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Engine provided modules that shaders can import with `#import fathom::<name>`
+pub const BUILTIN_SHADER_MODULES: &[(&str, &str)] = &[
+    ("fathom::camera", include_str!("builtin/camera.wgsl")),
+    ("fathom::transforms", include_str!("builtin/transforms.wgsl")),
+    ("fathom::lights", include_str!("builtin/lights.wgsl")),
+    ("fathom::shadows", include_str!("builtin/shadows.wgsl")),
+    ("fathom::lighting", include_str!("builtin/lighting.wgsl")),
+    ("fathom::shadow", include_str!("builtin/shadow.wgsl")),
+    ("fathom::viewport_clear", include_str!("builtin/viewport_clear.wgsl")),
+    ("fathom::material", include_str!("builtin/material.wgsl")),
+    ("fathom::mesh", include_str!("builtin/mesh.wgsl")),
+    ("fathom::mesh2d", include_str!("builtin/mesh2d.wgsl")),
+];
+
+pub fn builtin_shader_module(name: &str) -> Option<&'static str> {
+    BUILTIN_SHADER_MODULES.iter()
+        .find(|(module_name, _)| *module_name == name)
+        .map(|(_, content)| *content)
+}
+
+/// A name checked by `#ifdef`/`#ifndef`. A def with a value can also be substituted into the
+/// source with `#{NAME}`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ShaderDef {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl ShaderDef {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: None,
+        }
+    }
+
+    pub fn with_value(name: impl Into<String>, value: impl ToString) -> Self {
+        Self {
+            name: name.into(),
+            value: Some(value.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("{path}:{line}: {kind}")]
+pub struct ShaderPreprocessorError {
+    pub path: String,
+    pub line: u32,
+    pub kind: ShaderPreprocessorErrorKind,
+}
+
+#[derive(Clone, Debug, Error)]
+pub enum ShaderPreprocessorErrorKind {
+    #[error("unknown directive `#{0}`")]
+    UnknownDirective(String),
+    #[error("`#import` expects a quoted asset path or a built-in module name")]
+    InvalidImport,
+    #[error("unknown built-in shader module `{0}`")]
+    UnknownBuiltinModule(String),
+    #[error("built-in shader modules can only import other built-in modules, found `{0}`")]
+    AssetImportInBuiltinModule(String),
+    #[error("`#{0}` expects a shader def name")]
+    MissingShaderDefName(String),
+    #[error("`#{0}` without a matching `#ifdef` or `#ifndef`")]
+    UnmatchedDirective(String),
+    #[error("`#ifdef` or `#ifndef` is never closed with `#endif`")]
+    UnterminatedConditional,
+    #[error("shader def `{0}` has no value to substitute")]
+    MissingShaderDefValue(String),
+}
+
+pub enum ShaderImport {
+    /// An asset path, resolved relative to the importing shader
+    Asset(String),
+    Builtin(String),
+}
+
+/// Parses an `#import "path.wgsl"` or `#import fathom::module` line. Returns `None` for any other
+/// line
+pub fn parse_import(line: &str) -> Option<Result<ShaderImport, ShaderPreprocessorErrorKind>> {
+    let import = line.trim().strip_prefix("#import")?;
+    if !import.is_empty() && !import.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let import = import.trim();
+    if let Some(path) = import.strip_prefix('"') {
+        return Some(path.strip_suffix('"')
+            .filter(|path| !path.is_empty())
+            .map(|path| ShaderImport::Asset(path.to_string()))
+            .ok_or(ShaderPreprocessorErrorKind::InvalidImport));
+    }
+    if import.is_empty() || import.contains(char::is_whitespace) {
+        return Some(Err(ShaderPreprocessorErrorKind::InvalidImport));
+    }
+    Some(Ok(ShaderImport::Builtin(import.to_string())))
+}
+
+#[derive(Clone, Debug)]
+struct SourceLine {
+    text: String,
+    /// Index into [`ShaderSource::modules`]
+    module: usize,
+    line: u32,
+}
+
+/// The source of a shader with every import inlined. Each imported module is only included once.
+/// The remaining preprocessor directives are kept so the source can be composed with different
+/// shader defs
+#[derive(Clone, Debug, Default)]
+pub struct ShaderSource {
+    /// Asset paths or built-in module names of every module in the source, starting with the
+    /// shader itself
+    modules: Vec<String>,
+    lines: Vec<SourceLine>,
+}
+
+impl ShaderSource {
+    pub fn new(module: impl Into<String>) -> Self {
+        Self {
+            modules: vec![module.into()],
+            lines: Vec::new(),
+        }
+    }
+
+    /// Preprocesses a built-in module, inlining the built-in modules it imports
+    pub fn from_builtin(name: &str) -> Result<Self, ShaderPreprocessorErrorKind> {
+        let content = builtin_shader_module(name)
+            .ok_or_else(|| ShaderPreprocessorErrorKind::UnknownBuiltinModule(name.to_string()))?;
+
+        let mut source = Self::new(name);
+        for (line_index, line) in content.lines().enumerate() {
+            match parse_import(line) {
+                None => source.push_line(line, line_index as u32 + 1),
+                Some(Ok(ShaderImport::Builtin(import))) => source.append_import(&Self::from_builtin(&import)?),
+                Some(Ok(ShaderImport::Asset(import))) => {
+                    return Err(ShaderPreprocessorErrorKind::AssetImportInBuiltinModule(import));
+                }
+                Some(Err(kind)) => return Err(kind),
+            }
+        }
+        Ok(source)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.modules[0]
+    }
+
+    /// Appends a line of the shader itself
+    pub fn push_line(&mut self, text: &str, line: u32) {
+        self.lines.push(SourceLine {
+            text: text.to_string(),
+            module: 0,
+            line,
+        });
+    }
+
+    /// Appends the lines of an imported module, skipping the modules that were already included
+    pub fn append_import(&mut self, imported: &ShaderSource) {
+        let module_indices: Vec<Option<usize>> = imported.modules.iter()
+            .map(|module| {
+                if self.modules.contains(module) {
+                    None
+                } else {
+                    self.modules.push(module.clone());
+                    Some(self.modules.len() - 1)
+                }
+            })
+            .collect();
+
+        for line in &imported.lines {
+            if let Some(module) = module_indices[line.module] {
+                self.lines.push(SourceLine {
+                    text: line.text.clone(),
+                    module,
+                    line: line.line,
+                });
+            }
+        }
+    }
+
+    /// Evaluates `#define`, `#ifdef`, `#ifndef`, `#else` and `#endif` with the given shader defs
+    /// and substitutes `#{NAME}` with the value of the def
+    pub fn compose(&self, shader_defs: &[ShaderDef]) -> Result<ComposedShader, ShaderPreprocessorError> {
+        struct Conditional<'a> {
+            parent_active: bool,
+            condition: bool,
+            active: bool,
+            in_else: bool,
+            opened_at: &'a SourceLine,
+        }
+
+        let mut defined: HashMap<String, Option<String>> = shader_defs.iter()
+            .map(|shader_def| (shader_def.name.clone(), shader_def.value.clone()))
+            .collect();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut composed = ComposedShader {
+            source: String::new(),
+            modules: self.modules.clone(),
+            line_origins: Vec::new(),
+        };
+
+        for source_line in &self.lines {
+            let error = |kind| ShaderPreprocessorError {
+                path: self.modules[source_line.module].clone(),
+                line: source_line.line,
+                kind,
+            };
+            let active = conditionals.last().map_or(true, |conditional| conditional.active);
+
+            if let Some((directive, argument)) = parse_directive(&source_line.text) {
+                match directive {
+                    "ifdef" | "ifndef" => {
+                        let name = argument.ok_or_else(|| error(ShaderPreprocessorErrorKind::MissingShaderDefName(directive.to_string())))?;
+                        let condition = defined.contains_key(name) == (directive == "ifdef");
+                        conditionals.push(Conditional {
+                            parent_active: active,
+                            condition,
+                            active: active && condition,
+                            in_else: false,
+                            opened_at: source_line,
+                        });
+                    }
+                    "else" => {
+                        let conditional = conditionals.last_mut()
+                            .filter(|conditional| !conditional.in_else)
+                            .ok_or_else(|| error(ShaderPreprocessorErrorKind::UnmatchedDirective(directive.to_string())))?;
+                        conditional.in_else = true;
+                        conditional.active = conditional.parent_active && !conditional.condition;
+                    }
+                    "endif" => {
+                        conditionals.pop()
+                            .ok_or_else(|| error(ShaderPreprocessorErrorKind::UnmatchedDirective(directive.to_string())))?;
+                    }
+                    "define" => {
+                        let definition = argument.ok_or_else(|| error(ShaderPreprocessorErrorKind::MissingShaderDefName(directive.to_string())))?;
+                        if active {
+                            let mut parts = definition.splitn(2, char::is_whitespace);
+                            let name = parts.next().unwrap_or_default().to_string();
+                            let value = parts.next().map(|value| value.trim().to_string());
+                            defined.insert(name, value);
+                        }
+                    }
+                    _ => return Err(error(ShaderPreprocessorErrorKind::UnknownDirective(directive.to_string()))),
+                }
+                continue;
+            }
+
+            if active {
+                let text = substitute_shader_defs(&source_line.text, &defined).map_err(error)?;
+                composed.source.push_str(&text);
+                composed.source.push('\n');
+                composed.line_origins.push((source_line.module, source_line.line));
+            }
+        }
+
+        if let Some(conditional) = conditionals.last() {
+            return Err(ShaderPreprocessorError {
+                path: self.modules[conditional.opened_at.module].clone(),
+                line: conditional.opened_at.line,
+                kind: ShaderPreprocessorErrorKind::UnterminatedConditional,
+            });
+        }
+
+        Ok(composed)
+    }
+}
+
+/// Splits a `#directive argument` line. `#{NAME}` substitutions are not directives
+fn parse_directive(line: &str) -> Option<(&str, Option<&str>)> {
+    let directive = line.trim().strip_prefix('#')?;
+    if !directive.starts_with(|character: char| character.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let mut parts = directive.splitn(2, char::is_whitespace);
+    let name = parts.next()?;
+    let argument = parts.next().map(str::trim).filter(|argument| !argument.is_empty());
+    Some((name, argument))
+}
+
+fn substitute_shader_defs(
+    line: &str,
+    defined: &HashMap<String, Option<String>>,
+) -> Result<String, ShaderPreprocessorErrorKind> {
+    if !line.contains("#{") {
+        return Ok(line.to_string());
+    }
+
+    let mut substituted = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("#{") {
+        let Some(length) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + length];
+        let value = defined.get(name)
+            .and_then(Option::as_deref)
+            .ok_or_else(|| ShaderPreprocessorErrorKind::MissingShaderDefValue(name.to_string()))?;
+        substituted.push_str(&rest[..start]);
+        substituted.push_str(value);
+        rest = &rest[start + length + 1..];
+    }
+    substituted.push_str(rest);
+    Ok(substituted)
+}
+
+/// WGSL produced by [`ShaderSource::compose`], which remembers where each line came from so errors
+/// can point at the file that contains the offending line
+#[derive(Clone, Debug)]
+pub struct ComposedShader {
+    pub source: String,
+    modules: Vec<String>,
+    line_origins: Vec<(usize, u32)>,
+}
+
+impl ComposedShader {
+    /// The path of the shader that was composed
+    pub fn path(&self) -> &str {
+        &self.modules[0]
+    }
+
+    /// The module path and line number a 1-based line of the composed source came from
+    pub fn origin(&self, line_number: u32) -> Option<(&str, u32)> {
+        let (module, line) = self.line_origins.get(line_number.checked_sub(1)? as usize)?;
+        Some((self.modules[*module].as_str(), *line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(path: &str, content: &str) -> ShaderSource {
+        let mut source = ShaderSource::new(path);
+        for (line_index, line) in content.lines().enumerate() {
+            source.push_line(line, line_index as u32 + 1);
+        }
+        source
+    }
+
+    fn composed_lines(source: &ShaderSource, shader_defs: &[ShaderDef]) -> Vec<String> {
+        source.compose(shader_defs).unwrap()
+            .source
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn nested_conditionals() {
+        let shader = source("shader.wgsl", "\
+#ifdef A
+a
+#ifndef B
+a_not_b
+#else
+a_b
+#endif
+#else
+not_a
+#ifdef B
+not_a_b
+#endif
+#endif
+always");
+
+        assert_eq!(composed_lines(&shader, &[]), ["not_a", "always"]);
+        assert_eq!(composed_lines(&shader, &[ShaderDef::new("A")]), ["a", "a_not_b", "always"]);
+        assert_eq!(composed_lines(&shader, &[ShaderDef::new("A"), ShaderDef::new("B")]), ["a", "a_b", "always"]);
+        assert_eq!(composed_lines(&shader, &[ShaderDef::new("B")]), ["not_a", "not_a_b", "always"]);
+    }
+
+    #[test]
+    fn define_only_applies_when_active() {
+        let shader = source("shader.wgsl", "\
+#ifdef A
+#define B
+#endif
+#ifdef B
+b
+#endif");
+
+        assert!(composed_lines(&shader, &[]).is_empty());
+        assert_eq!(composed_lines(&shader, &[ShaderDef::new("A")]), ["b"]);
+    }
+
+    #[test]
+    fn substitutes_shader_def_values() {
+        let shader = source("shader.wgsl", "\
+#define COUNT 4u
+var<uniform> values: array<f32, #{COUNT}>;
+const SCALE: f32 = #{SCALE} * #{SCALE};");
+
+        let lines = composed_lines(&shader, &[ShaderDef::with_value("SCALE", 0.5)]);
+        assert_eq!(lines, ["var<uniform> values: array<f32, 4u>;", "const SCALE: f32 = 0.5 * 0.5;"]);
+    }
+
+    #[test]
+    fn substituting_a_def_without_a_value_fails() {
+        let shader = source("shader.wgsl", "\nlet x = #{FLAG};");
+
+        let error = shader.compose(&[ShaderDef::new("FLAG")]).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(matches!(error.kind, ShaderPreprocessorErrorKind::MissingShaderDefValue(name) if name == "FLAG"));
+    }
+
+    #[test]
+    fn unmatched_and_unterminated_conditionals_fail() {
+        let error = source("shader.wgsl", "a\n#endif").compose(&[]).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(matches!(error.kind, ShaderPreprocessorErrorKind::UnmatchedDirective(_)));
+
+        let error = source("shader.wgsl", "#ifdef A\n#else\n#else\n#endif").compose(&[]).unwrap_err();
+        assert_eq!(error.line, 3);
+        assert!(matches!(error.kind, ShaderPreprocessorErrorKind::UnmatchedDirective(_)));
+
+        // Reported at the directive that was never closed
+        let error = source("shader.wgsl", "#ifdef A\n#endif\n#ifndef B\na").compose(&[]).unwrap_err();
+        assert_eq!(error.line, 3);
+        assert!(matches!(error.kind, ShaderPreprocessorErrorKind::UnterminatedConditional));
+    }
+
+    #[test]
+    fn duplicate_imports_are_included_once() {
+        let common = source("common.wgsl", "common");
+        let mut first = source("first.wgsl", "first");
+        first.append_import(&common);
+        let mut second = source("second.wgsl", "second");
+        second.append_import(&common);
+
+        let mut shader = source("shader.wgsl", "shader");
+        shader.append_import(&first);
+        shader.append_import(&second);
+        shader.append_import(&common);
+
+        assert_eq!(composed_lines(&shader, &[]), ["shader", "first", "common", "second"]);
+    }
+
+    #[test]
+    fn cyclic_imports_are_included_once() {
+        let shader_path = "shader.wgsl";
+        // The imported module already imported the shader that is importing it
+        let mut imported = source("imported.wgsl", "imported");
+        imported.append_import(&source(shader_path, "shader"));
+
+        let mut shader = source(shader_path, "shader");
+        shader.append_import(&imported);
+
+        assert_eq!(composed_lines(&shader, &[]), ["shader", "imported"]);
+    }
+
+    #[test]
+    fn maps_composed_lines_to_their_origin() {
+        let imported = source("imported.wgsl", "#ifdef A\nskipped\n#endif\nimported");
+        let mut shader = source("shader.wgsl", "first");
+        shader.append_import(&imported);
+        shader.push_line("last", 2);
+
+        let composed = shader.compose(&[]).unwrap();
+        assert_eq!(composed.path(), "shader.wgsl");
+        assert_eq!(composed.origin(1), Some(("shader.wgsl", 1)));
+        assert_eq!(composed.origin(2), Some(("imported.wgsl", 4)));
+        assert_eq!(composed.origin(3), Some(("shader.wgsl", 2)));
+        assert_eq!(composed.origin(0), None);
+        assert_eq!(composed.origin(4), None);
+    }
+
+    #[test]
+    fn errors_point_at_the_imported_module() {
+        let imported = source("imported.wgsl", "a\n#unknown");
+        let mut shader = source("shader.wgsl", "b");
+        shader.append_import(&imported);
+
+        let error = shader.compose(&[]).unwrap_err();
+        assert_eq!(error.path, "imported.wgsl");
+        assert_eq!(error.line, 2);
+        assert!(matches!(error.kind, ShaderPreprocessorErrorKind::UnknownDirective(directive) if directive == "unknown"));
+    }
+
+    #[test]
+    fn parses_imports() {
+        assert!(matches!(parse_import("#import \"shaders/common.wgsl\""), Some(Ok(ShaderImport::Asset(path))) if path == "shaders/common.wgsl"));
+        assert!(matches!(parse_import("  #import fathom::camera"), Some(Ok(ShaderImport::Builtin(name))) if name == "fathom::camera"));
+        assert!(matches!(parse_import("#import \"\""), Some(Err(ShaderPreprocessorErrorKind::InvalidImport))));
+        assert!(matches!(parse_import("#import fathom::camera fathom::lights"), Some(Err(ShaderPreprocessorErrorKind::InvalidImport))));
+        assert!(parse_import("#imports").is_none());
+        assert!(parse_import("let x = 1;").is_none());
+    }
+
+    #[test]
+    fn builtin_modules_compose() {
+        for (name, _) in BUILTIN_SHADER_MODULES {
+            ShaderSource::from_builtin(name).unwrap().compose(&[]).unwrap();
+        }
+    }
+}
@@ -1,7 +1,213 @@
-use bevy::prelude::Event;
-use winit::event::KeyEvent;
+use std::hash::Hash;
+use bevy::prelude::{Event, EventReader, ResMut, Resource};
+use bevy::utils::HashSet;
+use winit::dpi::PhysicalPosition;
+use winit::event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, Touch, TouchPhase};
+use winit::keyboard::{KeyCode, PhysicalKey};
+
+/// Number of pixels a single line of [`MouseScrollDelta::LineDelta`] scrolls
+const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
 
 #[derive(Event)]
 pub enum InputEvent {
-    Keyboard(KeyEvent)
-}
\ No newline at end of file
+    Keyboard(KeyEvent),
+    MouseButton {
+        button: MouseButton,
+        state: ElementState,
+    },
+    /// The cursor moved within the window. The position is in physical pixels relative to the
+    /// top left corner of the window
+    CursorMoved {
+        position: PhysicalPosition<f64>,
+    },
+    CursorEntered,
+    CursorLeft,
+    /// Raw, unaccelerated mouse movement reported by the device. Unlike [`InputEvent::CursorMoved`]
+    /// this keeps being sent when the cursor is at the edge of the window or the screen, which
+    /// makes it the right choice for mouse-look camera controllers
+    MouseMotion {
+        delta: (f64, f64),
+    },
+    MouseWheel {
+        delta: MouseScrollDelta,
+        phase: TouchPhase,
+    },
+    Touch(Touch),
+    /// The window lost focus. Releases are not reported while it is unfocused, so every held key
+    /// and mouse button is released
+    FocusLost,
+}
+
+/// Tracks which buttons are held down, along with which were pressed or released this frame
+#[derive(Debug)]
+pub struct ButtonInput<T: Copy + Eq + Hash> {
+    pressed: HashSet<T>,
+    just_pressed: HashSet<T>,
+    just_released: HashSet<T>,
+}
+
+impl<T: Copy + Eq + Hash> Default for ButtonInput<T> {
+    fn default() -> Self {
+        Self {
+            pressed: HashSet::new(),
+            just_pressed: HashSet::new(),
+            just_released: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> ButtonInput<T> {
+    /// Marks the button as pressed. Pressing a button that is already held down (e.g. from key
+    /// repeat) does not mark it as just pressed again
+    pub fn press(&mut self, button: T) {
+        if self.pressed.insert(button) {
+            self.just_pressed.insert(button);
+        }
+    }
+
+    pub fn release(&mut self, button: T) {
+        if self.pressed.remove(&button) {
+            self.just_released.insert(button);
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.just_released.extend(self.pressed.drain());
+    }
+
+    /// Whether the button is currently held down
+    pub fn pressed(&self, button: T) -> bool {
+        self.pressed.contains(&button)
+    }
+
+    /// Whether the button went down this frame
+    pub fn just_pressed(&self, button: T) -> bool {
+        self.just_pressed.contains(&button)
+    }
+
+    /// Whether the button went up this frame
+    pub fn just_released(&self, button: T) -> bool {
+        self.just_released.contains(&button)
+    }
+
+    pub fn any_pressed(&self, buttons: impl IntoIterator<Item = T>) -> bool {
+        buttons.into_iter().any(|button| self.pressed(button))
+    }
+
+    pub fn get_pressed(&self) -> impl Iterator<Item = &T> {
+        self.pressed.iter()
+    }
+
+    pub fn get_just_pressed(&self) -> impl Iterator<Item = &T> {
+        self.just_pressed.iter()
+    }
+
+    pub fn get_just_released(&self) -> impl Iterator<Item = &T> {
+        self.just_released.iter()
+    }
+
+    /// Clears the just pressed and just released state. Called at the start of every frame
+    pub fn clear(&mut self) {
+        self.just_pressed.clear();
+        self.just_released.clear();
+    }
+}
+
+/// The state of the keyboard, keyed by the physical location of each key
+#[derive(Resource, Default, Debug)]
+pub struct KeyboardState {
+    pub keys: ButtonInput<KeyCode>,
+}
+
+impl KeyboardState {
+    pub fn pressed(&self, key: KeyCode) -> bool {
+        self.keys.pressed(key)
+    }
+
+    pub fn just_pressed(&self, key: KeyCode) -> bool {
+        self.keys.just_pressed(key)
+    }
+
+    pub fn just_released(&self, key: KeyCode) -> bool {
+        self.keys.just_released(key)
+    }
+}
+
+/// The state of the mouse. Motion and scroll are accumulated over the current frame
+#[derive(Resource, Default, Debug)]
+pub struct MouseState {
+    pub buttons: ButtonInput<MouseButton>,
+    /// Position of the cursor in physical pixels, or `None` when it is outside the window
+    pub cursor_position: Option<PhysicalPosition<f64>>,
+    /// Raw mouse movement since the last frame
+    pub motion: (f64, f64),
+    /// Scroll since the last frame in pixels
+    pub scroll: (f32, f32),
+}
+
+impl MouseState {
+    pub fn pressed(&self, button: MouseButton) -> bool {
+        self.buttons.pressed(button)
+    }
+
+    pub fn just_pressed(&self, button: MouseButton) -> bool {
+        self.buttons.just_pressed(button)
+    }
+
+    pub fn just_released(&self, button: MouseButton) -> bool {
+        self.buttons.just_released(button)
+    }
+}
+
+/// Clears last frame's state and applies every [`InputEvent`] received since then to the
+/// [`KeyboardState`] and [`MouseState`] resources
+pub fn update_input_state(
+    mut input_events: EventReader<InputEvent>,
+    mut keyboard_state: ResMut<KeyboardState>,
+    mut mouse_state: ResMut<MouseState>,
+) {
+    keyboard_state.keys.clear();
+    mouse_state.buttons.clear();
+    mouse_state.motion = (0.0, 0.0);
+    mouse_state.scroll = (0.0, 0.0);
+
+    for event in input_events.read() {
+        match event {
+            InputEvent::Keyboard(KeyEvent { physical_key: PhysicalKey::Code(key_code), state, .. }) => {
+                match state {
+                    ElementState::Pressed => keyboard_state.keys.press(*key_code),
+                    ElementState::Released => keyboard_state.keys.release(*key_code),
+                }
+            }
+            InputEvent::MouseButton { button, state } => {
+                match state {
+                    ElementState::Pressed => mouse_state.buttons.press(*button),
+                    ElementState::Released => mouse_state.buttons.release(*button),
+                }
+            }
+            InputEvent::CursorMoved { position } => {
+                mouse_state.cursor_position = Some(*position);
+            }
+            InputEvent::CursorLeft => {
+                mouse_state.cursor_position = None;
+            }
+            InputEvent::FocusLost => {
+                keyboard_state.keys.release_all();
+                mouse_state.buttons.release_all();
+            }
+            InputEvent::MouseMotion { delta } => {
+                mouse_state.motion.0 += delta.0;
+                mouse_state.motion.1 += delta.1;
+            }
+            InputEvent::MouseWheel { delta, .. } => {
+                let (x, y) = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => (x * PIXELS_PER_SCROLL_LINE, y * PIXELS_PER_SCROLL_LINE),
+                    MouseScrollDelta::PixelDelta(position) => (position.x as f32, position.y as f32),
+                };
+                mouse_state.scroll.0 += x;
+                mouse_state.scroll.1 += y;
+            }
+            _ => ()
+        }
+    }
+}
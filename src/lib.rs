@@ -8,19 +8,31 @@ use bevy::app::{App, PluginGroupBuilder};
 use bevy::ecs::schedule::ExecutorKind;
 use bevy::prelude::{AppExit, Local, Mut, Plugin, PluginGroup, Schedule, World};
 use winit::event_loop::{ControlFlow, EventLoop};
-use crate::app::{schedule, WinitApplicationState};
+use crate::app::{schedule, HeadlessSettings, WinitApplicationState};
+use crate::time::{run_fixed_main, update_time, FixedTime, Time};
 
 pub mod app;
 pub mod renderer;
 pub mod assets;
 pub mod input;
+pub mod time;
+pub mod transform;
+pub mod testing;
 
-struct FathomDefaultPlugins;
+/// The plugins every Fathom application needs. Headless applications are driven by a simple loop
+/// instead of a winit event loop
+struct FathomDefaultPlugins {
+    headless: Option<HeadlessSettings>,
+}
 
 impl PluginGroup for FathomDefaultPlugins {
     fn build(self) -> PluginGroupBuilder {
-        PluginGroupBuilder::start::<Self>()
-            .add(FathomRunnerPlugin)
+        let group = PluginGroupBuilder::start::<Self>()
+            .add(FathomRunnerPlugin);
+        match self.headless {
+            Some(settings) => group.add(HeadlessRunnerPlugin(settings)),
+            None => group.add(WinitRunnerPlugin),
+        }
     }
 }
 
@@ -28,11 +40,6 @@ struct FathomRunnerPlugin;
 
 impl Plugin for FathomRunnerPlugin {
     fn build(&self, app: &mut App) {
-        let event_loop = EventLoop::new().unwrap();
-        event_loop.set_control_flow(ControlFlow::Poll);
-        app.insert_non_send_resource(event_loop);
-        app.set_runner(fathom_app_runner);
-
         let mut main_schedule = Schedule::new(schedule::Main);
         // TODO: Figure out why bevy does this for "facilitator" schedules
         main_schedule.set_executor_kind(ExecutorKind::SingleThreaded);
@@ -41,6 +48,8 @@ impl Plugin for FathomRunnerPlugin {
         app.add_schedule(Schedule::new(schedule::Startup));
         app.add_schedule(Schedule::new(schedule::PostStartup));
         app.add_schedule(Schedule::new(schedule::First));
+        app.add_schedule(Schedule::new(schedule::FixedMain));
+        app.add_schedule(Schedule::new(schedule::FixedUpdate));
         app.add_schedule(Schedule::new(schedule::Update));
         app.add_schedule(Schedule::new(schedule::PreRender));
         app.add_schedule(Schedule::new(schedule::Last));
@@ -49,6 +58,31 @@ impl Plugin for FathomRunnerPlugin {
             .init_resource::<schedule::MainScheduleOrder>()
             .add_systems(schedule::Main, run_main);
 
+        app.init_resource::<Time>()
+            .init_resource::<FixedTime>()
+            .add_systems(schedule::First, update_time)
+            .add_systems(schedule::FixedMain, run_fixed_main);
+
+    }
+}
+
+struct WinitRunnerPlugin;
+
+impl Plugin for WinitRunnerPlugin {
+    fn build(&self, app: &mut App) {
+        let event_loop = EventLoop::new().unwrap();
+        event_loop.set_control_flow(ControlFlow::Poll);
+        app.insert_non_send_resource(event_loop);
+        app.set_runner(fathom_app_runner);
+    }
+}
+
+struct HeadlessRunnerPlugin(HeadlessSettings);
+
+impl Plugin for HeadlessRunnerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.0.clone());
+        app.set_runner(headless_app_runner);
     }
 }
 
@@ -66,6 +100,23 @@ fn fathom_app_runner(mut app: App) -> AppExit {
     AppExit::Success
 }
 
+/// Updates the app in a loop without a window until it exits or has run
+/// [`HeadlessSettings::frame_count`] frames
+fn headless_app_runner(mut app: App) -> AppExit {
+    let frame_count = app.world().resource::<HeadlessSettings>().frame_count;
+    let mut frames_run = 0;
+    while frame_count.map_or(true, |frame_count| frames_run < frame_count) {
+        app.update();
+        frames_run += 1;
+        if let Some(exit) = app.should_exit() {
+            return exit;
+        }
+    }
+
+    log::info!("Ran {} headless frames, exiting", frames_run);
+    AppExit::Success
+}
+
 pub fn run_main(world: &mut World, mut run_at_least_once: Local<bool>) {
     if !*run_at_least_once {
         world.resource_scope(|world, order: Mut<schedule::MainScheduleOrder>| {
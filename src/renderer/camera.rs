@@ -1,7 +1,561 @@
-use bevy::math::Mat4;
-use bevy::prelude::Component;
+use std::f32::consts::PI;
+use std::mem::offset_of;
+use std::num::NonZeroU64;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bytemuck::{Pod, Zeroable};
+use crate::app::schedule;
+use crate::assets::materials::Material;
+use crate::renderer::frame::{ClearColor, Frame};
+use crate::renderer::light::MAX_DIRECTIONAL_LIGHTS;
+use crate::renderer::material::MaterialBindGroups;
+use crate::renderer::shadow::MAX_CASCADES;
+use crate::renderer::texture::{DepthTexture, GpuImages, Image};
+use crate::renderer::viewport::{Viewport, ViewportClear};
+use crate::renderer::RendererState;
+use crate::transform::{GlobalTransform, Transform};
 
-#[derive(Component)]
+const INITIAL_CAMERA_CAPACITY: usize = 4;
+
+/// A perspective camera. Where it is and which way it looks comes from its [`Transform`], the
+/// camera looks down its local -Z axis. Every camera has its own directional light shadow cascades
+/// fit to its view, so the memory they take grows with the number of cameras
+#[derive(Component, Clone, Debug)]
+#[require(Transform)]
 pub struct Camera {
-    pub transform: Mat4
-}
\ No newline at end of file
+    /// Vertical field of view in radians
+    pub fov_y: f32,
+    pub near: f32,
+    pub far: f32,
+    pub target: RenderTarget,
+    /// The part of the target the camera draws into, all of it when `None`
+    pub viewport: Option<Viewport>,
+    /// Cameras are drawn in ascending order, so a camera draws over those with a lower order that
+    /// share its target. Cameras that render to images are drawn before those that render to the
+    /// window regardless of their order
+    pub order: isize,
+    pub clear: CameraClear,
+    /// Only meshes on one of these layers are drawn by the camera
+    pub layers: RenderLayers,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            fov_y: 2.0 * PI / 5.0,
+            near: 0.1,
+            far: 100.0,
+            target: RenderTarget::Window,
+            viewport: None,
+            order: 0,
+            clear: CameraClear::Default,
+            layers: RenderLayers::default(),
+        }
+    }
+}
+
+/// What a [`Camera`] renders to
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RenderTarget {
+    /// The window, or the offscreen texture of a headless application
+    #[default]
+    Window,
+    /// An image created with [`Image::render_target`]. Cameras that render to images are drawn
+    /// before the ones that render to the window, so materials can sample the image in the same
+    /// frame. Meshes whose material samples the image are skipped by the camera rendering to it
+    Image(Handle<Image>),
+}
+
+/// What a [`Camera`] clears its viewport to before it draws
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CameraClear {
+    /// Clears color to the [`ClearColor`] and clears depth
+    #[default]
+    Default,
+    /// Clears color to the given color and clears depth
+    Color(wgpu::Color),
+    /// Keeps the color drawn by the cameras before it and only clears depth, so everything it
+    /// draws is on top of them, e.g. for a HUD
+    DepthOnly,
+    /// Keeps both color and depth, so what it draws is depth tested against the cameras before it
+    None,
+}
+
+/// The layers an entity is on as a bit mask, one bit per layer. Meshes without this component are
+/// on layer 0, and a camera only draws the meshes that share a layer with its [`Camera::layers`]
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RenderLayers(pub u32);
+
+impl Default for RenderLayers {
+    fn default() -> Self {
+        Self::layer(0)
+    }
+}
+
+impl RenderLayers {
+    pub const ALL: Self = Self(u32::MAX);
+    pub const NONE: Self = Self(0);
+
+    /// Only `layer`, which must be less than 32
+    pub const fn layer(layer: u8) -> Self {
+        Self::NONE.with(layer)
+    }
+
+    /// Adds `layer`, which must be less than 32
+    pub const fn with(self, layer: u8) -> Self {
+        let bit = 1u32.checked_shl(layer as u32).expect("Render layer must be less than 32");
+        Self(self.0 | bit)
+    }
+
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl Camera {
+    pub fn projection_matrix(&self, aspect_ratio: f32) -> Mat4 {
+        Mat4::perspective_rh(self.fov_y, aspect_ratio, self.near, self.far)
+    }
+
+    /// Aspect ratio of the camera's viewport in a `target_width` by `target_height` target
+    pub fn aspect_ratio(&self, target_width: u32, target_height: u32) -> f32 {
+        let (width, height) = match self.viewport {
+            Some(viewport) => {
+                let viewport = viewport.to_pixels(target_width, target_height);
+                (viewport.width(), viewport.height())
+            }
+            None => (target_width as f32, target_height as f32),
+        };
+        width / height.max(1.0)
+    }
+}
+
+/// Laid out to match `CameraUniforms` in the `fathom::camera` shader module
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuCameraUniform {
+    pub view_projection: [[f32; 4]; 4],
+    /// Transforms world space into view space, used to pick a shadow cascade by view depth
+    pub view: [[f32; 4]; 4],
+    /// The world space position of the camera, used for specular lighting
+    pub position: [f32; 4],
+    /// Written by [`prepare_lights`](crate::renderer::light::prepare_lights) after the rest
+    pub shadows: GpuCameraShadows,
+}
+
+/// The shadow cascades of every directional light fit to one camera, laid out to match
+/// `CameraShadows` in the `fathom::camera` shader module
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuCameraShadows {
+    /// Far distance from the camera of each cascade, indexed by directional light
+    pub cascade_splits: [[f32; 4]; MAX_DIRECTIONAL_LIGHTS],
+    /// [`MAX_CASCADES`] view-projections per directional light
+    pub cascade_view_projections: [[[f32; 4]; 4]; MAX_DIRECTIONAL_LIGHTS * MAX_CASCADES],
+    /// The first layer of the camera's cascades in the directional shadow maps
+    pub first_layer: u32,
+    pub _padding: [u32; 3],
+}
+
+impl Default for GpuCameraUniform {
+    fn default() -> Self {
+        Self {
+            view_projection: Mat4::IDENTITY.to_cols_array_2d(),
+            view: Mat4::IDENTITY.to_cols_array_2d(),
+            position: [0.0, 0.0, 0.0, 1.0],
+            shadows: GpuCameraShadows::zeroed(),
+        }
+    }
+}
+
+/// What a prepared camera draws into this frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum CameraTarget {
+    Window,
+    Image(AssetId<Image>),
+}
+
+/// A camera whose uniforms have been written this frame, see [`prepare_cameras`]
+#[derive(Clone, Debug)]
+pub(crate) struct PreparedCamera {
+    pub(crate) entity: Entity,
+    pub(crate) target: CameraTarget,
+    pub(crate) color_format: wgpu::TextureFormat,
+    /// Dynamic offset of the camera's data in the camera uniform buffer
+    pub(crate) uniform_offset: u32,
+    /// Aspect ratio of the camera's viewport
+    pub(crate) aspect_ratio: f32,
+    /// The viewport in pixels, `None` when it covers the whole target
+    viewport: Option<Rect>,
+    clear_color: Option<wgpu::Color>,
+    clear_depth: bool,
+    layers: RenderLayers,
+}
+
+impl PreparedCamera {
+    /// Whether the camera draws entities on `layers`, layer 0 for entities without [`RenderLayers`]
+    pub(crate) fn sees(&self, layers: Option<&RenderLayers>) -> bool {
+        self.layers.intersects(layers.copied().unwrap_or_default())
+    }
+
+    /// Whether the camera renders to an image the material samples, in which case the material
+    /// can not be drawn by this camera
+    pub(crate) fn renders_to_image_sampled_by(&self, material: &Handle<Material>, material_bind_groups: &MaterialBindGroups) -> bool {
+        match self.target {
+            CameraTarget::Window => false,
+            CameraTarget::Image(image) => material_bind_groups.samples_image(material, image),
+        }
+    }
+
+    /// The color view of the camera's target, `None` for the frame itself, and its depth view.
+    /// Returns `None` if the target image has not been uploaded yet
+    fn views<'a>(
+        &self,
+        camera_uniforms: &'a CameraUniforms,
+        gpu_images: &'a GpuImages,
+        renderer_state: &'a RendererState,
+    ) -> Option<(Option<&'a wgpu::TextureView>, &'a wgpu::TextureView)> {
+        match self.target {
+            CameraTarget::Window => Some((None, &renderer_state.depth_texture.view)),
+            CameraTarget::Image(image) => Some((
+                Some(&gpu_images.get(image)?.view),
+                &camera_uniforms.image_depth_textures.get(&image)?.view,
+            )),
+        }
+    }
+
+    /// Begins a render pass that draws into the camera's viewport of its target with its depth
+    /// texture. Returns `None` if the target image has not been uploaded yet
+    pub(crate) fn begin_render_pass<'a>(
+        &self,
+        frame: &'a mut Frame,
+        label: &'a str,
+        camera_uniforms: &'a CameraUniforms,
+        gpu_images: &'a GpuImages,
+        renderer_state: &'a RendererState,
+    ) -> Option<wgpu::RenderPass<'a>> {
+        let (color_view, depth_view) = self.views(camera_uniforms, gpu_images, renderer_state)?;
+        let mut render_pass = frame.begin_target_pass(label, self.target, color_view, Some(depth_view), None, false);
+        self.set_viewport(&mut render_pass);
+        Some(render_pass)
+    }
+
+    /// Clears the camera's viewport as set by its [`CameraClear`]. Clears of a camera covering its
+    /// whole target are load ops, partial viewports are cleared by drawing over them
+    fn clear(
+        &self,
+        frame: &mut Frame,
+        camera_uniforms: &CameraUniforms,
+        gpu_images: &GpuImages,
+        renderer_state: &RendererState,
+        viewport_clear: &ViewportClear,
+    ) {
+        if self.clear_color.is_none() && !self.clear_depth {
+            return;
+        }
+        let Some((color_view, depth_view)) = self.views(camera_uniforms, gpu_images, renderer_state) else {
+            return;
+        };
+
+        if self.viewport.is_none() {
+            frame.begin_target_pass("Camera clear pass", self.target, color_view, Some(depth_view), self.clear_color, self.clear_depth);
+        } else {
+            let mut render_pass = frame.begin_target_pass("Camera viewport clear pass", self.target, color_view, Some(depth_view), None, false);
+            self.set_viewport(&mut render_pass);
+            viewport_clear.draw(&mut render_pass, self.color_format, self.clear_color, self.clear_depth);
+        }
+    }
+
+    fn set_viewport(&self, render_pass: &mut wgpu::RenderPass) {
+        if let Some(viewport) = self.viewport {
+            render_pass.set_viewport(viewport.min.x, viewport.min.y, viewport.width(), viewport.height(), 0.0, 1.0);
+        }
+    }
+}
+
+/// The camera being drawn while the [`RenderCamera`](schedule::RenderCamera) schedule runs
+#[derive(Resource)]
+pub struct CurrentCamera(pub(crate) PreparedCamera);
+
+impl CurrentCamera {
+    pub fn entity(&self) -> Entity {
+        self.0.entity
+    }
+}
+
+/// The uniforms of every camera in a single buffer, each bound at group 0 with its own dynamic
+/// offset, along with a depth texture for every image a camera renders to
+#[derive(Resource)]
+pub struct CameraUniforms {
+    pub(crate) bind_group_layout: wgpu::BindGroupLayout,
+    buffer: wgpu::Buffer,
+    pub(crate) bind_group: wgpu::BindGroup,
+    /// Number of cameras the buffer can currently hold
+    capacity: usize,
+    /// Distance in bytes between two cameras in the buffer, padded to the device's minimum uniform
+    /// buffer offset alignment
+    stride: usize,
+    staging: Vec<u8>,
+    /// Cameras in the order they are rendered in
+    cameras: Vec<PreparedCamera>,
+    image_depth_textures: HashMap<AssetId<Image>, DepthTexture>,
+}
+
+impl CameraUniforms {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Self::binding_size(),
+                },
+                count: None,
+            }],
+            label: Some("Camera Bind Group Layout"),
+        });
+
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as usize;
+        let stride = size_of::<GpuCameraUniform>().div_ceil(alignment) * alignment;
+        let (buffer, bind_group) = Self::create_buffer(device, &bind_group_layout, INITIAL_CAMERA_CAPACITY, stride);
+
+        Self {
+            bind_group_layout,
+            buffer,
+            bind_group,
+            capacity: INITIAL_CAMERA_CAPACITY,
+            stride,
+            staging: Vec::new(),
+            cameras: Vec::new(),
+            image_depth_textures: HashMap::new(),
+        }
+    }
+
+    fn binding_size() -> Option<NonZeroU64> {
+        NonZeroU64::new(size_of::<GpuCameraUniform>() as u64)
+    }
+
+    fn create_buffer(
+        device: &wgpu::Device,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        capacity: usize,
+        stride: usize,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Camera Uniform Buffer"),
+            size: (capacity * stride) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffer,
+                    offset: 0,
+                    size: Self::binding_size(),
+                }),
+            }],
+            label: Some("Camera Bind Group"),
+        });
+
+        (buffer, bind_group)
+    }
+
+    /// Every camera prepared this frame, in the order they are rendered in
+    pub(crate) fn cameras(&self) -> &[PreparedCamera] {
+        &self.cameras
+    }
+
+    /// Writes the shadow cascades fit to `camera`, which must have been prepared this frame
+    pub(crate) fn write_shadows(&self, queue: &wgpu::Queue, camera: &PreparedCamera, shadows: &GpuCameraShadows) {
+        let offset = camera.uniform_offset as usize + offset_of!(GpuCameraUniform, shadows);
+        queue.write_buffer(&self.buffer, offset as wgpu::BufferAddress, bytemuck::bytes_of(shadows));
+    }
+
+    /// The color formats of every camera's target, which pipelines are needed for
+    pub(crate) fn color_formats(&self) -> Vec<wgpu::TextureFormat> {
+        let mut color_formats = Vec::new();
+        for camera in &self.cameras {
+            if !color_formats.contains(&camera.color_format) {
+                color_formats.push(camera.color_format);
+            }
+        }
+        color_formats
+    }
+}
+
+/// A camera that is drawn this frame, gathered by [`prepare_cameras`]
+struct CameraView<'a> {
+    entity: Entity,
+    camera: &'a Camera,
+    global_transform: &'a GlobalTransform,
+    target: CameraTarget,
+    color_format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    viewport: Option<Rect>,
+}
+
+/// Writes the view-projection matrix, view matrix and position of every camera into the camera
+/// uniform buffer and sorts the cameras by their order, with those that render to images first.
+/// Cameras whose target image has not been uploaded yet or whose viewport is empty are skipped.
+/// Their shadow cascades are written afterwards by
+/// [`prepare_lights`](crate::renderer::light::prepare_lights)
+pub fn prepare_cameras(
+    renderer_state: Res<RendererState>,
+    mut camera_uniforms: ResMut<CameraUniforms>,
+    mut viewport_clear: ResMut<ViewportClear>,
+    cameras: Query<(Entity, &Camera, &GlobalTransform)>,
+    gpu_images: Res<GpuImages>,
+    clear_color: Res<ClearColor>,
+    mut warned_about_target: Local<bool>,
+) {
+    let device = &renderer_state.device;
+    let camera_uniforms = camera_uniforms.as_mut();
+    let mut views = Vec::new();
+    for (entity, camera, global_transform) in &cameras {
+        let (target, color_format, width, height) = match &camera.target {
+            RenderTarget::Window => (
+                CameraTarget::Window,
+                renderer_state.config.format,
+                renderer_state.config.width,
+                renderer_state.config.height,
+            ),
+            RenderTarget::Image(image) => {
+                let Some(gpu_image) = gpu_images.get(image) else {
+                    continue;
+                };
+                if !gpu_image.texture.usage().contains(wgpu::TextureUsages::RENDER_ATTACHMENT) {
+                    if !*warned_about_target {
+                        log::warn!("Camera {:?} renders to an image that is not a render target, create it with Image::render_target", entity);
+                        *warned_about_target = true;
+                    }
+                    continue;
+                }
+                let texture = &gpu_image.texture;
+                (CameraTarget::Image(image.id()), texture.format(), texture.width(), texture.height())
+            }
+        };
+        let viewport = camera.viewport.map(|viewport| viewport.to_pixels(width, height));
+        if viewport.is_some_and(|viewport| viewport.is_empty()) {
+            continue;
+        }
+        views.push(CameraView {
+            entity,
+            camera,
+            global_transform,
+            target,
+            color_format,
+            width,
+            height,
+            viewport,
+        });
+    }
+    views.sort_by_key(|view| (matches!(view.target, CameraTarget::Window), view.camera.order));
+
+    // Image targets each get a depth texture of their size, which is dropped once no camera uses it
+    camera_uniforms.image_depth_textures
+        .retain(|image, _| views.iter().any(|view| view.target == CameraTarget::Image(*image)));
+    for view in &views {
+        let CameraTarget::Image(image) = view.target else {
+            continue;
+        };
+        let depth_texture = camera_uniforms.image_depth_textures.get(&image);
+        if depth_texture.map_or(true, |depth_texture| depth_texture.texture.width() != view.width || depth_texture.texture.height() != view.height) {
+            camera_uniforms.image_depth_textures.insert(image, DepthTexture::new(device, view.width, view.height));
+        }
+    }
+
+    if views.len() > camera_uniforms.capacity {
+        let capacity = views.len().next_power_of_two();
+        log::debug!("Growing camera uniform buffer from {} to {} cameras", camera_uniforms.capacity, capacity);
+        let (buffer, bind_group) = CameraUniforms::create_buffer(
+            device,
+            &camera_uniforms.bind_group_layout,
+            capacity,
+            camera_uniforms.stride,
+        );
+        camera_uniforms.buffer = buffer;
+        camera_uniforms.bind_group = bind_group;
+        camera_uniforms.capacity = capacity;
+    }
+
+    let stride = camera_uniforms.stride;
+    camera_uniforms.cameras.clear();
+    camera_uniforms.staging.clear();
+    camera_uniforms.staging.resize(views.len() * stride, 0);
+    for (index, view) in views.into_iter().enumerate() {
+        let offset = index * stride;
+        let camera = view.camera;
+        let aspect_ratio = camera.aspect_ratio(view.width, view.height);
+        let view_matrix = view.global_transform.matrix().inverse();
+        let uniform = GpuCameraUniform {
+            view_projection: (camera.projection_matrix(aspect_ratio) * view_matrix).to_cols_array_2d(),
+            view: view_matrix.to_cols_array_2d(),
+            position: view.global_transform.translation().extend(1.0).to_array(),
+            shadows: GpuCameraShadows::zeroed(),
+        };
+        camera_uniforms.staging[offset..offset + size_of::<GpuCameraUniform>()]
+            .copy_from_slice(bytemuck::bytes_of(&uniform));
+
+        let (clear_color, clear_depth) = match camera.clear {
+            CameraClear::Default => (Some(clear_color.0), true),
+            CameraClear::Color(color) => (Some(color), true),
+            CameraClear::DepthOnly => (None, true),
+            CameraClear::None => (None, false),
+        };
+        if view.viewport.is_some() && (clear_color.is_some() || clear_depth) {
+            viewport_clear.prepare(device, view.color_format, clear_color.is_some(), clear_depth);
+        }
+        camera_uniforms.cameras.push(PreparedCamera {
+            entity: view.entity,
+            target: view.target,
+            color_format: view.color_format,
+            uniform_offset: offset as u32,
+            aspect_ratio,
+            viewport: view.viewport,
+            clear_color,
+            clear_depth,
+            layers: camera.layers,
+        });
+    }
+
+    if !camera_uniforms.staging.is_empty() {
+        renderer_state.queue.write_buffer(&camera_uniforms.buffer, 0, &camera_uniforms.staging);
+    }
+}
+
+/// Runs the [`RenderCamera`](schedule::RenderCamera) schedule once for every camera prepared this
+/// frame, in their order, so each camera is drawn completely before the next one draws over it
+pub fn render_cameras(world: &mut World) {
+    if !world.contains_resource::<Frame>() {
+        return;
+    }
+    let Some(camera_uniforms) = world.get_resource::<CameraUniforms>() else {
+        return;
+    };
+
+    for camera in camera_uniforms.cameras().to_vec() {
+        world.insert_resource(CurrentCamera(camera));
+        let _ = world.run_schedule(schedule::RenderCamera);
+    }
+    world.remove_resource::<CurrentCamera>();
+}
+
+/// Clears the current camera's viewport before anything is drawn by it
+pub fn clear_camera(
+    camera: Res<CurrentCamera>,
+    camera_uniforms: Res<CameraUniforms>,
+    gpu_images: Res<GpuImages>,
+    viewport_clear: Res<ViewportClear>,
+    renderer_state: Res<RendererState>,
+    frame_opt: Option<ResMut<Frame>>,
+) {
+    if let Some(mut frame) = frame_opt {
+        camera.0.clear(&mut frame, &camera_uniforms, &gpu_images, &renderer_state, &viewport_clear);
+    }
+}
@@ -0,0 +1,193 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use crate::renderer::camera::CameraTarget;
+use crate::renderer::{SurfaceTarget, RendererState};
+use crate::renderer::screenshot::{ScreenshotCapture, ScreenshotRequests};
+
+/// The color the frame is cleared to before anything is drawn
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ClearColor(pub wgpu::Color);
+
+impl Default for ClearColor {
+    fn default() -> Self {
+        Self(wgpu::Color {
+            r: 0.1,
+            g: 0.1,
+            b: 0.1,
+            a: 1.0,
+        })
+    }
+}
+
+/// The surface texture being rendered to this frame along with the command encoder shared by every
+/// render system. It is created in [`begin_frame`] and submitted and presented in [`end_frame`], so
+/// it is only available to systems in the Render schedule. Headless frames render to the offscreen
+/// target and have no surface texture to present
+#[derive(Resource)]
+pub struct Frame {
+    surface_texture: Option<wgpu::SurfaceTexture>,
+    pub view: wgpu::TextureView,
+    pub encoder: wgpu::CommandEncoder,
+    clear_color: wgpu::Color,
+    /// Targets whose color and depth have been cleared this frame
+    cleared_colors: HashSet<CameraTarget>,
+    cleared_depths: HashSet<CameraTarget>,
+}
+
+impl Frame {
+    /// Begins a render pass that draws into the frame. The first pass of the frame clears the color
+    /// target to the [`ClearColor`] and the first pass that uses `depth_view` clears it, every other
+    /// pass loads what previous passes have drawn
+    pub fn begin_render_pass<'a>(
+        &'a mut self,
+        label: &'a str,
+        depth_view: Option<&'a wgpu::TextureView>,
+    ) -> wgpu::RenderPass<'a> {
+        self.begin_target_pass(label, CameraTarget::Window, None, depth_view, None, false)
+    }
+
+    /// Begins a render pass that draws into a camera's target, the frame itself when `color_view`
+    /// is `None`. Color is cleared to `clear_color` if it is given and depth if `clear_depth` is
+    /// set. Otherwise the first pass into a target this frame clears it like
+    /// [`Frame::begin_render_pass`] and every other pass loads what previous passes have drawn
+    pub(crate) fn begin_target_pass<'a>(
+        &'a mut self,
+        label: &'a str,
+        target: CameraTarget,
+        color_view: Option<&'a wgpu::TextureView>,
+        depth_view: Option<&'a wgpu::TextureView>,
+        clear_color: Option<wgpu::Color>,
+        clear_depth: bool,
+    ) -> wgpu::RenderPass<'a> {
+        let first_color_pass = self.cleared_colors.insert(target);
+        let color_load = match clear_color {
+            Some(clear_color) => wgpu::LoadOp::Clear(clear_color),
+            None => Self::load_op(first_color_pass, self.clear_color),
+        };
+        let depth = depth_view.map(|depth_view| {
+            let first_depth_pass = self.cleared_depths.insert(target);
+            (depth_view, Self::load_op(first_depth_pass || clear_depth, 1.0))
+        });
+
+        Self::begin_pass(&mut self.encoder, label, color_view.unwrap_or(&self.view), color_load, depth)
+    }
+
+    fn load_op<V>(clear: bool, clear_value: V) -> wgpu::LoadOp<V> {
+        if clear {
+            wgpu::LoadOp::Clear(clear_value)
+        } else {
+            wgpu::LoadOp::Load
+        }
+    }
+
+    fn begin_pass<'a>(
+        encoder: &'a mut wgpu::CommandEncoder,
+        label: &'a str,
+        color_view: &'a wgpu::TextureView,
+        color_load: wgpu::LoadOp<wgpu::Color>,
+        depth: Option<(&'a wgpu::TextureView, wgpu::LoadOp<f32>)>,
+    ) -> wgpu::RenderPass<'a> {
+        let depth_stencil_attachment = depth.map(|(depth_view, depth_load)| wgpu::RenderPassDepthStencilAttachment {
+            view: depth_view,
+            depth_ops: Some(wgpu::Operations {
+                load: depth_load,
+                store: wgpu::StoreOp::Store,
+            }),
+            stencil_ops: None,
+        });
+
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: color_load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        })
+    }
+}
+
+/// Acquires the surface texture for this frame and creates the encoder render systems record into
+pub fn begin_frame(
+    mut commands: Commands,
+    renderer_state: Res<RendererState>,
+    clear_color: Res<ClearColor>,
+) {
+    let (surface_texture, view) = match &renderer_state.surface_target {
+        SurfaceTarget::Surface(surface) => {
+            let surface_texture = match surface.get_current_texture() {
+                Ok(surface_texture) => surface_texture,
+                Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                    log::debug!("Surface is outdated or lost, reconfiguring it and skipping this frame");
+                    surface.configure(&renderer_state.device, &renderer_state.config);
+                    return;
+                }
+                Err(wgpu::SurfaceError::Timeout) => {
+                    log::warn!("Timed out acquiring the surface texture, skipping this frame");
+                    return;
+                }
+                Err(wgpu::SurfaceError::OutOfMemory) => {
+                    panic!("Ran out of memory acquiring the surface texture");
+                }
+            };
+            let view = surface_texture.texture.create_view(&wgpu::TextureViewDescriptor::default());
+            (Some(surface_texture), view)
+        }
+        SurfaceTarget::Offscreen(texture) => (None, texture.create_view(&wgpu::TextureViewDescriptor::default())),
+    };
+
+    let encoder = renderer_state.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Main rendering command encoder")
+    });
+
+    commands.insert_resource(Frame {
+        surface_texture,
+        view,
+        encoder,
+        clear_color: clear_color.0,
+        cleared_colors: HashSet::new(),
+        cleared_depths: HashSet::new(),
+    });
+}
+
+/// Submits everything recorded into the [`Frame`] and presents it. If no render system drew
+/// anything the frame is still cleared so the previous contents of the surface are never shown.
+/// Pending screenshots are copied from the frame before it is presented
+pub fn end_frame(world: &mut World) {
+    let Some(mut frame) = world.remove_resource::<Frame>() else {
+        return;
+    };
+
+    if !frame.cleared_colors.contains(&CameraTarget::Window) {
+        frame.begin_render_pass("Clear render pass", None);
+    }
+
+    let screenshots = world.get_resource_mut::<ScreenshotRequests>()
+        .filter(|requests| !requests.is_empty())
+        .map(|mut requests| requests.take());
+    let renderer_state = world.resource::<RendererState>();
+    let screenshot_capture = screenshots.and_then(|screenshots| {
+        let texture = match (&frame.surface_texture, &renderer_state.surface_target) {
+            (Some(surface_texture), _) => &surface_texture.texture,
+            (None, SurfaceTarget::Offscreen(texture)) => texture,
+            (None, SurfaceTarget::Surface(_)) => unreachable!("Frames rendered to a surface always have a surface texture"),
+        };
+        ScreenshotCapture::encode(&renderer_state.device, &mut frame.encoder, texture, screenshots)
+            .inspect_err(|error| log::error!("Unable to capture screenshot: {}", error))
+            .ok()
+    });
+
+    renderer_state.queue.submit(Some(frame.encoder.finish()));
+    if let Some(surface_texture) = frame.surface_texture {
+        surface_texture.present();
+    }
+    if let Some(screenshot_capture) = screenshot_capture {
+        screenshot_capture.finish(renderer_state);
+    }
+}
@@ -0,0 +1,446 @@
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+use crate::renderer::camera::{Camera, CameraUniforms, GpuCameraShadows};
+use crate::renderer::shadow::{cascade_splits, cascade_view_projection, spot_light_view_projection, CascadeSettings, ShadowMapKind, ShadowMaps, ShadowPasses, ShadowSettings, MAX_CASCADES};
+use crate::renderer::RendererState;
+use crate::transform::{GlobalTransform, Transform};
+
+/// Bind group index the lights are bound to in 3D pipelines
+pub const LIGHTS_BIND_GROUP_INDEX: u32 = 3;
+/// The most lights of each kind that are uploaded. Any lights past these are ignored
+pub const MAX_DIRECTIONAL_LIGHTS: usize = 4;
+pub const MAX_POINT_LIGHTS: usize = 64;
+pub const MAX_SPOT_LIGHTS: usize = 16;
+
+/// Light that reaches every surface from the same direction, like the sun. It shines along the
+/// local -Z axis of its [`Transform`]. Its shadow cascades are fit to every camera separately, so
+/// each camera gets shadows across its whole view
+#[derive(Component, Clone, Copy, Debug)]
+#[require(Transform)]
+pub struct DirectionalLight {
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// `None` disables shadows for this light
+    pub shadows: Option<ShadowSettings>,
+    pub cascades: CascadeSettings,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        Self {
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            shadows: None,
+            cascades: CascadeSettings::default(),
+        }
+    }
+}
+
+/// Light that shines in every direction from the position of its [`Transform`]. Its intensity
+/// falls off with distance and reaches zero at `range`
+#[derive(Component, Clone, Copy, Debug)]
+#[require(Transform)]
+pub struct PointLight {
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+}
+
+impl Default for PointLight {
+    fn default() -> Self {
+        Self {
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            range: 20.0,
+        }
+    }
+}
+
+/// A [`PointLight`] restricted to a cone around the local -Z axis of its [`Transform`]. The light
+/// is at full intensity within `inner_angle` and fades out towards `outer_angle`, both in radians
+/// from the axis
+#[derive(Component, Clone, Copy, Debug)]
+#[require(Transform)]
+pub struct SpotLight {
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    /// `None` disables shadows for this light
+    pub shadows: Option<ShadowSettings>,
+}
+
+impl Default for SpotLight {
+    fn default() -> Self {
+        Self {
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            range: 20.0,
+            inner_angle: 0.0,
+            outer_angle: std::f32::consts::FRAC_PI_4,
+            shadows: None,
+        }
+    }
+}
+
+/// Light that reaches every surface equally, regardless of position or orientation. The ambient
+/// light of every entity with this component is added together
+#[derive(Component, Clone, Copy, Debug)]
+pub struct AmbientLight {
+    pub color: [f32; 3],
+    pub intensity: f32,
+}
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        Self {
+            color: [1.0, 1.0, 1.0],
+            intensity: 0.1,
+        }
+    }
+}
+
+/// Laid out to match `DirectionalLight` in the `fathom::lights` shader module
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuDirectionalLight {
+    /// The direction towards the light
+    pub direction: [f32; 4],
+    /// Color multiplied by intensity
+    pub color: [f32; 4],
+    /// The first layer is relative to the first layer of the camera's cascades, see
+    /// [`GpuCameraShadows`]
+    pub shadow: GpuShadowParams,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuPointLight {
+    /// The range is stored in w
+    pub position: [f32; 4],
+    pub color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuSpotLight {
+    /// The range is stored in w
+    pub position: [f32; 4],
+    /// The direction the light shines in, with the cosine of the outer angle in w
+    pub direction: [f32; 4],
+    /// The cosine of the inner angle is stored in w
+    pub color: [f32; 4],
+    pub shadow: GpuShadowParams,
+    pub view_projection: [[f32; 4]; 4],
+}
+
+/// How a light's shadow map is sampled, laid out to match `ShadowParams` in the `fathom::lights`
+/// shader module. All zeros disables the light's shadows
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuShadowParams {
+    pub depth_bias: f32,
+    pub normal_bias: f32,
+    /// The light's resolution divided by the size of the shadow map array it is in
+    pub uv_scale: f32,
+    /// The first layer of the light's shadow maps in the array
+    pub first_layer: u32,
+    /// Number of shadow maps the light has, which is the number of cascades for directional lights
+    pub layer_count: u32,
+    pub _padding: [u32; 3],
+}
+
+/// Every light in the scene, laid out to match `Lights` in the `fathom::lights` shader module
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuLightsUniform {
+    pub ambient_color: [f32; 4],
+    /// Number of directional, point and spot lights
+    pub counts: [u32; 4],
+    pub directional_lights: [GpuDirectionalLight; MAX_DIRECTIONAL_LIGHTS],
+    pub point_lights: [GpuPointLight; MAX_POINT_LIGHTS],
+    pub spot_lights: [GpuSpotLight; MAX_SPOT_LIGHTS],
+}
+
+/// The uniform buffer the lights are gathered into each frame, bound at [`LIGHTS_BIND_GROUP_INDEX`]
+/// along with the shadow maps
+#[derive(Resource)]
+pub struct LightUniforms {
+    pub(crate) bind_group_layout: wgpu::BindGroupLayout,
+    buffer: wgpu::Buffer,
+    pub(crate) bind_group: wgpu::BindGroup,
+}
+
+impl LightUniforms {
+    pub fn new(device: &wgpu::Device, shadow_maps: &ShadowMaps) -> Self {
+        let shadow_map_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Depth,
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                shadow_map_entry(1),
+                shadow_map_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+            ],
+            label: Some("Lights Bind Group Layout"),
+        });
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lights Uniform Buffer"),
+            contents: bytemuck::bytes_of(&GpuLightsUniform::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &buffer, shadow_maps);
+
+        Self {
+            bind_group_layout,
+            buffer,
+            bind_group,
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+        shadow_maps: &ShadowMaps,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(shadow_maps.view(ShadowMapKind::Directional)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(shadow_maps.view(ShadowMapKind::Spot)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&shadow_maps.sampler),
+                },
+            ],
+            label: Some("Lights Bind Group"),
+        })
+    }
+}
+
+/// Gathers every light into the lights uniform buffer and prepares a shadow pass for each spot
+/// light with shadows enabled and each cascade of a directional light with shadows enabled. The
+/// cascades are fit to every camera prepared by
+/// [`prepare_cameras`](crate::renderer::camera::prepare_cameras) and written into its uniform, so
+/// this runs after it. Lights past the maximum of their kind are ignored, which is logged the
+/// first time it happens
+pub fn prepare_lights(
+    renderer_state: Res<RendererState>,
+    camera_uniforms: Res<CameraUniforms>,
+    mut light_uniforms: ResMut<LightUniforms>,
+    mut shadow_maps: ResMut<ShadowMaps>,
+    mut shadow_passes: ResMut<ShadowPasses>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    ambient_lights: Query<&AmbientLight>,
+    directional_lights: Query<(&DirectionalLight, &GlobalTransform)>,
+    point_lights: Query<(&PointLight, &GlobalTransform)>,
+    spot_lights: Query<(&SpotLight, &GlobalTransform)>,
+    mut warned_about_light_count: Local<bool>,
+    mut warned_about_shadow_resolution: Local<bool>,
+) {
+    let device = &renderer_state.device;
+    // Larger shadow maps would fail validation when their texture array is created
+    let max_resolution = device.limits().max_texture_dimension_2d;
+    let mut clamp_shadows = |shadows: Option<ShadowSettings>| shadows.map(|shadows| {
+        if shadows.resolution <= max_resolution {
+            return shadows;
+        }
+        if !*warned_about_shadow_resolution {
+            log::warn!("Shadow resolution {} is larger than the device supports, using {}", shadows.resolution, max_resolution);
+            *warned_about_shadow_resolution = true;
+        }
+        ShadowSettings {
+            resolution: max_resolution,
+            ..shadows
+        }
+    });
+    // Counted before the lights are truncated so the warning below can see the overflow
+    let light_counts = [
+        ("directional", directional_lights.iter().count(), MAX_DIRECTIONAL_LIGHTS),
+        ("point", point_lights.iter().count(), MAX_POINT_LIGHTS),
+        ("spot", spot_lights.iter().count(), MAX_SPOT_LIGHTS),
+    ];
+    let directional_lights: Vec<_> = directional_lights.iter()
+        .take(MAX_DIRECTIONAL_LIGHTS)
+        .map(|(light, global_transform)| (light, clamp_shadows(light.shadows), global_transform))
+        .collect();
+    let spot_lights: Vec<_> = spot_lights.iter()
+        .take(MAX_SPOT_LIGHTS)
+        .map(|(light, global_transform)| (light, clamp_shadows(light.shadows), global_transform))
+        .collect();
+    let shadowed_directional_lights = directional_lights.iter()
+        .filter_map(|(light, shadows, _)| shadows.map(|shadows| (shadows, light.cascades)));
+
+    // Every shadow map of a kind shares one texture array, sized for the largest resolution. Each
+    // camera has a block of layers holding the cascades of every directional light
+    let camera_directional_layers: u32 = shadowed_directional_lights.clone()
+        .map(|(_, cascades)| cascades.count.clamp(1, MAX_CASCADES as u32))
+        .sum();
+    let directional_layers = camera_directional_layers * camera_uniforms.cameras().len() as u32;
+    let directional_size = shadowed_directional_lights
+        .map(|(shadows, _)| shadows.resolution)
+        .max()
+        .unwrap_or(1);
+    let spot_layers = spot_lights.iter().filter(|(_, shadows, _)| shadows.is_some()).count() as u32;
+    let spot_size = spot_lights.iter()
+        .filter_map(|(_, shadows, _)| *shadows)
+        .map(|shadows| shadows.resolution)
+        .max()
+        .unwrap_or(1);
+    let directional_recreated = shadow_maps.prepare(device, ShadowMapKind::Directional, directional_size, directional_layers);
+    let spot_recreated = shadow_maps.prepare(device, ShadowMapKind::Spot, spot_size, spot_layers);
+    if directional_recreated || spot_recreated {
+        let light_uniforms = light_uniforms.as_mut();
+        light_uniforms.bind_group = LightUniforms::create_bind_group(
+            device,
+            &light_uniforms.bind_group_layout,
+            &light_uniforms.buffer,
+            &shadow_maps,
+        );
+    }
+
+    let mut lights = GpuLightsUniform::zeroed();
+    shadow_passes.clear();
+
+    let ambient_color = ambient_lights.iter()
+        .fold(Vec3::ZERO, |ambient_color, light| ambient_color + Vec3::from(light.color) * light.intensity);
+    lights.ambient_color = ambient_color.extend(1.0).to_array();
+
+    let mut next_layer = 0;
+    for (index, (light, shadows, global_transform)) in directional_lights.iter().enumerate() {
+        let gpu_light = &mut lights.directional_lights[index];
+        gpu_light.direction = (-global_transform.forward()).extend(0.0).to_array();
+        gpu_light.color = light_color(light.color, light.intensity, 0.0);
+
+        if let Some(shadows) = *shadows {
+            let cascade_count = light.cascades.count.clamp(1, MAX_CASCADES as u32);
+            gpu_light.shadow = shadow_params(&shadows, shadow_maps.size(ShadowMapKind::Directional), next_layer, cascade_count);
+            next_layer += cascade_count;
+        }
+        lights.counts[0] += 1;
+    }
+
+    for (camera_index, prepared_camera) in camera_uniforms.cameras().iter().enumerate() {
+        let Ok((camera, camera_transform)) = cameras.get(prepared_camera.entity) else {
+            continue;
+        };
+        let mut camera_shadows = GpuCameraShadows {
+            first_layer: camera_index as u32 * camera_directional_layers,
+            ..GpuCameraShadows::zeroed()
+        };
+
+        for (index, (light, shadows, global_transform)) in directional_lights.iter().enumerate() {
+            let Some(shadows) = *shadows else {
+                continue;
+            };
+            let to_light = -global_transform.forward();
+            let first_layer = camera_shadows.first_layer + lights.directional_lights[index].shadow.first_layer;
+            let far = light.cascades.max_distance.min(camera.far);
+            let splits = cascade_splits(camera.near, far, &light.cascades);
+
+            let mut cascade_near = camera.near;
+            for (cascade, cascade_far) in splits.into_iter().enumerate() {
+                let view_projection = cascade_view_projection(
+                    camera,
+                    camera_transform,
+                    prepared_camera.aspect_ratio,
+                    cascade_near,
+                    cascade_far,
+                    to_light,
+                    shadows.resolution,
+                );
+                camera_shadows.cascade_splits[index][cascade] = cascade_far;
+                camera_shadows.cascade_view_projections[index * MAX_CASCADES + cascade] = view_projection.to_cols_array_2d();
+                shadow_passes.push(ShadowMapKind::Directional, first_layer + cascade as u32, shadows.resolution, view_projection);
+                cascade_near = cascade_far;
+            }
+        }
+        camera_uniforms.write_shadows(&renderer_state.queue, prepared_camera, &camera_shadows);
+    }
+
+    for (index, (light, global_transform)) in point_lights.iter().take(MAX_POINT_LIGHTS).enumerate() {
+        lights.point_lights[index] = GpuPointLight {
+            position: global_transform.translation().extend(light.range).to_array(),
+            color: light_color(light.color, light.intensity, 0.0),
+        };
+        lights.counts[1] += 1;
+    }
+
+    let mut next_layer = 0;
+    for (index, (light, shadows, global_transform)) in spot_lights.iter().enumerate() {
+        let gpu_light = &mut lights.spot_lights[index];
+        gpu_light.position = global_transform.translation().extend(light.range).to_array();
+        gpu_light.direction = global_transform.forward().extend(light.outer_angle.cos()).to_array();
+        gpu_light.color = light_color(light.color, light.intensity, light.inner_angle.cos());
+
+        if let Some(shadows) = *shadows {
+            let view_projection = spot_light_view_projection(global_transform, light.outer_angle, light.range);
+            gpu_light.shadow = shadow_params(&shadows, shadow_maps.size(ShadowMapKind::Spot), next_layer, 1);
+            gpu_light.view_projection = view_projection.to_cols_array_2d();
+            shadow_passes.push(ShadowMapKind::Spot, next_layer, shadows.resolution, view_projection);
+            next_layer += 1;
+        }
+        lights.counts[2] += 1;
+    }
+
+    for (kind, count, max) in light_counts {
+        if count > max && !*warned_about_light_count {
+            log::warn!("Only the first {} of {} {} lights are used", max, count, kind);
+            *warned_about_light_count = true;
+        }
+    }
+
+    renderer_state.queue.write_buffer(&light_uniforms.buffer, 0, bytemuck::bytes_of(&lights));
+    shadow_passes.write(device, &renderer_state.queue);
+}
+
+fn light_color(color: [f32; 3], intensity: f32, w: f32) -> [f32; 4] {
+    (Vec3::from(color) * intensity).extend(w).to_array()
+}
+
+fn shadow_params(shadows: &ShadowSettings, shadow_map_size: u32, first_layer: u32, layer_count: u32) -> GpuShadowParams {
+    GpuShadowParams {
+        depth_bias: shadows.depth_bias,
+        normal_bias: shadows.normal_bias,
+        uv_scale: shadows.resolution as f32 / shadow_map_size as f32,
+        first_layer,
+        layer_count,
+        _padding: [0; 3],
+    }
+}
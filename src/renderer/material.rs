@@ -1,11 +1,23 @@
 use std::ops::{Deref};
 use bevy::prelude::*;
-use crate::assets::materials::Material;
-use crate::renderer::mesh::{GpuMeshes, Mesh};
-use crate::renderer::pipeline::Pipelines;
+use bevy::utils::{HashMap, HashSet};
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+use crate::assets::materials::{Material, MaterialProperties};
+use crate::assets::shaders::{Shader, ShadersState};
+use crate::renderer::camera::{CameraUniforms, CurrentCamera, RenderLayers};
+use crate::renderer::frame::Frame;
+use crate::renderer::mesh::{GpuMeshes, Mesh, Mesh2D};
+use crate::renderer::light::{LightUniforms, LIGHTS_BIND_GROUP_INDEX};
+use crate::renderer::model::{ModelUniforms, MODEL_BIND_GROUP_INDEX};
+use crate::renderer::pipeline::{PipelineKey, Pipelines};
+use crate::renderer::texture::{GpuImage, GpuImages, Image};
 use crate::renderer::{Renderable, RendererState};
 
 
+/// Bind group index the material's textures are bound to in 3D pipelines
+pub const MATERIAL_BIND_GROUP_INDEX: u32 = 2;
+
 #[derive(Resource)]
 pub struct DefaultMaterial(pub(crate) Handle<Material>);
 
@@ -16,57 +28,339 @@ impl Deref for DefaultMaterial {
     }
 }
 
-#[derive(Component)]
+/// Draws the [`Mesh`] on this entity with the given [`Material`] instead of the default material.
+/// A render pipeline is looked up for the material the first time it is drawn with a mesh's vertex
+/// buffer layout and shared by every material and mesh with the same [`PipelineKey`]
+#[derive(Component, Clone, Debug)]
 pub struct MeshMaterial {
     material: Handle<Material>
 }
 
+impl MeshMaterial {
+    pub fn new(material: Handle<Material>) -> Self {
+        Self {
+            material
+        }
+    }
+
+    pub fn material(&self) -> &Handle<Material> {
+        &self.material
+    }
+}
+
+impl From<Handle<Material>> for MeshMaterial {
+    fn from(material: Handle<Material>) -> Self {
+        Self::new(material)
+    }
+}
+
+/// Finds the render pipeline of every mesh that does not have one yet for its material and vertex
+/// buffer layout, creating it if no other material shares its [`PipelineKey`]. 3D meshes get a
+/// pipeline for the color format of every camera's target, 2D meshes for the window's. Meshes
+/// without a [`MeshMaterial`] use the default material. A material is skipped until it and its
+/// shaders have finished loading. Modified materials are looked up again since their key may have
+/// changed
+pub fn prepare_material_pipelines(
+    mut material_events: EventReader<AssetEvent<Material>>,
+    meshes: Query<(&Mesh, Option<&MeshMaterial>)>,
+    meshes_2d: Query<&Mesh2D>,
+    default_material: Option<Res<DefaultMaterial>>,
+    camera_uniforms: Option<Res<CameraUniforms>>,
+    materials: Res<Assets<Material>>,
+    shader_assets: Res<Assets<Shader>>,
+    mut shaders_state: ResMut<ShadersState>,
+    mut pipelines: ResMut<Pipelines>,
+    renderer_state: Res<RendererState>,
+) {
+    for event in material_events.read() {
+        if let AssetEvent::Modified { id } | AssetEvent::Removed { id } = event {
+            pipelines.material_to_pipeline_id_map.remove(id);
+        }
+    }
+
+    let device = &renderer_state.device;
+    let default_material_handle = default_material.as_ref().map(|default_material| &default_material.0);
+    let camera_color_formats = camera_uniforms.map(|camera_uniforms| camera_uniforms.color_formats()).unwrap_or_default();
+    let mesh_layouts = meshes.iter()
+        .filter_map(|(mesh, mesh_material)| {
+            let material_handle = mesh_material.map(MeshMaterial::material).or(default_material_handle)?;
+            Some((material_handle, mesh.vertex_buffer_layout()))
+        })
+        .flat_map(|(material_handle, vertex_buffer_layout)| camera_color_formats.iter()
+            .map(move |color_format| (material_handle, vertex_buffer_layout, *color_format)))
+        .chain(meshes_2d.iter().filter_map(|mesh| {
+            Some((default_material_handle?, mesh.vertex_buffer_layout(), renderer_state.config.format))
+        }));
+    for (material_handle, vertex_buffer_layout, color_format) in mesh_layouts {
+        if pipelines.get_pipeline_id_by_material(material_handle, vertex_buffer_layout, color_format).is_some() {
+            continue;
+        }
+
+        let Some(material) = materials.get(material_handle) else {
+            continue;
+        };
+        let key = PipelineKey::from_material(material, vertex_buffer_layout, color_format);
+        let vertex_shader_loaded = shaders_state.ensure_shader_module(device, &key.vertex_module_key(), &shader_assets);
+        let fragment_shader_loaded = shaders_state.ensure_shader_module(device, &key.fragment_module_key(), &shader_assets);
+        if !vertex_shader_loaded || !fragment_shader_loaded {
+            continue;
+        }
+
+        if let Some(pipeline_id) = pipelines.get_or_create_pipeline(device, &key, &shaders_state) {
+            log::debug!("Using pipeline_id={} for material={:?}", pipeline_id, material_handle);
+            pipelines.material_to_pipeline_id_map.entry(material_handle.id())
+                .or_default()
+                .entry(color_format)
+                .or_default()
+                .insert(vertex_buffer_layout.clone(), pipeline_id);
+        }
+    }
+}
+
+/// The surface parameters of a material, laid out to match `MaterialUniforms` in the
+/// `fathom::material` shader module
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuMaterialUniform {
+    pub ambient_color: [f32; 4],
+    /// The dissolve is stored in alpha
+    pub diffuse_color: [f32; 4],
+    /// The shininess is stored in w
+    pub specular_color: [f32; 4],
+    pub emissive_color: [f32; 4],
+}
+
+impl From<&MaterialProperties> for GpuMaterialUniform {
+    fn from(properties: &MaterialProperties) -> Self {
+        let [ambient_r, ambient_g, ambient_b] = properties.ambient_color;
+        let [diffuse_r, diffuse_g, diffuse_b] = properties.diffuse_color;
+        let [specular_r, specular_g, specular_b] = properties.specular_color;
+        let [emissive_r, emissive_g, emissive_b] = properties.emissive_color;
+        Self {
+            ambient_color: [ambient_r, ambient_g, ambient_b, 1.0],
+            diffuse_color: [diffuse_r, diffuse_g, diffuse_b, properties.dissolve],
+            specular_color: [specular_r, specular_g, specular_b, properties.shininess],
+            emissive_color: [emissive_r, emissive_g, emissive_b, 1.0],
+        }
+    }
+}
+
+struct GpuMaterial {
+    /// Kept alive for as long as the bind group that uses it
+    _uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// The image bound as the diffuse texture, `None` when the fallback is bound
+    diffuse_image: Option<AssetId<Image>>,
+}
+
+/// The bind group of every material with its diffuse texture, sampler and properties
+#[derive(Resource)]
+pub struct MaterialBindGroups {
+    pub(crate) bind_group_layout: wgpu::BindGroupLayout,
+    /// Bound for materials without a diffuse texture, and for materials whose texture is still loading
+    fallback_image: GpuImage,
+    gpu_materials: HashMap<AssetId<Material>, GpuMaterial>,
+}
+
+impl MaterialBindGroups {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("Material Bind Group Layout"),
+        });
+
+        Self {
+            bind_group_layout,
+            fallback_image: Image::solid_color([255, 255, 255, 255]).create_gpu_image(device, queue),
+            gpu_materials: HashMap::new(),
+        }
+    }
+
+    pub fn bind_group(&self, material: impl Into<AssetId<Material>>) -> Option<&wgpu::BindGroup> {
+        self.gpu_materials.get(&material.into()).map(|gpu_material| &gpu_material.bind_group)
+    }
+
+    /// Whether the material's bind group samples `image`. A render pass can not sample the image
+    /// it renders to, so cameras skip materials that sample their target
+    pub fn samples_image(&self, material: impl Into<AssetId<Material>>, image: AssetId<Image>) -> bool {
+        self.gpu_materials.get(&material.into())
+            .is_some_and(|gpu_material| gpu_material.diffuse_image == Some(image))
+    }
+
+    fn create_gpu_material(
+        &self,
+        device: &wgpu::Device,
+        diffuse_image: Option<(AssetId<Image>, &GpuImage)>,
+        properties: &MaterialProperties,
+    ) -> GpuMaterial {
+        let gpu_image = diffuse_image.map_or(&self.fallback_image, |(_, gpu_image)| gpu_image);
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Material Uniform Buffer"),
+            contents: bytemuck::bytes_of(&GpuMaterialUniform::from(properties)),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&gpu_image.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&gpu_image.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("Material Bind Group"),
+        });
+
+        GpuMaterial {
+            _uniform_buffer: uniform_buffer,
+            bind_group,
+            diffuse_image: diffuse_image.map(|(image, _)| image),
+        }
+    }
+}
+
+/// Creates the bind group and uniform buffer of the default material and every [`MeshMaterial`].
+/// Bind groups are recreated when their material or its diffuse texture changes
+pub fn prepare_material_bind_groups(
+    mut material_events: EventReader<AssetEvent<Material>>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    mesh_materials: Query<&MeshMaterial>,
+    default_material: Option<Res<DefaultMaterial>>,
+    materials: Res<Assets<Material>>,
+    gpu_images: Res<GpuImages>,
+    mut material_bind_groups: ResMut<MaterialBindGroups>,
+    renderer_state: Res<RendererState>,
+) {
+    for event in material_events.read() {
+        if let AssetEvent::Modified { id } | AssetEvent::Removed { id } = event {
+            material_bind_groups.gpu_materials.remove(id);
+        }
+    }
+    let changed_images: HashSet<AssetId<Image>> = image_events.read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id }
+            | AssetEvent::Modified { id }
+            | AssetEvent::Removed { id }
+            | AssetEvent::Unused { id } => Some(*id),
+            AssetEvent::LoadedWithDependencies { .. } => None,
+        })
+        .collect();
+    if !changed_images.is_empty() {
+        material_bind_groups.gpu_materials.retain(|material_id, _| {
+            let diffuse_texture = materials.get(*material_id).and_then(|material| material.diffuse_texture.as_ref());
+            !diffuse_texture.is_some_and(|diffuse_texture| changed_images.contains(&diffuse_texture.id()))
+        });
+    }
+
+    let material_handles = default_material.iter()
+        .map(|default_material| &default_material.0)
+        .chain(mesh_materials.iter().map(MeshMaterial::material));
+    for material_handle in material_handles {
+        if material_bind_groups.gpu_materials.contains_key(&material_handle.id()) {
+            continue;
+        }
+        let Some(material) = materials.get(material_handle) else {
+            continue;
+        };
+
+        // A texture that is still loading is replaced with the fallback. Its bind group is recreated
+        // when the texture is uploaded since that sends an Added event for it
+        let diffuse_image = material.diffuse_texture.as_ref()
+            .and_then(|diffuse_texture| Some((diffuse_texture.id(), gpu_images.get(diffuse_texture)?)));
+        let gpu_material = material_bind_groups.create_gpu_material(&renderer_state.device, diffuse_image, &material.properties);
+        material_bind_groups.gpu_materials.insert(material_handle.id(), gpu_material);
+    }
+}
+
+/// This system renders any 3D Meshes that have a [`MeshMaterial`] for the current camera. It runs
+/// after `default_3d_render_pass` and draws into the same target and depth buffer
 pub fn render_mesh_with_material(
-    renderable_entities: Query<(&Mesh, &MeshMaterial), With<Renderable>>,
+    renderable_entities: Query<(Entity, &Mesh, &MeshMaterial, Option<&RenderLayers>), With<Renderable>>,
     pipelines: Res<Pipelines>,
     gpu_meshes: Res<GpuMeshes>,
+    gpu_images: Res<GpuImages>,
+    camera: Res<CurrentCamera>,
+    camera_uniforms: Res<CameraUniforms>,
+    model_uniforms: Res<ModelUniforms>,
+    material_bind_groups: Res<MaterialBindGroups>,
+    light_uniforms: Res<LightUniforms>,
     renderer_state: Res<RendererState>,
+    frame_opt: Option<ResMut<Frame>>,
 ) {
-    let device = &renderer_state.device;
-    let queue = &renderer_state.queue;
-    let surface = &renderer_state.surface;
-    let frame = surface.get_current_texture().unwrap();
-    let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
-    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-        label: Some("Main rendering command encoder")
-    });
-    for (mesh, mesh_material) in &renderable_entities {
-        let material_handle = mesh_material.material.clone();
-        let pipeline_id = pipelines.get_pipeline_id_by_material(material_handle)
-            .unwrap_or_else(|| {
-                panic!("Unable to get pipeline_id from the given material handle");
-            });
+    let Some(mut frame) = frame_opt else {
+        return;
+    };
+    if renderable_entities.is_empty() {
+        return;
+    }
+
+    let camera = &camera.0;
+    let Some(mut render_pass) = camera.begin_render_pass(&mut frame, "Material render pass", &camera_uniforms, &gpu_images, &renderer_state) else {
+        return;
+    };
+    render_pass.set_bind_group(0, &camera_uniforms.bind_group, &[camera.uniform_offset]);
+    render_pass.set_bind_group(LIGHTS_BIND_GROUP_INDEX, &light_uniforms.bind_group, &[]);
+
+    for (entity, mesh, mesh_material, layers) in &renderable_entities {
+        if !camera.sees(layers) {
+            continue;
+        }
+        let Some(pipeline_id) = pipelines.get_pipeline_id_by_material(mesh_material.material(), mesh.vertex_buffer_layout(), camera.color_format) else {
+            // The pipeline is created once the material and its shaders have loaded
+            continue;
+        };
+        let Some((model_bind_group, model_offset)) = model_uniforms.bind_group(entity) else {
+            continue;
+        };
+        let Some(material_bind_group) = material_bind_groups.bind_group(mesh_material.material()) else {
+            continue;
+        };
+        if camera.renders_to_image_sampled_by(mesh_material.material(), &material_bind_groups) {
+            continue;
+        }
         let pipeline_opt = pipelines.registered_pipelines.get(pipeline_id);
         let vertex_buffer_opt = gpu_meshes.buffers_map.get(&mesh.vertex_buffer_id);
 
-
         if let (Some(pipeline), Some(vertex_buffer)) = (pipeline_opt, vertex_buffer_opt) {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
             render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(MODEL_BIND_GROUP_INDEX, model_bind_group, &[model_offset]);
+            render_pass.set_bind_group(MATERIAL_BIND_GROUP_INDEX, material_bind_group, &[]);
             render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
 
-            if let Some((_, _, uniform_bind_group)) = pipelines.render_pipeline_state.get(pipeline_id) {
-                render_pass.set_bind_group(0, &uniform_bind_group, &[]);
-            }
-
             if let Some(Some(index_buffer)) = &mesh.has_indices().then(|| gpu_meshes.buffers_map.get(&mesh.index_buffer_id)) {
                 render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
                 render_pass.draw_indexed(0..mesh.num_indices() as u32, 0, 0..1);
@@ -74,11 +368,7 @@ pub fn render_mesh_with_material(
                 render_pass.draw(0..mesh.num_vertices() as u32, 0..1);
             }
         } else {
-            error!("Unable to perform render pass for pipeline_id={} | vertex_buffer_id={}", pipeline_id, mesh.vertex_buffer_id);
+            error!("Unable to draw mesh for pipeline_id={} | vertex_buffer_id={}", pipeline_id, mesh.vertex_buffer_id);
         }
     }
-
-    queue.submit(Some(encoder.finish()));
-    frame.present();
-
-}
\ No newline at end of file
+}
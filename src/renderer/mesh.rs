@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use bevy::ecs::component::ComponentId;
 use bevy::ecs::world::DeferredWorld;
 use bevy::prelude::*;
@@ -5,43 +6,128 @@ use bevy::utils::HashMap;
 use bytemuck::cast_slice;
 use rand::random;
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
-use crate::renderer::{Renderable, RendererState, Vertex, Vertex2D};
-
-#[derive(Component)]
-#[require(Renderable)]
+use crate::renderer::{Renderable, RendererState};
+use crate::renderer::vertex::{MeshVertexAttribute, MeshVertexBufferLayout, Vertex, Vertex2D, VertexAttributeValues};
+use crate::transform::Transform;
+
+/// A 3D mesh made of named vertex attributes. Every attribute has one value per vertex and the
+/// vertex buffer layout is derived from the attributes the mesh has, so meshes can carry normals,
+/// tangents, extra uv or color sets or custom attributes without a dedicated vertex type
+#[derive(Component, Asset, TypePath, Clone, Default)]
+#[require(Renderable, Transform)]
 pub struct Mesh {
-    vertices: Vec<Vertex>,
+    /// Attributes by shader location, which is the order they are interleaved in
+    attributes: BTreeMap<u32, (MeshVertexAttribute, VertexAttributeValues)>,
     indices: Option<Vec<u32>>,
+    vertex_buffer_layout: MeshVertexBufferLayout,
     pub vertex_buffer_id: u64,
     pub index_buffer_id: u64,
 }
 
 impl Mesh {
+    /// Creates a mesh with [`MeshVertexAttribute::POSITION`] and [`MeshVertexAttribute::COLOR`]
+    /// attributes taken from the vertices
     pub fn new(
         vertices: Vec<Vertex>
     ) -> Self {
-        Self {
-            vertices,
-            indices: None,
-            vertex_buffer_id: 0,
-            index_buffer_id: 0,
-        }
+        Self::default()
+            .with_attribute(MeshVertexAttribute::POSITION, vertices.iter().map(|vertex| vertex.position).collect::<Vec<_>>())
+            .with_attribute(MeshVertexAttribute::COLOR, vertices.iter().map(|vertex| vertex.color).collect::<Vec<_>>())
     }
 
     pub fn with_indices(
         vertices: Vec<Vertex>,
         indices: Vec<u32>
     ) -> Self {
-        Self {
-            vertices,
-            indices: Some(indices),
-            vertex_buffer_id: 0,
-            index_buffer_id: 0,
+        let mut mesh = Self::new(vertices);
+        mesh.set_indices(indices);
+        mesh
+    }
+
+    /// Adds or replaces an attribute. Every attribute must have one value per vertex and its values
+    /// must match the attribute's format
+    pub fn with_attribute(mut self, attribute: MeshVertexAttribute, values: impl Into<VertexAttributeValues>) -> Self {
+        self.insert_attribute(attribute, values);
+        self
+    }
+
+    pub fn insert_attribute(&mut self, attribute: MeshVertexAttribute, values: impl Into<VertexAttributeValues>) {
+        let values = values.into();
+        assert_eq!(
+            values.format(), attribute.format,
+            "Values of mesh attribute {} must have format {:?}", attribute.name, attribute.format
+        );
+        if let Some((_, existing_values)) = self.attributes.values().find(|(existing, _)| existing.shader_location != attribute.shader_location) {
+            assert_eq!(values.len(), existing_values.len(), "Mesh attribute {} must have one value per vertex", attribute.name);
+        }
+
+        self.attributes.insert(attribute.shader_location, (attribute, values));
+        self.vertex_buffer_layout = MeshVertexBufferLayout::from_attributes(self.attributes.values().map(|(attribute, _)| attribute));
+    }
+
+    pub fn remove_attribute(&mut self, attribute: MeshVertexAttribute) -> Option<VertexAttributeValues> {
+        let (_, values) = self.attributes.remove(&attribute.shader_location)?;
+        self.vertex_buffer_layout = MeshVertexBufferLayout::from_attributes(self.attributes.values().map(|(attribute, _)| attribute));
+        Some(values)
+    }
+
+    pub fn attribute(&self, attribute: MeshVertexAttribute) -> Option<&VertexAttributeValues> {
+        self.attributes.get(&attribute.shader_location)
+            .filter(|(existing, _)| *existing == attribute)
+            .map(|(_, values)| values)
+    }
+
+    pub fn attributes(&self) -> impl Iterator<Item = (&MeshVertexAttribute, &VertexAttributeValues)> {
+        self.attributes.values().map(|(attribute, values)| (attribute, values))
+    }
+
+    pub fn set_indices(&mut self, indices: Vec<u32>) {
+        self.indices = Some(indices);
+    }
+
+    /// Sets the per-vertex normals of this mesh. There must be one normal for each vertex
+    pub fn with_normals(self, normals: Vec<[f32; 3]>) -> Self {
+        self.with_attribute(MeshVertexAttribute::NORMAL, normals)
+    }
+
+    /// Sets the per-vertex texture coordinates of this mesh. There must be one uv for each vertex
+    pub fn with_uvs(self, uvs: Vec<[f32; 2]>) -> Self {
+        self.with_attribute(MeshVertexAttribute::UV_0, uvs)
+    }
+
+    /// Replaces the color of every vertex in this mesh
+    pub fn with_vertex_color(self, color: [f32; 3]) -> Self {
+        let num_vertices = self.num_vertices();
+        self.with_attribute(MeshVertexAttribute::COLOR, vec![color; num_vertices])
+    }
+
+    pub fn indices(&self) -> Option<&[u32]> {
+        self.indices.as_deref()
+    }
+
+    pub fn positions(&self) -> Option<&[[f32; 3]]> {
+        match self.attribute(MeshVertexAttribute::POSITION)? {
+            VertexAttributeValues::Float32x3(positions) => Some(positions),
+            _ => None,
+        }
+    }
+
+    pub fn normals(&self) -> Option<&[[f32; 3]]> {
+        match self.attribute(MeshVertexAttribute::NORMAL)? {
+            VertexAttributeValues::Float32x3(normals) => Some(normals),
+            _ => None,
+        }
+    }
+
+    pub fn uvs(&self) -> Option<&[[f32; 2]]> {
+        match self.attribute(MeshVertexAttribute::UV_0)? {
+            VertexAttributeValues::Float32x2(uvs) => Some(uvs),
+            _ => None,
         }
     }
 
     pub fn num_vertices(&self) -> usize {
-        self.vertices.len()
+        self.attributes.values().next().map_or(0, |(_, values)| values.len())
     }
 
     pub fn num_indices(&self) -> usize {
@@ -55,12 +141,49 @@ impl Mesh {
     pub fn has_indices(&self) -> bool {
         self.indices.is_some()
     }
+
+    pub fn vertex_buffer_layout(&self) -> &MeshVertexBufferLayout {
+        &self.vertex_buffer_layout
+    }
+
+    /// The attributes of every vertex interleaved in the order of [`Mesh::vertex_buffer_layout`]
+    pub fn vertex_buffer_data(&self) -> Vec<u8> {
+        let num_vertices = self.num_vertices();
+        let mut data = Vec::with_capacity(num_vertices * self.vertex_buffer_layout.array_stride as usize);
+        for index in 0..num_vertices {
+            for (_, values) in self.attributes.values() {
+                data.extend_from_slice(values.vertex_bytes(index));
+            }
+        }
+        data
+    }
 }
 
 pub fn setup_on_add_hook_for_mesh(world: &mut World) {
     world.register_component_hooks::<Mesh>().on_add(create_gpu_buffer_for_mesh);
 }
 
+/// Attaches a loaded [`Mesh`] asset (e.g. `asset_server.load("simple_scene.obj#Cube")`) to an entity.
+/// The [`Mesh`] component is inserted once the asset has finished loading. Objects of an OBJ file
+/// also get the material they select with `usemtl`, see [`insert_obj_materials`]
+///
+/// [`insert_obj_materials`]: crate::assets::obj::insert_obj_materials
+#[derive(Component)]
+pub struct MeshHandle(pub Handle<Mesh>);
+
+pub fn insert_loaded_meshes(
+    mut commands: Commands,
+    pending_meshes: Query<(Entity, &MeshHandle), Without<Mesh>>,
+    mesh_assets: Res<Assets<Mesh>>,
+) {
+    for (entity, mesh_handle) in &pending_meshes {
+        if let Some(mesh) = mesh_assets.get(&mesh_handle.0) {
+            log::debug!("Mesh asset loaded, inserting mesh for entity_id={}", entity);
+            commands.entity(entity).insert(mesh.clone());
+        }
+    }
+}
+
 #[derive(Resource)]
 pub struct GpuMeshes {
     pub buffers_map: HashMap<u64, wgpu::Buffer>
@@ -76,7 +199,7 @@ pub fn create_gpu_buffer_for_mesh(
         let buffer_id = random();
         let buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: Some(format!("{}", buffer_id).as_str()),
-            contents: cast_slice(&mesh_component.vertices),
+            contents: &mesh_component.vertex_buffer_data(),
             usage: wgpu::BufferUsages::VERTEX,
         });
 
@@ -120,6 +243,7 @@ pub fn create_gpu_buffer_for_mesh(
 pub struct Mesh2D {
     vertices: Vec<Vertex2D>,
     indices: Option<Vec<u32>>,
+    vertex_buffer_layout: MeshVertexBufferLayout,
     pub vertex_buffer_id: u64,
     pub index_buffer_id: u64,
 }
@@ -131,6 +255,7 @@ impl Mesh2D {
         Self {
             vertices,
             indices: None,
+            vertex_buffer_layout: Vertex2D::mesh_vertex_buffer_layout(),
             vertex_buffer_id: 0,
             index_buffer_id: 0,
         }
@@ -143,6 +268,7 @@ impl Mesh2D {
         Self {
             vertices,
             indices: Some(indices),
+            vertex_buffer_layout: Vertex2D::mesh_vertex_buffer_layout(),
             vertex_buffer_id: 0,
             index_buffer_id: 0,
         }
@@ -163,6 +289,10 @@ impl Mesh2D {
     pub fn has_indices(&self) -> bool {
         self.indices.is_some()
     }
+
+    pub fn vertex_buffer_layout(&self) -> &MeshVertexBufferLayout {
+        &self.vertex_buffer_layout
+    }
 }
 
 pub fn setup_on_add_hook_for_mesh2d(world: &mut World) {
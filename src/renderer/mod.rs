@@ -4,23 +4,39 @@ pub mod pipeline;
 pub mod vertex;
 pub mod texture;
 pub mod material;
-
-use std::f32::consts::PI;
+pub mod frame;
+pub mod model;
+pub mod light;
+pub mod shadow;
+pub mod screenshot;
+pub mod viewport;
+
+use std::sync::Arc;
 use bevy::asset::{handle_internal_asset_events, LoadState};
 use bevy::prelude::*;
-use bevy::utils::HashMap;
-use wgpu::{CompositeAlphaMode, InstanceDescriptor, StoreOp};
+use bevy::utils::{HashMap, HashSet};
+use wgpu::{CompositeAlphaMode, InstanceDescriptor};
 use log::{error};
-use crate::app::schedule::{Initialization, PreRender, Render};
-use crate::app::WindowState;
+use thiserror::Error;
+use crate::app::schedule::{Initialization, Last, PreRender, Render, RenderCamera};
+use crate::app::{HeadlessSettings, WindowResized, WindowState};
 use crate::assets::shaders::{Shader, ShadersState, DEFAULT_2D_SHADER, DEFAULT_3D_SHADER};
 use crate::assets::{initialize_asset_server, tick_task_pools};
 use crate::assets::materials::Material;
-use crate::renderer::camera::Camera;
-use crate::renderer::material::{DefaultMaterial, MeshMaterial};
-use crate::renderer::mesh::{setup_on_add_hook_for_mesh, setup_on_add_hook_for_mesh2d, GpuMeshes, Mesh, Mesh2D};
-use crate::renderer::pipeline::Pipelines;
-use crate::renderer::vertex::{Vertex, Vertex2D};
+use crate::assets::obj::insert_obj_materials;
+use crate::renderer::camera::{clear_camera, prepare_cameras, render_cameras, CameraUniforms, CurrentCamera, RenderLayers};
+use crate::renderer::model::{prepare_model_uniforms, ModelUniforms, MODEL_BIND_GROUP_INDEX};
+use crate::renderer::light::{prepare_lights, LightUniforms, LIGHTS_BIND_GROUP_INDEX};
+use crate::renderer::shadow::{prepare_shadow_pipelines, render_shadow_maps, ShadowMaps, ShadowPasses};
+use crate::renderer::frame::{begin_frame, end_frame, ClearColor, Frame};
+use crate::renderer::screenshot::{queue_screenshots, Screenshot, ScreenshotRequests};
+use crate::renderer::material::{prepare_material_bind_groups, prepare_material_pipelines, render_mesh_with_material, DefaultMaterial, MaterialBindGroups, MeshMaterial, MATERIAL_BIND_GROUP_INDEX};
+use crate::renderer::mesh::{insert_loaded_meshes, setup_on_add_hook_for_mesh, setup_on_add_hook_for_mesh2d, GpuMeshes, Mesh, Mesh2D};
+use crate::renderer::texture::{prepare_images, DepthTexture, GpuImages};
+use crate::renderer::pipeline::{reload_modified_shaders, Pipelines};
+use crate::renderer::viewport::ViewportClear;
+use crate::renderer::vertex::VertexLayout;
+use crate::transform::propagate_transforms;
 
 pub struct Fathom3DRenderPlugin;
 
@@ -29,13 +45,36 @@ impl Plugin for Fathom3DRenderPlugin {
         app.add_systems(Initialization, (
             initialize_renderer,
             initialize_asset_server,
-            initialize_render_resources,
-            add_default_render_resources,
-            setup_on_add_hook_for_mesh
+            (
+                initialize_render_resources,
+                add_default_render_resources,
+                setup_on_add_hook_for_mesh
+            ).chain().run_if(resource_exists::<RendererState>)
+        ).chain());
+        app.init_resource::<ClearColor>();
+        app.add_event::<Screenshot>();
+        app.init_resource::<ScreenshotRequests>();
+        app.add_systems(PreRender, (
+            (resize_surface, insert_loaded_meshes, insert_obj_materials).chain().run_if(resource_exists::<RendererState>),
+            propagate_transforms,
+            (
+                prepare_model_uniforms,
+                prepare_images,
+                prepare_cameras,
+                prepare_lights,
+                prepare_shadow_pipelines,
+                reload_modified_shaders,
+                prepare_material_pipelines,
+                prepare_material_bind_groups,
+                begin_frame
+            ).chain().run_if(resource_exists::<RendererState>)
         ).chain());
-        app.add_systems(PreRender, pre_render);
-        app.add_systems(Render, default_3d_render_pass);
-        app.add_systems(Last, tick_task_pools);
+        app.add_systems(Render, (render_shadow_maps, render_cameras).chain().run_if(resource_exists::<RendererState>));
+        app.add_systems(RenderCamera, (clear_camera, default_3d_render_pass, render_mesh_with_material).chain());
+        app.add_systems(Last, (
+            (queue_screenshots, end_frame).chain().run_if(resource_exists::<RendererState>),
+            tick_task_pools
+        ));
     }
 }
 
@@ -45,50 +84,48 @@ impl Plugin for Fathom2DRenderPlugin {
         app.add_systems(Initialization, (
             initialize_renderer,
             initialize_asset_server,
-            initialize_render_resources,
-            add_default_2d_render_resources,
-            setup_on_add_hook_for_mesh2d
+            (
+                initialize_render_resources,
+                add_default_2d_render_resources,
+                setup_on_add_hook_for_mesh2d
+            ).chain().run_if(resource_exists::<RendererState>)
         ).chain());
-        app.add_systems(Render, render2d);
-        app.add_systems(Last, tick_task_pools);
+        app.init_resource::<ClearColor>();
+        app.add_event::<Screenshot>();
+        app.init_resource::<ScreenshotRequests>();
+        app.add_systems(PreRender, (resize_surface, reload_modified_shaders, prepare_material_pipelines, begin_frame)
+            .chain()
+            .run_if(resource_exists::<RendererState>));
+        app.add_systems(Render, render2d.run_if(resource_exists::<RendererState>));
+        app.add_systems(Last, (
+            (queue_screenshots, end_frame).chain().run_if(resource_exists::<RendererState>),
+            tick_task_pools
+        ));
     }
 }
 
-pub fn initialize_renderer(mut world: &mut World) {
-    let window_state = world.get_resource_mut::<WindowState>().unwrap();
-    let window = window_state.clone_window();
+/// Creates the wgpu device and the target frames are rendered to. With [`HeadlessSettings`] the
+/// target is an offscreen texture and any adapter is accepted, preferring a GPU over a software
+/// fallback, otherwise it is the window's surface. When a headless application has no adapter the
+/// error is logged and [`RendererState`] is never inserted, so nothing is rendered but the rest of
+/// the application still runs
+pub fn initialize_renderer(world: &mut World) {
     let instance = wgpu::Instance::new(InstanceDescriptor::default());
-    let surface = instance.create_surface(window.clone())
-        .expect("Could not create a surface");
-    let adapter = pollster::block_on(create_adapter(&instance, &surface))
-        .expect("Could not create wgpu adapter");
-    let (device, queue) = pollster::block_on(create_device_and_queue(&adapter))
-        .expect("Could not create device and queue with given adapter");
-    let capabilities = surface.get_capabilities(&adapter);
-    let format = capabilities.formats.iter()
-        .find(|format| format.is_srgb())
-        .copied()
-        .expect("Unable to find a suitable texture format");
-    let config = wgpu::SurfaceConfiguration {
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        format,
-        width: window.inner_size().width,
-        height: window.inner_size().height,
-        present_mode: wgpu::PresentMode::AutoVsync,
-        desired_maximum_frame_latency: 2,
-        view_formats: vec![],
-        alpha_mode: CompositeAlphaMode::Auto
+    let renderer_state = match world.get_resource::<HeadlessSettings>() {
+        Some(settings) => match RendererState::new_headless(instance, settings) {
+            Ok(renderer_state) => renderer_state,
+            Err(error) => {
+                error!("Rendering is disabled: {}", error);
+                return;
+            }
+        },
+        None => {
+            let window = world.resource::<WindowState>().clone_window();
+            RendererState::new_windowed(instance, window)
+        }
     };
-    surface.configure(&device, &config);
-
-    world.insert_resource(RendererState {
-        instance,
-        config,
-        surface,
-        adapter,
-        device,
-        queue,
-    });
+
+    world.insert_resource(renderer_state);
 }
 
 pub fn initialize_render_resources(
@@ -117,6 +154,7 @@ pub fn initialize_render_resources(
                     tick_task_pools()
                 },
                 LoadState::Loaded => break,
+                LoadState::Failed(error) => panic!("Unable to load the default shader: {}", error),
                 _ => log::debug!("**** Load state is: {:?}", load_state)
             }
         }
@@ -130,15 +168,11 @@ pub fn initialize_render_resources(
         log::debug!("Cloned handles are NOT the same {:?} != {:?}", default_3d_shader, new_handle);
     }
 
-    world.insert_resource(Pipelines {
-        registered_pipelines: HashMap::new(),
-        material_to_pipeline_id_map: HashMap::new(),
-        render_pipeline_state: HashMap::new(),
-        default_material: None,
-    });
+    world.insert_resource(Pipelines::default());
 
     let mut shader_state = ShadersState {
         loaded_shader_modules: HashMap::new(),
+        failed_shader_modules: HashSet::new(),
         shader_handles: Vec::new(),
     };
 
@@ -149,178 +183,108 @@ pub fn initialize_render_resources(
     world.insert_resource(GpuMeshes {
         buffers_map: HashMap::new(),
     });
-
+    world.insert_resource(GpuImages::default());
 }
 
+/// Registers the 3D pipeline layout, camera uniforms, material bind groups, light uniforms and
+/// shadow maps and adds the default 3D material. Its pipeline is created in PreRender like any
+/// other material's
 pub fn add_default_render_resources(
     renderer_state: Res<RendererState>,
-    mut shaders_state: ResMut<ShadersState>,
+    shaders_state: Res<ShadersState>,
     mut pipelines: ResMut<Pipelines>,
-    shader_assets: Res<Assets<Shader>>,
-    mut materials: ResMut<Assets<Material>>
+    mut materials: ResMut<Assets<Material>>,
+    mut commands: Commands,
 ) {
     log::debug!("Adding default render resources...");
     let device = &renderer_state.as_ref().device;
     let shader_handle = shaders_state.shader_handles.get(0)
         .expect("No shader handles available. Default shader should be the first element")
         .clone();
-    let default_shader = shader_assets.get(&shader_handle.clone())
-        .expect("Could not get default shader from provided handle");
-
-    log::debug!("*** DEFAULT SHADER START");
-    log::debug!("================");
-    log::debug!("\n{}\n", default_shader.shader_content.clone());
-    log::debug!("================");
-    log::debug!("*** DEFAULT SHADER END");
-
-    let shader_module = device.create_shader_module(
-        wgpu::ShaderModuleDescriptor {
-            label: Some("default_3d_shader"),
-            source: wgpu::ShaderSource::Wgsl(default_shader.shader_content.clone().into()) ,
-        }
-    );
-
-    shaders_state.loaded_shader_modules.insert(shader_handle.clone(), shader_module);
-
-    if let (Some(vertex_shader_module), Some(_fragment_shader_module)) =
-        (shaders_state.loaded_shader_modules.get(&shader_handle.clone()), shaders_state.loaded_shader_modules.get(&shader_handle.clone())) {
-        let format = renderer_state.config.format.clone();
-
-        let (pipeline_layout, uniform_buffer, uniform_bind_group) =
-            Pipelines::create_uniform(device, &[Mat4::IDENTITY]);
-
-        let render_pipeline = Pipelines::pipeline_builder(device)
-            .with_label("Default 3D Render Pipeline")
-            .with_layout(&pipeline_layout)
-            .with_vertex_shader(&vertex_shader_module)
-            .with_fragment_shader(&vertex_shader_module)
-            .with_vertex_entry_point("vertex_main")
-            .with_fragment_entry_point("fragment_main")
-            .with_vertex_buffers(&[Vertex::vertex_buf_layout()])
-            .with_color_state_targets(&[Some(format.into())])
-            .build();
-
-        pipelines.registered_pipelines.insert(1, render_pipeline);
-        let default_material_handle = materials.add(Material {
-            vertex_shader: shader_handle.clone(),
-            fragment_shader: shader_handle.clone(),
-            material_pipeline_id: 1,
-        });
-        pipelines.material_to_pipeline_id_map.insert(
-            default_material_handle,
-            1
-        );
-        pipelines.render_pipeline_state.insert(1, (pipeline_layout, uniform_buffer, uniform_bind_group));
 
-    } else {
-        error!("Unable to create default pipeline because default shaders were not loaded");
-    }
+    let model_uniforms = ModelUniforms::new(device);
+    let material_bind_groups = MaterialBindGroups::new(device, &renderer_state.queue);
+    let shadow_maps = ShadowMaps::new(device);
+    let shadow_passes = ShadowPasses::new(device, &model_uniforms.bind_group_layout);
+    let light_uniforms = LightUniforms::new(device, &shadow_maps);
+    let camera_uniforms = CameraUniforms::new(device);
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("3D Pipeline Layout"),
+        bind_group_layouts: &[
+            &camera_uniforms.bind_group_layout,
+            &model_uniforms.bind_group_layout,
+            &material_bind_groups.bind_group_layout,
+            &light_uniforms.bind_group_layout,
+        ],
+        push_constant_ranges: &[],
+    });
+    pipelines.pipeline_layouts.insert(VertexLayout::Vertex3D, pipeline_layout);
+
+    let default_material_handle = materials.add(Material::new(shader_handle.clone(), shader_handle));
+    commands.insert_resource(DefaultMaterial(default_material_handle));
+    commands.insert_resource(camera_uniforms);
+    commands.insert_resource(ViewportClear::new(device));
+    commands.insert_resource(model_uniforms);
+    commands.insert_resource(material_bind_groups);
+    commands.insert_resource(light_uniforms);
+    commands.insert_resource(shadow_maps);
+    commands.insert_resource(shadow_passes);
 }
 
+/// Registers the 2D pipeline layout and adds the default 2D material
 pub fn add_default_2d_render_resources(
     renderer_state: Res<RendererState>,
-    mut shaders_state: ResMut<ShadersState>,
+    shaders_state: Res<ShadersState>,
     mut pipelines: ResMut<Pipelines>,
-    shader_assets: Res<Assets<Shader>>,
-    mut materials: ResMut<Assets<Material>>
+    mut materials: ResMut<Assets<Material>>,
+    mut commands: Commands,
 ) {
     let device = &renderer_state.as_ref().device;
     let shader_handle = shaders_state.shader_handles.get(1)
-        .expect("No shader handles available. Default shader should be the first element")
+        .expect("No shader handles available. Default 2D shader should be the second element")
         .clone();
-    let default_shader = shader_assets.get(&shader_handle.clone())
-        .expect("Could not get default shader from provided handle");
-    let shader_module = device.create_shader_module(
-        wgpu::ShaderModuleDescriptor {
-            label: Some("default_2d_shader"),
-            source: wgpu::ShaderSource::Wgsl(default_shader.shader_content.clone().into()) ,
-        }
-    );
-
-    shaders_state.loaded_shader_modules.insert(shader_handle.clone(), shader_module);
-
-    if let Some(shader_module) = shaders_state.loaded_shader_modules.get(&shader_handle.clone()) {
-        let format = renderer_state.config.format.clone();
-
-        let render_pipeline = Pipelines::pipeline_builder(device)
-            .with_label("Default 2D Render Pipeline")
-            .with_vertex_shader(&shader_module)
-            .with_fragment_shader(&shader_module)
-            .with_vertex_entry_point("vertex_main")
-            .with_fragment_entry_point("fragment_main")
-            .with_vertex_buffers(&[Vertex2D::vertex_buf_layout()])
-            .with_color_state_targets(&[Some(format.into())])
-            .build();
-
-        pipelines.registered_pipelines.insert(2, render_pipeline);
-        let default_material_handle = materials.add(Material {
-            vertex_shader: Default::default(),
-            fragment_shader: Default::default(),
-            material_pipeline_id: 1,
-        });
-        pipelines.material_to_pipeline_id_map.insert(
-            default_material_handle,
-            2
-        );
-    } else {
-        error!("Unable to create default pipeline because default shaders were not loaded");
-    }
-}
 
-pub fn pre_render(
-    window_state: ResMut<WindowState>,
-    renderer_state: ResMut<RendererState>,
-    pipelines: ResMut<Pipelines>,
-    camera: Query<&Camera>,
-) {
-    let (_pipeline_layout, uniform_buffer, _uniform_bind_group) = pipelines.render_pipeline_state.get(&1).unwrap();
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("2D Pipeline Layout"),
+        bind_group_layouts: &[],
+        push_constant_ranges: &[],
+    });
+    pipelines.pipeline_layouts.insert(VertexLayout::Vertex2D, pipeline_layout);
 
-    let camera = camera.single();
-    let aspect_ratio = window_state.window().inner_size().width as f32 / window_state.window().inner_size().height as f32;
-    let mvp_matrix = Mat4::perspective_rh(2.0*PI/5.0, aspect_ratio, 0.1, 100.0) * camera.transform.inverse();
+    let default_material_handle = materials.add(Material::new_2d(shader_handle.clone(), shader_handle));
+    commands.insert_resource(DefaultMaterial(default_material_handle));
+}
 
-    renderer_state.queue.write_buffer(&uniform_buffer, 0, bytemuck::cast_slice(&[mvp_matrix]));
+/// Reconfigures the surface and every size dependent render target to match the window size
+pub fn resize_surface(
+    mut window_resized_events: EventReader<WindowResized>,
+    mut renderer_state: ResMut<RendererState>,
+) {
+    if let Some(window_resized) = window_resized_events.read().last() {
+        renderer_state.resize(window_resized.width, window_resized.height);
+    }
 }
 
 pub fn render2d(
     renderable_entities: Query<&Mesh2D>,
-    pipelines: ResMut<Pipelines>,
-    default_material_opt: Option<ResMut<DefaultMaterial>>,
+    pipelines: Res<Pipelines>,
+    default_material_opt: Option<Res<DefaultMaterial>>,
     gpu_meshes: Res<GpuMeshes>,
     renderer_state: Res<RendererState>,
+    frame_opt: Option<ResMut<Frame>>,
 ) {
-    if let Some(default_material) = default_material_opt.as_ref() {
-        let device = &renderer_state.device;
-        let queue = &renderer_state.queue;
-        let surface = &renderer_state.surface;
-        let frame = surface.get_current_texture().unwrap();
-        let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Main rendering command encoder")
-        });
+    if let (Some(default_material), Some(mut frame)) = (default_material_opt, frame_opt) {
+        let mut render_pass = frame.begin_render_pass("2D render pass", None);
+
         for mesh in &renderable_entities {
-            let pipeline_id = pipelines.get_pipeline_id_by_material(default_material.0.clone())
-                .unwrap_or_else(|| panic!("Unable to get pipeline id for default material"));
-            let pipeline_opt = &pipelines.registered_pipelines
-                .get(pipeline_id);
+            let Some(pipeline_id) = pipelines.get_pipeline_id_by_material(&default_material.0, mesh.vertex_buffer_layout(), renderer_state.config.format) else {
+                // The pipeline is created once the default shader has been compiled
+                continue;
+            };
+            let pipeline_opt = pipelines.registered_pipelines.get(pipeline_id);
             let vertex_buffer_opt = gpu_meshes.buffers_map.get(&mesh.vertex_buffer_id);
 
-
             if let (Some(pipeline), Some(vertex_buffer)) = (pipeline_opt, vertex_buffer_opt) {
-                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: None,
-                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Load,
-                            store: StoreOp::Store,
-                        },
-                    })],
-                    depth_stencil_attachment: None,
-                    timestamp_writes: None,
-                    occlusion_query_set: None,
-                });
                 render_pass.set_pipeline(pipeline);
                 render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
                 if let Some(Some(index_buffer)) = &mesh.has_indices().then(|| gpu_meshes.buffers_map.get(&mesh.index_buffer_id)) {
@@ -330,86 +294,85 @@ pub fn render2d(
                     render_pass.draw(0..mesh.num_vertices() as u32, 0..1);
                 }
             } else {
-                error!("Unable to perform render pass for pipeline_id={} | vertex_buffer_id={}", pipeline_id, mesh.vertex_buffer_id);
+                error!("Unable to draw mesh for pipeline_id={} | vertex_buffer_id={}", pipeline_id, mesh.vertex_buffer_id);
             }
         }
-
-        queue.submit(Some(encoder.finish()));
-        frame.present();
     }
-
 }
 
-/// This system renders any 3D Meshes that do not have a Material component. It uses the default 3d shader
+/// This system renders any 3D Meshes that do not have a Material component for the current camera.
+/// It uses the default 3d shader with a pipeline for each vertex buffer layout the meshes have
 pub fn default_3d_render_pass(
-    renderable_entities: Query<&Mesh, Without<MeshMaterial>>,
-    pipelines: ResMut<Pipelines>,
-    default_material_opt: Option<ResMut<DefaultMaterial>>,
+    renderable_entities: Query<(Entity, &Mesh, Option<&RenderLayers>), Without<MeshMaterial>>,
+    pipelines: Res<Pipelines>,
+    default_material_opt: Option<Res<DefaultMaterial>>,
     gpu_meshes: Res<GpuMeshes>,
+    gpu_images: Res<GpuImages>,
+    camera: Res<CurrentCamera>,
+    camera_uniforms: Res<CameraUniforms>,
+    model_uniforms: Res<ModelUniforms>,
+    material_bind_groups: Res<MaterialBindGroups>,
+    light_uniforms: Res<LightUniforms>,
     renderer_state: Res<RendererState>,
+    frame_opt: Option<ResMut<Frame>>,
 ) {
-    if let Some(default_material) = default_material_opt.as_ref() {
-        let device = &renderer_state.device;
-        let queue = &renderer_state.queue;
-        let surface = &renderer_state.surface;
-        let frame = surface.get_current_texture().unwrap();
-        let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Main rendering command encoder")
-        });
-
-        for mesh in &renderable_entities {
-            let pipeline_id = pipelines.get_pipeline_id_by_material(default_material.0.clone())
-                .unwrap_or_else(|| panic!("Unable to get pipeline id for default material"));
-            let pipeline_opt = &pipelines.registered_pipelines
-                .get(pipeline_id);
-            let vertex_buffer_opt = gpu_meshes.buffers_map.get(&mesh.vertex_buffer_id);
-
-
-            if let (Some(pipeline), Some(vertex_buffer)) = (pipeline_opt, vertex_buffer_opt) {
-                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: None,
-                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Load,
-                            store: StoreOp::Store,
-                        },
-                    })],
-                    depth_stencil_attachment: None,
-                    timestamp_writes: None,
-                    occlusion_query_set: None,
-                });
-                render_pass.set_pipeline(pipeline);
-                render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-
-                if let Some((_, _, uniform_bind_group)) = pipelines.render_pipeline_state.get(pipeline_id) {
-                    render_pass.set_bind_group(0, &uniform_bind_group, &[]);
-                }
+    let (Some(default_material), Some(mut frame)) = (default_material_opt, frame_opt) else {
+        return;
+    };
+    let Some(material_bind_group) = material_bind_groups.bind_group(&default_material.0) else {
+        return;
+    };
+    let camera = &camera.0;
+    if camera.renders_to_image_sampled_by(&default_material.0, &material_bind_groups) {
+        return;
+    }
+    let Some(mut render_pass) = camera.begin_render_pass(&mut frame, "Default 3D render pass", &camera_uniforms, &gpu_images, &renderer_state) else {
+        return;
+    };
+    render_pass.set_bind_group(0, &camera_uniforms.bind_group, &[camera.uniform_offset]);
+    render_pass.set_bind_group(MATERIAL_BIND_GROUP_INDEX, material_bind_group, &[]);
+    render_pass.set_bind_group(LIGHTS_BIND_GROUP_INDEX, &light_uniforms.bind_group, &[]);
 
-                if let Some(Some(index_buffer)) = &mesh.has_indices().then(|| gpu_meshes.buffers_map.get(&mesh.index_buffer_id)) {
-                    render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                    render_pass.draw_indexed(0..mesh.num_indices() as u32, 0, 0..1);
-                } else {
-                    render_pass.draw(0..mesh.num_vertices() as u32, 0..1);
-                }
+    for (entity, mesh, layers) in &renderable_entities {
+        if !camera.sees(layers) {
+            continue;
+        }
+        let Some(pipeline_id) = pipelines.get_pipeline_id_by_material(&default_material.0, mesh.vertex_buffer_layout(), camera.color_format) else {
+            // The pipeline is created once the default shader has been compiled
+            continue;
+        };
+        let Some((model_bind_group, model_offset)) = model_uniforms.bind_group(entity) else {
+            // The model uniform is created in PreRender, so meshes added since then are drawn next frame
+            continue;
+        };
+        let pipeline_opt = pipelines.registered_pipelines.get(pipeline_id);
+        let vertex_buffer_opt = gpu_meshes.buffers_map.get(&mesh.vertex_buffer_id);
+
+        if let (Some(pipeline), Some(vertex_buffer)) = (pipeline_opt, vertex_buffer_opt) {
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(MODEL_BIND_GROUP_INDEX, model_bind_group, &[model_offset]);
+            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            if let Some(Some(index_buffer)) = &mesh.has_indices().then(|| gpu_meshes.buffers_map.get(&mesh.index_buffer_id)) {
+                render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.num_indices() as u32, 0, 0..1);
             } else {
-                error!("Unable to perform render pass for pipeline_id={} | vertex_buffer_id={}", pipeline_id, mesh.vertex_buffer_id);
+                render_pass.draw(0..mesh.num_vertices() as u32, 0..1);
             }
+        } else {
+            error!("Unable to draw mesh for pipeline_id={} | vertex_buffer_id={}", pipeline_id, mesh.vertex_buffer_id);
         }
-
-        queue.submit(Some(encoder.finish()));
-        frame.present();
     }
-
 }
 
-async fn create_adapter(instance: &wgpu::Instance, surface: &wgpu::Surface<'_>) -> Option<wgpu::Adapter> {
+async fn create_adapter(
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface<'_>>,
+    force_fallback_adapter: bool,
+) -> Option<wgpu::Adapter> {
     instance.request_adapter(&wgpu::RequestAdapterOptions {
         power_preference: Default::default(),
-        force_fallback_adapter: false,
-        compatible_surface: Some(surface),
+        force_fallback_adapter,
+        compatible_surface: surface,
     }).await
 }
 
@@ -425,14 +388,149 @@ async fn create_device_and_queue(adapter: &wgpu::Adapter) -> Result<(wgpu::Devic
     ).await
 }
 
+#[derive(Debug, Error)]
+pub enum RendererError {
+    #[error("No wgpu adapter is available, headless rendering needs a GPU or a software rasterizer")]
+    NoAdapter,
+    #[error("Could not create a device with the adapter: {0}")]
+    RequestDevice(#[from] wgpu::RequestDeviceError),
+}
+
+/// Where frames are rendered to
+pub(crate) enum SurfaceTarget {
+    Surface(wgpu::Surface<'static>),
+    /// Used by headless applications. It can be copied from, e.g. to read back a frame
+    Offscreen(wgpu::Texture),
+}
+
+impl SurfaceTarget {
+    fn create_offscreen_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Render Target"),
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: config.usage | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        })
+    }
+}
+
 #[derive(Resource)]
 pub struct RendererState {
     instance: wgpu::Instance,
+    /// The format and size of the render target. Headless applications never configure a surface
+    /// with it, but use it the same way to size and format the offscreen texture
     config: wgpu::SurfaceConfiguration,
-    surface: wgpu::Surface<'static>,
+    surface_target: SurfaceTarget,
     adapter: wgpu::Adapter,
     device: wgpu::Device,
     queue: wgpu::Queue,
+    depth_texture: DepthTexture,
+}
+
+impl RendererState {
+    fn new_windowed(instance: wgpu::Instance, window: Arc<winit::window::Window>) -> Self {
+        let surface = instance.create_surface(window.clone())
+            .expect("Could not create a surface");
+        let adapter = pollster::block_on(create_adapter(&instance, Some(&surface), false))
+            .expect("Could not create wgpu adapter");
+        let (device, queue) = pollster::block_on(create_device_and_queue(&adapter))
+            .expect("Could not create device and queue with given adapter");
+        let capabilities = surface.get_capabilities(&adapter);
+        let format = capabilities.formats.iter()
+            .find(|format| format.is_srgb())
+            .copied()
+            .expect("Unable to find a suitable texture format");
+        let config = wgpu::SurfaceConfiguration {
+            // Copying from the surface is needed to capture screenshots, when it is supported
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | (capabilities.usages & wgpu::TextureUsages::COPY_SRC),
+            format,
+            width: window.inner_size().width,
+            height: window.inner_size().height,
+            present_mode: wgpu::PresentMode::AutoVsync,
+            desired_maximum_frame_latency: 2,
+            view_formats: vec![],
+            alpha_mode: CompositeAlphaMode::Auto
+        };
+        surface.configure(&device, &config);
+        let depth_texture = DepthTexture::new(&device, config.width, config.height);
+
+        Self {
+            instance,
+            config,
+            surface_target: SurfaceTarget::Surface(surface),
+            adapter,
+            device,
+            queue,
+            depth_texture,
+        }
+    }
+
+    fn new_headless(instance: wgpu::Instance, settings: &HeadlessSettings) -> Result<Self, RendererError> {
+        let adapter = if settings.force_fallback_adapter {
+            pollster::block_on(create_adapter(&instance, None, true))
+        } else {
+            pollster::block_on(create_adapter(&instance, None, false))
+                .or_else(|| pollster::block_on(create_adapter(&instance, None, true)))
+        }.ok_or(RendererError::NoAdapter)?;
+        log::info!("Rendering headless with adapter {:?}", adapter.get_info());
+        let (device, queue) = pollster::block_on(create_device_and_queue(&adapter))?;
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width: settings.width.max(1),
+            height: settings.height.max(1),
+            present_mode: wgpu::PresentMode::AutoVsync,
+            desired_maximum_frame_latency: 2,
+            view_formats: vec![],
+            alpha_mode: CompositeAlphaMode::Auto
+        };
+        let texture = SurfaceTarget::create_offscreen_texture(&device, &config);
+        let depth_texture = DepthTexture::new(&device, config.width, config.height);
+
+        Ok(Self {
+            instance,
+            config,
+            surface_target: SurfaceTarget::Offscreen(texture),
+            adapter,
+            device,
+            queue,
+            depth_texture,
+        })
+    }
+
+    /// Reconfigures the render target and the depth texture for the new size. A size of zero (e.g.
+    /// when the window is minimized) can not be configured, so the previous configuration is kept
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            log::debug!("Ignoring resize to {}x{}", width, height);
+            return;
+        }
+        if width == self.config.width && height == self.config.height {
+            return;
+        }
+
+        log::debug!("Resizing render target to {}x{}", width, height);
+        self.config.width = width;
+        self.config.height = height;
+        match &mut self.surface_target {
+            SurfaceTarget::Surface(surface) => surface.configure(&self.device, &self.config),
+            SurfaceTarget::Offscreen(texture) => *texture = SurfaceTarget::create_offscreen_texture(&self.device, &self.config),
+        }
+        self.recreate_depth_texture();
+    }
+
+    /// Recreates the depth texture so it matches the current surface configuration size
+    pub fn recreate_depth_texture(&mut self) {
+        self.depth_texture = DepthTexture::new(&self.device, self.config.width, self.config.height);
+    }
 }
 
 
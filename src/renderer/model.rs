@@ -0,0 +1,174 @@
+use std::num::NonZeroU64;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bytemuck::{Pod, Zeroable};
+use crate::renderer::mesh::Mesh;
+use crate::renderer::RendererState;
+use crate::transform::GlobalTransform;
+
+/// Bind group index the model uniforms are bound to in 3D pipelines. Group 0 holds the camera
+pub const MODEL_BIND_GROUP_INDEX: u32 = 1;
+const INITIAL_MODEL_CAPACITY: usize = 64;
+
+/// Multiplies the color of every fragment of a mesh. Meshes without a tint are drawn with white
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Tint(pub [f32; 4]);
+
+impl Default for Tint {
+    fn default() -> Self {
+        Self([1.0, 1.0, 1.0, 1.0])
+    }
+}
+
+/// The per-draw data for a single mesh, laid out to match `ModelUniforms` in the 3D shaders
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuModelUniform {
+    pub model: [[f32; 4]; 4],
+    /// A WGSL `mat3x3<f32>` has each column padded to 16 bytes
+    pub normal: [[f32; 4]; 3],
+    pub tint: [f32; 4],
+}
+
+impl GpuModelUniform {
+    pub fn new(global_transform: &GlobalTransform, tint: &Tint) -> Self {
+        let normal_matrix = global_transform.normal_matrix();
+        Self {
+            model: global_transform.matrix().to_cols_array_2d(),
+            normal: [
+                normal_matrix.x_axis.extend(0.0).to_array(),
+                normal_matrix.y_axis.extend(0.0).to_array(),
+                normal_matrix.z_axis.extend(0.0).to_array(),
+            ],
+            tint: tint.0,
+        }
+    }
+}
+
+/// GPU side per-draw data for every entity with a [`Mesh`]. The data for all meshes is written into
+/// a single uniform buffer each frame and each mesh is drawn with its own dynamic offset into that
+/// buffer, so the whole scene is uploaded with one write no matter how many meshes there are
+#[derive(Resource)]
+pub struct ModelUniforms {
+    pub(crate) bind_group_layout: wgpu::BindGroupLayout,
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// Number of models the buffer can currently hold
+    capacity: usize,
+    /// Distance in bytes between two models in the buffer, padded to the device's minimum uniform
+    /// buffer offset alignment
+    stride: usize,
+    staging: Vec<u8>,
+    entity_offsets: HashMap<Entity, u32>,
+}
+
+impl ModelUniforms {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Self::binding_size(),
+                },
+                count: None,
+            }],
+            label: Some("Model Bind Group Layout"),
+        });
+
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as usize;
+        let stride = size_of::<GpuModelUniform>().div_ceil(alignment) * alignment;
+        let (buffer, bind_group) = Self::create_buffer(device, &bind_group_layout, INITIAL_MODEL_CAPACITY, stride);
+
+        Self {
+            bind_group_layout,
+            buffer,
+            bind_group,
+            capacity: INITIAL_MODEL_CAPACITY,
+            stride,
+            staging: Vec::new(),
+            entity_offsets: HashMap::new(),
+        }
+    }
+
+    fn binding_size() -> Option<NonZeroU64> {
+        NonZeroU64::new(size_of::<GpuModelUniform>() as u64)
+    }
+
+    fn create_buffer(
+        device: &wgpu::Device,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        capacity: usize,
+        stride: usize,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Model Uniform Buffer"),
+            size: (capacity * stride) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffer,
+                    offset: 0,
+                    size: Self::binding_size(),
+                }),
+            }],
+            label: Some("Model Bind Group"),
+        });
+
+        (buffer, bind_group)
+    }
+
+    /// The bind group to bind at [`MODEL_BIND_GROUP_INDEX`] along with the dynamic offset of the
+    /// entity's data, or `None` if the entity's data has not been written yet
+    pub fn bind_group(&self, entity: Entity) -> Option<(&wgpu::BindGroup, u32)> {
+        self.entity_offsets.get(&entity).map(|offset| (&self.bind_group, *offset))
+    }
+}
+
+/// Packs the [`GlobalTransform`] and [`Tint`] of every mesh into the model uniform buffer and
+/// uploads it with a single write, growing the buffer when there are more meshes than it can hold
+pub fn prepare_model_uniforms(
+    renderer_state: Res<RendererState>,
+    mut model_uniforms: ResMut<ModelUniforms>,
+    meshes: Query<(Entity, &GlobalTransform, Option<&Tint>), With<Mesh>>,
+) {
+    let model_uniforms = model_uniforms.as_mut();
+    let mesh_count = meshes.iter().count();
+    if mesh_count > model_uniforms.capacity {
+        let capacity = mesh_count.next_power_of_two();
+        log::debug!("Growing model uniform buffer from {} to {} models", model_uniforms.capacity, capacity);
+        let (buffer, bind_group) = ModelUniforms::create_buffer(
+            &renderer_state.device,
+            &model_uniforms.bind_group_layout,
+            capacity,
+            model_uniforms.stride,
+        );
+        model_uniforms.buffer = buffer;
+        model_uniforms.bind_group = bind_group;
+        model_uniforms.capacity = capacity;
+    }
+
+    let stride = model_uniforms.stride;
+    model_uniforms.entity_offsets.clear();
+    model_uniforms.staging.clear();
+    model_uniforms.staging.resize(mesh_count * stride, 0);
+    let default_tint = Tint::default();
+    for (index, (entity, global_transform, tint)) in meshes.iter().enumerate() {
+        let offset = index * stride;
+        let uniform = GpuModelUniform::new(global_transform, tint.unwrap_or(&default_tint));
+        model_uniforms.staging[offset..offset + size_of::<GpuModelUniform>()]
+            .copy_from_slice(bytemuck::bytes_of(&uniform));
+        model_uniforms.entity_offsets.insert(entity, offset as u32);
+    }
+
+    if !model_uniforms.staging.is_empty() {
+        renderer_state.queue.write_buffer(&model_uniforms.buffer, 0, &model_uniforms.staging);
+    }
+}
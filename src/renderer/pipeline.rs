@@ -1,27 +1,210 @@
 use bevy::prelude::*;
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 use bytemuck::NoUninit;
 use wgpu::util::DeviceExt;
 use crate::assets::materials::Material;
+use crate::assets::shaders::preprocessor::ShaderDef;
+use crate::assets::shaders::{Shader, ShaderModuleKey, ShadersState};
+use crate::renderer::texture::DepthTexture;
+use crate::renderer::vertex::{MeshVertexBufferLayout, VertexLayout};
+use crate::renderer::RendererState;
 
 pub type PipelineId = u64;
-#[derive(Resource)]
+
+/// Everything that determines how a render pipeline is created. Materials drawing meshes that
+/// produce the same key are drawn with the same pipeline
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub vertex_shader: Handle<Shader>,
+    pub fragment_shader: Handle<Shader>,
+    /// The material's defs and a def for each of the mesh's attributes. Sorted so the order defs
+    /// were added in does not matter
+    pub shader_defs: Vec<ShaderDef>,
+    pub vertex_layout: VertexLayout,
+    pub vertex_buffer_layout: MeshVertexBufferLayout,
+    pub blend: Option<wgpu::BlendState>,
+    pub cull_mode: Option<wgpu::Face>,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub color_format: wgpu::TextureFormat,
+}
+
+impl PipelineKey {
+    pub fn from_material(
+        material: &Material,
+        vertex_buffer_layout: &MeshVertexBufferLayout,
+        color_format: wgpu::TextureFormat,
+    ) -> Self {
+        let mut shader_defs = material.shader_defs.clone();
+        shader_defs.extend(vertex_buffer_layout.shader_defs());
+        shader_defs.sort();
+        shader_defs.dedup();
+        Self {
+            vertex_shader: material.vertex_shader.clone(),
+            fragment_shader: material.fragment_shader.clone(),
+            shader_defs,
+            vertex_layout: material.vertex_layout,
+            vertex_buffer_layout: vertex_buffer_layout.clone(),
+            blend: material.blend,
+            cull_mode: material.cull_mode,
+            depth_test: material.depth_test,
+            depth_write: material.depth_write,
+            color_format,
+        }
+    }
+
+    pub fn vertex_module_key(&self) -> ShaderModuleKey {
+        ShaderModuleKey {
+            shader: self.vertex_shader.clone(),
+            shader_defs: self.shader_defs.clone(),
+        }
+    }
+
+    pub fn fragment_module_key(&self) -> ShaderModuleKey {
+        ShaderModuleKey {
+            shader: self.fragment_shader.clone(),
+            shader_defs: self.shader_defs.clone(),
+        }
+    }
+}
+
+#[derive(Resource, Default)]
 pub struct Pipelines {
     pub(crate) registered_pipelines: HashMap<PipelineId, wgpu::RenderPipeline>,
-    pub(crate) material_to_pipeline_id_map: HashMap<Handle<Material>, PipelineId>,
-    pub(crate) render_pipeline_state: HashMap<PipelineId, (wgpu::PipelineLayout, wgpu::Buffer, wgpu::BindGroup)>,
-    pub default_material: Option<Handle<Material>>
+    pub(crate) key_to_pipeline_id_map: HashMap<PipelineKey, PipelineId>,
+    /// The pipeline of each material for every color format and vertex buffer layout it has been
+    /// drawn with. Keyed by id so materials are not kept alive by having been drawn
+    pub(crate) material_to_pipeline_id_map: HashMap<AssetId<Material>, HashMap<wgpu::TextureFormat, HashMap<MeshVertexBufferLayout, PipelineId>>>,
+    /// Keys whose pipeline failed to be created, e.g. because the mesh lacks an attribute the
+    /// shader reads. They are retried once one of their shaders is rebuilt
+    failed_pipeline_keys: HashSet<PipelineKey>,
+    /// The layout pipelines are created with for each vertex layout, which decides the bind groups
+    /// a pipeline has access to
+    pub(crate) pipeline_layouts: HashMap<VertexLayout, wgpu::PipelineLayout>,
+    next_pipeline_id: PipelineId,
 }
 
 impl Pipelines {
-    pub fn get_pipeline_id_by_material(&self, material: Handle<Material>) -> Option<&PipelineId> {
-         self.material_to_pipeline_id_map.get(&material)
+    pub fn get_pipeline_id_by_material(
+        &self,
+        material: impl Into<AssetId<Material>>,
+        vertex_buffer_layout: &MeshVertexBufferLayout,
+        color_format: wgpu::TextureFormat,
+    ) -> Option<&PipelineId> {
+        self.material_to_pipeline_id_map.get(&material.into())?.get(&color_format)?.get(vertex_buffer_layout)
     }
-    pub fn get_pipeline_by_material(&self, material: Handle<Material>) -> Option<&wgpu::RenderPipeline> {
-        if let Some(pipeline_id) = self.material_to_pipeline_id_map.get(&material) {
-            return self.registered_pipelines.get(pipeline_id)
+
+    pub fn get_pipeline_by_material(
+        &self,
+        material: impl Into<AssetId<Material>>,
+        vertex_buffer_layout: &MeshVertexBufferLayout,
+        color_format: wgpu::TextureFormat,
+    ) -> Option<&wgpu::RenderPipeline> {
+        let pipeline_id = self.get_pipeline_id_by_material(material, vertex_buffer_layout, color_format)?;
+        self.registered_pipelines.get(pipeline_id)
+    }
+
+    /// Returns the pipeline for `key`, creating it if no pipeline with the same key exists yet.
+    /// Returns `None` if the key's shader modules have not been compiled, there is no pipeline
+    /// layout for its vertex layout or the pipeline failed to be created
+    pub fn get_or_create_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        key: &PipelineKey,
+        shaders_state: &ShadersState,
+    ) -> Option<PipelineId> {
+        if let Some(pipeline_id) = self.key_to_pipeline_id_map.get(key) {
+            return Some(*pipeline_id);
+        }
+        if self.failed_pipeline_keys.contains(key) {
+            return None;
+        }
+
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let render_pipeline = self.create_pipeline(device, key, shaders_state);
+        if let Some(error) = pollster::block_on(device.pop_error_scope()) {
+            error!("Unable to create render pipeline for key={:?}: {}", key, error);
+            self.failed_pipeline_keys.insert(key.clone());
+            return None;
+        }
+
+        let render_pipeline = render_pipeline?;
+        self.next_pipeline_id += 1;
+        let pipeline_id = self.next_pipeline_id;
+        log::info!("Created render pipeline | pipeline_id={} | key={:?}", pipeline_id, key);
+        self.registered_pipelines.insert(pipeline_id, render_pipeline);
+        self.key_to_pipeline_id_map.insert(key.clone(), pipeline_id);
+        Some(pipeline_id)
+    }
+
+    /// Recreates every pipeline that uses the shader, e.g. after its module was recompiled. The
+    /// pipeline ids stay the same so materials keep using them. A pipeline that fails to be
+    /// recreated is left as it was
+    pub fn rebuild_pipelines_using_shader(
+        &mut self,
+        device: &wgpu::Device,
+        shader_id: AssetId<Shader>,
+        shaders_state: &ShadersState,
+    ) {
+        let dependent_pipelines: Vec<(PipelineKey, PipelineId)> = self.key_to_pipeline_id_map.iter()
+            .filter(|(key, _)| key.vertex_shader.id() == shader_id || key.fragment_shader.id() == shader_id)
+            .map(|(key, pipeline_id)| (key.clone(), *pipeline_id))
+            .collect();
+
+        self.failed_pipeline_keys.retain(|key| key.vertex_shader.id() != shader_id && key.fragment_shader.id() != shader_id);
+        for (key, pipeline_id) in dependent_pipelines {
+            device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let render_pipeline = self.create_pipeline(device, &key, shaders_state);
+            if let Some(error) = pollster::block_on(device.pop_error_scope()) {
+                error!("Keeping previous render pipeline for pipeline_id={}, rebuilding it failed: {}", pipeline_id, error);
+                continue;
+            }
+
+            if let Some(render_pipeline) = render_pipeline {
+                log::info!("Rebuilt render pipeline | pipeline_id={} | key={:?}", pipeline_id, key);
+                self.registered_pipelines.insert(pipeline_id, render_pipeline);
+            }
         }
-        None
+    }
+
+    fn create_pipeline(
+        &self,
+        device: &wgpu::Device,
+        key: &PipelineKey,
+        shaders_state: &ShadersState,
+    ) -> Option<wgpu::RenderPipeline> {
+        let vertex_shader_module = shaders_state.loaded_shader_modules.get(&key.vertex_module_key())?;
+        let fragment_shader_module = shaders_state.loaded_shader_modules.get(&key.fragment_module_key())?;
+        let Some(pipeline_layout) = self.pipeline_layouts.get(&key.vertex_layout) else {
+            error!("Unable to create pipeline, no pipeline layout registered for {:?}", key.vertex_layout);
+            return None;
+        };
+
+        let targets = [Some(key.color_format.into())];
+        let vertex_buffers = [key.vertex_buffer_layout.vertex_buf_layout()];
+        let mut builder = Self::pipeline_builder(device)
+            .with_label("Material Render Pipeline")
+            .with_layout(pipeline_layout)
+            .with_vertex_shader(vertex_shader_module)
+            .with_fragment_shader(fragment_shader_module)
+            .with_vertex_entry_point("vertex_main")
+            .with_fragment_entry_point("fragment_main")
+            .with_vertex_buffers(&vertex_buffers)
+            .with_color_state_targets(&targets);
+        if let Some(blend) = key.blend {
+            builder = builder.with_blend_state(blend);
+        }
+        if let Some(cull_mode) = key.cull_mode {
+            builder = builder.with_cull_mode(cull_mode);
+        }
+        if key.depth_test {
+            builder = builder.with_depth_stencil(wgpu::DepthStencilState {
+                depth_write_enabled: key.depth_write,
+                ..DepthTexture::depth_stencil_state()
+            });
+        }
+
+        Some(builder.build())
     }
 
     pub fn pipeline_builder(device: &wgpu::Device) -> PipelineBuilder {
@@ -29,19 +212,33 @@ impl Pipelines {
             device,
             label: None,
             layout: None,
-            module: None,
+            vertex_module: None,
+            fragment_module: None,
             vertex_entry_point: None,
             fragment_entry_point: None,
             vertex_buffers: None,
             targets: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            cull_mode: None,
+            front_face: wgpu::FrontFace::Ccw,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            blend: None,
+            multisample_count: 1,
+            depth_stencil: None,
         }
     }
 
-    pub fn create_uniform<A: NoUninit>(device: &wgpu::Device, contents: &[A]) -> (wgpu::PipelineLayout, wgpu::Buffer, wgpu::BindGroup) {
+    /// Creates a uniform buffer bound at group 0 and a pipeline layout that uses it. Any
+    /// `additional_bind_group_layouts` are added to the pipeline layout as groups 1, 2, ...
+    pub fn create_uniform<A: NoUninit>(
+        device: &wgpu::Device,
+        contents: &[A],
+        additional_bind_group_layouts: &[&wgpu::BindGroupLayout],
+    ) -> (wgpu::PipelineLayout, wgpu::Buffer, wgpu::BindGroup) {
         let uniform_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Uniform,
                     has_dynamic_offset: false,
@@ -67,9 +264,11 @@ impl Pipelines {
             label: Some("Uniform Bind Group"),
         });
 
+        let mut bind_group_layouts = vec![&uniform_bind_group_layout];
+        bind_group_layouts.extend_from_slice(additional_bind_group_layouts);
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
-            bind_group_layouts: &[&uniform_bind_group_layout],
+            bind_group_layouts: &bind_group_layouts,
             push_constant_ranges: &[],
         });
 
@@ -77,15 +276,44 @@ impl Pipelines {
     }
 }
 
+/// Recompiles the shader modules of shaders whose files changed and rebuilds every pipeline that
+/// uses them. Shaders are only reloaded when the `file_watcher` feature is enabled
+pub fn reload_modified_shaders(
+    mut shader_events: EventReader<AssetEvent<Shader>>,
+    shader_assets: Res<Assets<Shader>>,
+    mut shaders_state: ResMut<ShadersState>,
+    mut pipelines: ResMut<Pipelines>,
+    renderer_state: Res<RendererState>,
+) {
+    let device = &renderer_state.device;
+    for event in shader_events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+
+        if shaders_state.reload_shader_module(device, *id, &shader_assets) {
+            pipelines.rebuild_pipelines_using_shader(device, *id, &shaders_state);
+        }
+    }
+}
+
 pub struct PipelineBuilder<'a> {
     device: &'a wgpu::Device,
     label: wgpu::Label<'a>,
     layout: Option<&'a wgpu::PipelineLayout>,
-    module: Option<&'a wgpu::ShaderModule>,
+    vertex_module: Option<&'a wgpu::ShaderModule>,
+    fragment_module: Option<&'a wgpu::ShaderModule>,
     vertex_entry_point: Option<&'a str>,
     fragment_entry_point: Option<&'a str>,
     vertex_buffers: Option<&'a [wgpu::VertexBufferLayout<'a>]>,
     targets: Option<&'a [Option<wgpu::ColorTargetState>]>,
+    topology: wgpu::PrimitiveTopology,
+    cull_mode: Option<wgpu::Face>,
+    front_face: wgpu::FrontFace,
+    polygon_mode: wgpu::PolygonMode,
+    blend: Option<wgpu::BlendState>,
+    multisample_count: u32,
+    depth_stencil: Option<wgpu::DepthStencilState>,
 }
 
 impl<'a> PipelineBuilder<'a> {
@@ -99,12 +327,14 @@ impl<'a> PipelineBuilder<'a> {
         self
     }
     pub fn with_vertex_shader(mut self, shader_module: &'a wgpu::ShaderModule) -> Self {
-        self.module = Some(shader_module);
+        self.vertex_module = Some(shader_module);
         self
     }
 
+    /// The module containing the fragment entry point. When this is not set the vertex shader
+    /// module is used for both stages
     pub fn with_fragment_shader(mut self, shader_module: &'a wgpu::ShaderModule) -> Self {
-        self.module = Some(shader_module);
+        self.fragment_module = Some(shader_module);
         self
     }
 
@@ -128,39 +358,101 @@ impl<'a> PipelineBuilder<'a> {
         self
     }
 
-    fn build_fragment_state(self) -> Option<wgpu::FragmentState<'a>> {
-        if self.module.is_some() || self.fragment_entry_point.is_some() || self.targets.is_some() {
+    /// Defaults to [`wgpu::PrimitiveTopology::TriangleList`]. Strip topologies use `Uint32` strip
+    /// indices to match the index buffers created for meshes
+    pub fn with_topology(mut self, topology: wgpu::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn with_cull_mode(mut self, cull_mode: wgpu::Face) -> Self {
+        self.cull_mode = Some(cull_mode);
+        self
+    }
+
+    /// Defaults to [`wgpu::FrontFace::Ccw`]
+    pub fn with_front_face(mut self, front_face: wgpu::FrontFace) -> Self {
+        self.front_face = front_face;
+        self
+    }
+
+    /// Defaults to [`wgpu::PolygonMode::Fill`]. Other modes require the matching device feature
+    pub fn with_polygon_mode(mut self, polygon_mode: wgpu::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    /// Overrides the blend state of every color target
+    pub fn with_blend_state(mut self, blend: wgpu::BlendState) -> Self {
+        self.blend = Some(blend);
+        self
+    }
+
+    /// Defaults to 1. The color and depth attachments the pipeline renders to must use the same
+    /// sample count
+    pub fn with_multisample_count(mut self, multisample_count: u32) -> Self {
+        self.multisample_count = multisample_count;
+        self
+    }
+
+    pub fn with_depth_stencil(mut self, depth_stencil: wgpu::DepthStencilState) -> Self {
+        self.depth_stencil = Some(depth_stencil);
+        self
+    }
+
+    fn build_color_targets(&self) -> Vec<Option<wgpu::ColorTargetState>> {
+        self.targets.unwrap_or(&[]).iter()
+            .map(|target| target.clone().map(|target| wgpu::ColorTargetState {
+                blend: self.blend.or(target.blend),
+                ..target
+            }))
+            .collect()
+    }
+
+    fn build_fragment_state<'b>(&self, targets: &'b [Option<wgpu::ColorTargetState>]) -> Option<wgpu::FragmentState<'b>>
+    where
+        'a: 'b,
+    {
+        if self.fragment_module.is_some() || self.fragment_entry_point.is_some() || self.targets.is_some() {
             return Some(wgpu::FragmentState {
-                module: self.module.unwrap_or_else(|| panic!("Shader module is required to create a Render Pipeline")),
+                module: self.fragment_module.or(self.vertex_module)
+                    .unwrap_or_else(|| panic!("Fragment shader module is required to create a Render Pipeline")),
                 entry_point: self.fragment_entry_point.unwrap_or_else(|| panic!("Fragment entry point is required to create a Render Pipeline")),
                 compilation_options: Default::default(),
-                targets: self.targets.unwrap_or(&[]),
+                targets,
             })
         }
 
         None
     }
+
     pub fn build(self) -> wgpu::RenderPipeline {
+        let targets = self.build_color_targets();
         self.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: self.label,
             layout: self.layout,
             vertex: wgpu::VertexState {
-                module: self.module.unwrap_or_else(|| panic!("Shader module is required to create a Render Pipeline")),
+                module: self.vertex_module.unwrap_or_else(|| panic!("Vertex shader module is required to create a Render Pipeline")),
                 entry_point:  self.vertex_entry_point.unwrap_or_else(|| panic!("Vertex entry point is required to create a Render Pipeline")),
                 compilation_options: Default::default(),
                 buffers: self.vertex_buffers.unwrap_or(&[]),
             },
             primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
+                topology: self.topology,
+                strip_index_format: self.topology.is_strip().then_some(wgpu::IndexFormat::Uint32),
+                front_face: self.front_face,
+                cull_mode: self.cull_mode,
+                polygon_mode: self.polygon_mode,
                 ..Default::default()
             },
-            depth_stencil: None,
-            multisample: Default::default(),
-            fragment: self.build_fragment_state(),
+            depth_stencil: self.depth_stencil.clone(),
+            multisample: wgpu::MultisampleState {
+                count: self.multisample_count,
+                ..Default::default()
+            },
+            fragment: self.build_fragment_state(&targets),
             multiview: None,
             cache: None,
         })
     }
 }
-
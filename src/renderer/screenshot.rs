@@ -0,0 +1,201 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use bevy::prelude::*;
+use thiserror::Error;
+use crate::renderer::RendererState;
+
+/// Send this event to capture the next frame that is rendered, after every render system has drawn
+/// into it. Works for both the window surface and the offscreen target of headless applications
+#[derive(Event)]
+pub struct Screenshot {
+    output: ScreenshotOutput,
+}
+
+enum ScreenshotOutput {
+    SaveToDisk(PathBuf),
+    Callback(Box<dyn FnOnce(ScreenshotImage) + Send + Sync>),
+}
+
+impl Screenshot {
+    /// Writes the frame to `path` as a PNG
+    pub fn save_to_disk(path: impl Into<PathBuf>) -> Self {
+        Self {
+            output: ScreenshotOutput::SaveToDisk(path.into()),
+        }
+    }
+
+    /// Hands the frame to `callback` once it has been read back from the GPU
+    pub fn with_callback(callback: impl FnOnce(ScreenshotImage) + Send + Sync + 'static) -> Self {
+        Self {
+            output: ScreenshotOutput::Callback(Box::new(callback)),
+        }
+    }
+}
+
+/// A captured frame as tightly packed 8 bit RGBA rows, top row first. The colors are sRGB encoded
+#[derive(Clone, Debug)]
+pub struct ScreenshotImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl ScreenshotImage {
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<(), ScreenshotError> {
+        ::image::save_buffer_with_format(
+            path,
+            &self.data,
+            self.width,
+            self.height,
+            ::image::ExtendedColorType::Rgba8,
+            ::image::ImageFormat::Png,
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ScreenshotError {
+    #[error("Frames in the {0:?} format can not be captured")]
+    UnsupportedFormat(wgpu::TextureFormat),
+    #[error("The render target can not be copied from")]
+    NotCopyable,
+    #[error("Unable to map the screenshot buffer: {0}")]
+    Map(#[from] wgpu::BufferAsyncError),
+    #[error("Unable to write the screenshot: {0}")]
+    Encode(#[from] ::image::ImageError),
+}
+
+/// Screenshots waiting for a frame to capture. Requests are moved out of their events so they are
+/// not dropped when frames are skipped, e.g. while the window is minimized
+#[derive(Resource, Default)]
+pub struct ScreenshotRequests(Vec<Screenshot>);
+
+impl ScreenshotRequests {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<Screenshot> {
+        std::mem::take(&mut self.0)
+    }
+}
+
+pub fn queue_screenshots(
+    mut screenshots: ResMut<Events<Screenshot>>,
+    mut requests: ResMut<ScreenshotRequests>,
+) {
+    requests.0.extend(screenshots.drain());
+}
+
+/// A copy of a frame into a buffer that can be mapped once the frame has been submitted
+pub(crate) struct ScreenshotCapture {
+    buffer: wgpu::Buffer,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    requests: Vec<Screenshot>,
+}
+
+impl ScreenshotCapture {
+    /// Records a copy of `texture` into a new buffer. Rows in the buffer are padded to
+    /// [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`], which [`ScreenshotCapture::finish`] strips again
+    pub(crate) fn encode(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        requests: Vec<Screenshot>,
+    ) -> Result<Self, ScreenshotError> {
+        let format = texture.format();
+        if !matches!(format, wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb
+            | wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb)
+        {
+            return Err(ScreenshotError::UnsupportedFormat(format));
+        }
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            return Err(ScreenshotError::NotCopyable);
+        }
+
+        let (width, height) = (texture.width(), texture.height());
+        let padded_bytes_per_row = (width * 4).div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Screenshot Buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+
+        Ok(Self {
+            buffer,
+            format,
+            width,
+            height,
+            padded_bytes_per_row,
+            requests,
+        })
+    }
+
+    /// Waits for the copy to complete and hands the frame to every request. Must be called after
+    /// the encoder the copy was recorded into has been submitted
+    pub(crate) fn finish(self, renderer_state: &RendererState) {
+        let image = match self.read(&renderer_state.device) {
+            Ok(image) => image,
+            Err(error) => {
+                log::error!("Unable to capture screenshot: {}", error);
+                return;
+            }
+        };
+
+        for request in self.requests {
+            match request.output {
+                ScreenshotOutput::SaveToDisk(path) => match image.save_png(&path) {
+                    Ok(()) => log::info!("Saved screenshot to {:?}", path),
+                    Err(error) => log::error!("Unable to save screenshot to {:?}: {}", path, error),
+                },
+                ScreenshotOutput::Callback(callback) => callback(image.clone()),
+            }
+        }
+    }
+
+    fn read(&self, device: &wgpu::Device) -> Result<ScreenshotImage, ScreenshotError> {
+        let slice = self.buffer.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv().expect("Screenshot buffer should be mapped after waiting for the device")?;
+
+        let bytes_per_row = (self.width * 4) as usize;
+        let mut data = Vec::with_capacity(bytes_per_row * self.height as usize);
+        for padded_row in slice.get_mapped_range().chunks_exact(self.padded_bytes_per_row as usize) {
+            data.extend_from_slice(&padded_row[..bytes_per_row]);
+        }
+        self.buffer.unmap();
+
+        if matches!(self.format, wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb) {
+            for pixel in data.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        Ok(ScreenshotImage {
+            width: self.width,
+            height: self.height,
+            data,
+        })
+    }
+}
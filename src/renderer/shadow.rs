@@ -0,0 +1,523 @@
+use std::num::NonZeroU64;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use crate::assets::shaders::preprocessor::ShaderSource;
+use crate::renderer::camera::Camera;
+use crate::renderer::frame::Frame;
+use crate::renderer::light::{MAX_DIRECTIONAL_LIGHTS, MAX_SPOT_LIGHTS};
+use crate::renderer::mesh::{GpuMeshes, Mesh};
+use crate::renderer::model::{ModelUniforms, MODEL_BIND_GROUP_INDEX};
+use crate::renderer::pipeline::Pipelines;
+use crate::renderer::texture::{DepthTexture, DEPTH_FORMAT};
+use crate::renderer::vertex::{MeshVertexAttribute, MeshVertexBufferLayout};
+use crate::renderer::RendererState;
+use crate::transform::GlobalTransform;
+
+/// The most cascades a directional light's shadow can be split into. Every camera has its own
+/// cascades, and every cascade of every shadowed directional light for every camera is a layer of
+/// one texture array sized for the largest resolution, so 4 lights with 4 cascades at 2048x2048
+/// allocate 16 layers of 16MB per camera, 256MB for a single camera
+pub const MAX_CASCADES: usize = 4;
+/// Number of shadow views the view buffer holds before it has to grow, enough for a single camera
+const INITIAL_SHADOW_VIEW_CAPACITY: usize = MAX_DIRECTIONAL_LIGHTS * MAX_CASCADES + MAX_SPOT_LIGHTS;
+/// Distance of a spot light's shadow near plane from the light
+const SPOT_SHADOW_NEAR: f32 = 0.1;
+/// How far a cascade extends towards the light past the part of the camera frustum it covers, as a
+/// multiple of the cascade's radius, so objects outside the view can still cast shadows into it
+const CASCADE_CASTER_EXTENSION: f32 = 3.0;
+
+/// Enables shadows for a [`DirectionalLight`](crate::renderer::light::DirectionalLight) or
+/// [`SpotLight`](crate::renderer::light::SpotLight)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowSettings {
+    /// Width and height of the light's shadow map, or of each cascade of a directional light. It is
+    /// clamped to the device's maximum texture size. Every shadow map of a kind of light is sized
+    /// for the largest resolution of that kind, at 4 bytes per texel
+    pub resolution: u32,
+    /// Subtracted from a fragment's depth in the light's clip space before it is compared against
+    /// the shadow map. Raise it if surfaces shadow themselves (shadow acne)
+    pub depth_bias: f32,
+    /// World space distance a fragment is moved along its normal before it is projected into the
+    /// shadow map. Raise it for acne on surfaces at grazing angles to the light
+    pub normal_bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            depth_bias: 0.0005,
+            normal_bias: 0.05,
+        }
+    }
+}
+
+/// How a directional light's shadow is split over the camera frustum. Each cascade covers a slice
+/// of the frustum with its own shadow map, so shadows close to the camera get more texels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CascadeSettings {
+    /// Number of cascades, at most [`MAX_CASCADES`]
+    pub count: u32,
+    /// Distance from the camera past which nothing receives shadows
+    pub max_distance: f32,
+    /// Blends between evenly spaced splits at 0.0 and logarithmically spaced splits at 1.0
+    pub split_lambda: f32,
+}
+
+impl Default for CascadeSettings {
+    fn default() -> Self {
+        Self {
+            count: 4,
+            max_distance: 50.0,
+            split_lambda: 0.75,
+        }
+    }
+}
+
+/// Meshes with this component are not drawn into shadow maps
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct NotShadowCaster;
+
+/// Far distance from the camera of each cascade, starting at the camera's near plane
+pub fn cascade_splits(near: f32, far: f32, cascades: &CascadeSettings) -> Vec<f32> {
+    let count = cascades.count.clamp(1, MAX_CASCADES as u32);
+    (1..=count)
+        .map(|index| {
+            let fraction = index as f32 / count as f32;
+            let logarithmic_split = near * (far / near).powf(fraction);
+            let uniform_split = near + (far - near) * fraction;
+            cascades.split_lambda * logarithmic_split + (1.0 - cascades.split_lambda) * uniform_split
+        })
+        .collect()
+}
+
+/// The view-projection of a directional light shining towards `-to_light` that covers the slice of
+/// the camera frustum between `near` and `far`. The projection is fit around the bounding sphere
+/// of the slice and snapped to the shadow map's texels, so the shadow does not shimmer when the
+/// camera moves or rotates
+pub fn cascade_view_projection(
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+    aspect_ratio: f32,
+    near: f32,
+    far: f32,
+    to_light: Vec3,
+    resolution: u32,
+) -> Mat4 {
+    let tan_half_fov_y = (camera.fov_y / 2.0).tan();
+    let tan_half_fov_x = tan_half_fov_y * aspect_ratio;
+    let corners: Vec<Vec3> = [near, far].into_iter()
+        .flat_map(|distance| [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].map(|(x, y)| {
+            let corner = Vec3::new(x * tan_half_fov_x * distance, y * tan_half_fov_y * distance, -distance);
+            camera_transform.matrix().transform_point3(corner)
+        }))
+        .collect();
+    let center = corners.iter().sum::<Vec3>() / corners.len() as f32;
+    let radius = corners.iter().map(|corner| corner.distance(center)).fold(0.0, f32::max);
+    // Rounded so the size of the projection does not change as the camera rotates
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let up = if to_light.normalize().y.abs() > 0.99 { Vec3::Z } else { Vec3::Y };
+    let view = Mat4::look_at_rh(Vec3::ZERO, -to_light, up);
+    let texel_size = 2.0 * radius / resolution as f32;
+    let light_space_center = view.transform_point3(center);
+    let snapped_x = (light_space_center.x / texel_size).floor() * texel_size;
+    let snapped_y = (light_space_center.y / texel_size).floor() * texel_size;
+    let center_distance = -light_space_center.z;
+    let projection = Mat4::orthographic_rh(
+        snapped_x - radius,
+        snapped_x + radius,
+        snapped_y - radius,
+        snapped_y + radius,
+        center_distance - radius * (1.0 + CASCADE_CASTER_EXTENSION),
+        center_distance + radius,
+    );
+
+    projection * view
+}
+
+/// The view-projection of a spot light, covering its cone out to its range
+pub fn spot_light_view_projection(global_transform: &GlobalTransform, outer_angle: f32, range: f32) -> Mat4 {
+    let position = global_transform.translation();
+    let direction = global_transform.forward();
+    let up = if direction.y.abs() > 0.99 { Vec3::Z } else { Vec3::Y };
+    let view = Mat4::look_at_rh(position, position + direction, up);
+    let projection = Mat4::perspective_rh(2.0 * outer_angle, 1.0, SPOT_SHADOW_NEAR, range.max(SPOT_SHADOW_NEAR * 2.0));
+    projection * view
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShadowMapKind {
+    Directional,
+    Spot,
+}
+
+/// A depth texture array with a layer for each shadow map of one kind of light. Lights with a
+/// lower resolution than the array only render into the top left corner of their layer
+struct ShadowMapArray {
+    view: wgpu::TextureView,
+    layer_views: Vec<wgpu::TextureView>,
+    size: u32,
+}
+
+impl ShadowMapArray {
+    fn new(device: &wgpu::Device, label: &str, size: u32, layer_count: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: layer_count,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layer_views = (0..layer_count)
+            .map(|layer| texture.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            }))
+            .collect();
+
+        Self {
+            view,
+            layer_views,
+            size,
+        }
+    }
+}
+
+/// The shadow maps of every directional light cascade and spot light along with the comparison
+/// sampler they are sampled with. They are bound with the lights at
+/// [`LIGHTS_BIND_GROUP_INDEX`](crate::renderer::light::LIGHTS_BIND_GROUP_INDEX)
+#[derive(Resource)]
+pub struct ShadowMaps {
+    directional: ShadowMapArray,
+    spot: ShadowMapArray,
+    pub(crate) sampler: wgpu::Sampler,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Map Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        Self {
+            directional: ShadowMapArray::new(device, "Directional Shadow Maps", 1, 1),
+            spot: ShadowMapArray::new(device, "Spot Shadow Maps", 1, 1),
+            sampler,
+        }
+    }
+
+    fn array(&self, kind: ShadowMapKind) -> &ShadowMapArray {
+        match kind {
+            ShadowMapKind::Directional => &self.directional,
+            ShadowMapKind::Spot => &self.spot,
+        }
+    }
+
+    pub(crate) fn view(&self, kind: ShadowMapKind) -> &wgpu::TextureView {
+        &self.array(kind).view
+    }
+
+    pub(crate) fn size(&self, kind: ShadowMapKind) -> u32 {
+        self.array(kind).size
+    }
+
+    /// Recreates the array of `kind` when its size differs from `size` or it has fewer than
+    /// `layer_count` layers. Returns whether it was recreated, in which case bind groups that use
+    /// it must be recreated too
+    pub(crate) fn prepare(&mut self, device: &wgpu::Device, kind: ShadowMapKind, size: u32, layer_count: u32) -> bool {
+        let size = size.max(1);
+        let layer_count = layer_count.max(1);
+        let array = self.array(kind);
+        if array.size == size && array.layer_views.len() as u32 >= layer_count {
+            return false;
+        }
+
+        log::debug!("Creating {:?} shadow maps | size={} | layers={}", kind, size, layer_count);
+        let (label, array) = match kind {
+            ShadowMapKind::Directional => ("Directional Shadow Maps", &mut self.directional),
+            ShadowMapKind::Spot => ("Spot Shadow Maps", &mut self.spot),
+        };
+        *array = ShadowMapArray::new(device, label, size, layer_count);
+        true
+    }
+}
+
+/// A single shadow map to render this frame
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ShadowPass {
+    kind: ShadowMapKind,
+    layer: u32,
+    resolution: u32,
+    /// Dynamic offset of the pass's view-projection in the shadow view buffer
+    view_offset: u32,
+}
+
+/// Everything needed to render the shadow maps: the view-projection of every shadow pass in a
+/// single uniform buffer bound with a dynamic offset, and a depth only pipeline for every vertex
+/// buffer layout that casts shadows
+#[derive(Resource)]
+pub struct ShadowPasses {
+    view_bind_group_layout: wgpu::BindGroupLayout,
+    view_buffer: wgpu::Buffer,
+    view_bind_group: wgpu::BindGroup,
+    /// Number of view-projections the buffer can currently hold
+    capacity: usize,
+    /// Distance in bytes between two view-projections in the buffer, padded to the device's minimum
+    /// uniform buffer offset alignment
+    stride: usize,
+    staging: Vec<u8>,
+    passes: Vec<ShadowPass>,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader_module: wgpu::ShaderModule,
+    /// Pipelines by the array stride and position offset of the vertex buffer layout they read
+    pipelines: HashMap<(wgpu::BufferAddress, wgpu::BufferAddress), wgpu::RenderPipeline>,
+}
+
+impl ShadowPasses {
+    pub fn new(device: &wgpu::Device, model_bind_group_layout: &wgpu::BindGroupLayout) -> Self {
+        let view_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Self::binding_size(),
+                },
+                count: None,
+            }],
+            label: Some("Shadow View Bind Group Layout"),
+        });
+
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as usize;
+        let stride = size_of::<Mat4>().div_ceil(alignment) * alignment;
+        let (view_buffer, view_bind_group) = Self::create_view_buffer(device, &view_bind_group_layout, INITIAL_SHADOW_VIEW_CAPACITY, stride);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[&view_bind_group_layout, model_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let composed = ShaderSource::from_builtin("fathom::shadow")
+            .expect("Built-in shadow shader should only import built-in modules")
+            .compose(&[])
+            .expect("Built-in shadow shader should be valid");
+        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("fathom::shadow"),
+            source: wgpu::ShaderSource::Wgsl(composed.source.into()),
+        });
+
+        Self {
+            view_bind_group_layout,
+            view_buffer,
+            view_bind_group,
+            capacity: INITIAL_SHADOW_VIEW_CAPACITY,
+            stride,
+            staging: Vec::new(),
+            passes: Vec::new(),
+            pipeline_layout,
+            shader_module,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    fn binding_size() -> Option<NonZeroU64> {
+        NonZeroU64::new(size_of::<Mat4>() as u64)
+    }
+
+    fn create_view_buffer(
+        device: &wgpu::Device,
+        view_bind_group_layout: &wgpu::BindGroupLayout,
+        capacity: usize,
+        stride: usize,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let view_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow View Buffer"),
+            size: (capacity * stride) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let view_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: view_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &view_buffer,
+                    offset: 0,
+                    size: Self::binding_size(),
+                }),
+            }],
+            label: Some("Shadow View Bind Group"),
+        });
+
+        (view_buffer, view_bind_group)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.passes.clear();
+        self.staging.clear();
+    }
+
+    /// Adds a pass rendering into `layer` of the shadow maps of `kind`
+    pub(crate) fn push(&mut self, kind: ShadowMapKind, layer: u32, resolution: u32, view_projection: Mat4) {
+        let view_offset = self.staging.len();
+        self.staging.extend_from_slice(bytemuck::bytes_of(&view_projection.to_cols_array_2d()));
+        self.staging.resize(view_offset + self.stride, 0);
+        self.passes.push(ShadowPass {
+            kind,
+            layer,
+            resolution,
+            view_offset: view_offset as u32,
+        });
+    }
+
+    /// Uploads the view-projection of every pass, growing the view buffer if it can not hold them
+    pub(crate) fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.passes.len() > self.capacity {
+            let capacity = self.passes.len().next_power_of_two();
+            log::debug!("Growing shadow view buffer from {} to {} views", self.capacity, capacity);
+            let (view_buffer, view_bind_group) = Self::create_view_buffer(device, &self.view_bind_group_layout, capacity, self.stride);
+            self.view_buffer = view_buffer;
+            self.view_bind_group = view_bind_group;
+            self.capacity = capacity;
+        }
+        if !self.staging.is_empty() {
+            queue.write_buffer(&self.view_buffer, 0, &self.staging);
+        }
+    }
+
+    fn pipeline_key(vertex_buffer_layout: &MeshVertexBufferLayout) -> Option<(wgpu::BufferAddress, wgpu::BufferAddress)> {
+        vertex_buffer_layout.attributes.iter()
+            .find(|attribute| attribute.shader_location == MeshVertexAttribute::POSITION.shader_location)
+            .map(|attribute| (vertex_buffer_layout.array_stride, attribute.offset))
+    }
+
+    fn create_pipeline(&self, device: &wgpu::Device, (array_stride, position_offset): (wgpu::BufferAddress, wgpu::BufferAddress)) -> wgpu::RenderPipeline {
+        let attributes = [wgpu::VertexAttribute {
+            format: MeshVertexAttribute::POSITION.format,
+            offset: position_offset,
+            shader_location: MeshVertexAttribute::POSITION.shader_location,
+        }];
+        let vertex_buffers = [wgpu::VertexBufferLayout {
+            array_stride,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &attributes,
+        }];
+
+        Pipelines::pipeline_builder(device)
+            .with_label("Shadow Render Pipeline")
+            .with_layout(&self.pipeline_layout)
+            .with_vertex_shader(&self.shader_module)
+            .with_vertex_entry_point("vertex_main")
+            .with_vertex_buffers(&vertex_buffers)
+            .with_depth_stencil(wgpu::DepthStencilState {
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                ..DepthTexture::depth_stencil_state()
+            })
+            .build()
+    }
+}
+
+/// Creates the shadow pipeline for the vertex buffer layout of every shadow casting mesh that does
+/// not have one yet. Meshes without positions do not cast shadows
+pub fn prepare_shadow_pipelines(
+    meshes: Query<&Mesh, Without<NotShadowCaster>>,
+    mut shadow_passes: ResMut<ShadowPasses>,
+    renderer_state: Res<RendererState>,
+) {
+    for mesh in &meshes {
+        let Some(key) = ShadowPasses::pipeline_key(mesh.vertex_buffer_layout()) else {
+            continue;
+        };
+        if !shadow_passes.pipelines.contains_key(&key) {
+            log::debug!("Creating shadow pipeline | array_stride={} | position_offset={}", key.0, key.1);
+            let pipeline = shadow_passes.create_pipeline(&renderer_state.device, key);
+            shadow_passes.pipelines.insert(key, pipeline);
+        }
+    }
+}
+
+/// Renders every shadow casting mesh into the shadow map of each shadow pass prepared by
+/// [`prepare_lights`](crate::renderer::light::prepare_lights). Runs before the passes that sample
+/// the shadow maps
+pub fn render_shadow_maps(
+    meshes: Query<(Entity, &Mesh), Without<NotShadowCaster>>,
+    shadow_passes: Res<ShadowPasses>,
+    shadow_maps: Res<ShadowMaps>,
+    model_uniforms: Res<ModelUniforms>,
+    gpu_meshes: Res<GpuMeshes>,
+    frame_opt: Option<ResMut<Frame>>,
+) {
+    let Some(mut frame) = frame_opt else {
+        return;
+    };
+
+    for shadow_pass in &shadow_passes.passes {
+        let Some(layer_view) = shadow_maps.array(shadow_pass.kind).layer_views.get(shadow_pass.layer as usize) else {
+            continue;
+        };
+        let mut render_pass = frame.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow render pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: layer_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        let resolution = shadow_pass.resolution as f32;
+        render_pass.set_viewport(0.0, 0.0, resolution, resolution, 0.0, 1.0);
+        render_pass.set_bind_group(0, &shadow_passes.view_bind_group, &[shadow_pass.view_offset]);
+
+        for (entity, mesh) in &meshes {
+            let Some(pipeline) = ShadowPasses::pipeline_key(mesh.vertex_buffer_layout())
+                .and_then(|key| shadow_passes.pipelines.get(&key)) else {
+                continue;
+            };
+            let Some((model_bind_group, model_offset)) = model_uniforms.bind_group(entity) else {
+                continue;
+            };
+            let Some(vertex_buffer) = gpu_meshes.buffers_map.get(&mesh.vertex_buffer_id) else {
+                continue;
+            };
+
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(MODEL_BIND_GROUP_INDEX, model_bind_group, &[model_offset]);
+            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            if let Some(Some(index_buffer)) = &mesh.has_indices().then(|| gpu_meshes.buffers_map.get(&mesh.index_buffer_id)) {
+                render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.num_indices() as u32, 0, 0..1);
+            } else {
+                render_pass.draw(0..mesh.num_vertices() as u32, 0..1);
+            }
+        }
+    }
+}
@@ -1,3 +1,300 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::renderer::RendererState;
+
+/// Pixel data for a 2D texture, usually loaded from a PNG, JPEG or HDR file. A GPU texture is
+/// created for every loaded image by [`prepare_images`]
+#[derive(Asset, TypePath, Clone, Debug)]
 pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+    pub mip_level_count: u32,
+    /// Every mip level, largest first, with tightly packed rows
+    pub data: Vec<u8>,
+    pub sampler: ImageSampler,
+    /// How the GPU texture can be used. Images that cameras render to also need
+    /// `RENDER_ATTACHMENT`, see [`Image::render_target`]
+    pub usage: wgpu::TextureUsages,
+}
+
+impl Image {
+    /// An image with a single mip level
+    pub fn new(width: u32, height: u32, format: wgpu::TextureFormat, data: Vec<u8>) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            mip_level_count: 1,
+            data,
+            sampler: ImageSampler::default(),
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        }
+    }
+
+    /// A cleared image a [`Camera`](crate::renderer::camera::Camera) can render to and materials can
+    /// sample. It can also be copied from, e.g. to capture it
+    pub fn render_target(width: u32, height: u32, format: wgpu::TextureFormat) -> Self {
+        let bytes_per_pixel = format.block_copy_size(None)
+            .expect("Render target format must have a block size");
+        Self {
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            ..Self::new(width, height, format, vec![0; (width * height * bytes_per_pixel) as usize])
+        }.with_sampler(ImageSampler::default().with_address_mode(ImageAddressMode::ClampToEdge))
+    }
+
+    /// A 1x1 sRGB image
+    pub fn solid_color(rgba: [u8; 4]) -> Self {
+        Self::new(1, 1, wgpu::TextureFormat::Rgba8UnormSrgb, rgba.to_vec())
+    }
+
+    pub fn with_sampler(mut self, sampler: ImageSampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    fn size(&self) -> wgpu::Extent3d {
+        wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: 1,
+        }
+    }
+
+    /// Creates the texture, uploads every mip level and creates the sampler
+    pub fn create_gpu_image(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> GpuImage {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Image Texture"),
+            size: self.size(),
+            mip_level_count: self.mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: self.usage,
+            view_formats: &[],
+        });
+
+        let bytes_per_pixel = self.format.block_copy_size(None)
+            .expect("Image format must have a block size");
+        let mut offset = 0;
+        for mip_level in 0..self.mip_level_count {
+            let mip_size = self.size().mip_level_size(mip_level, wgpu::TextureDimension::D2);
+            let mip_length = (mip_size.width * mip_size.height * bytes_per_pixel) as usize;
+            let Some(mip_data) = self.data.get(offset..offset + mip_length) else {
+                error!("Image data is too short for mip_level={} of a {}x{} image", mip_level, self.width, self.height);
+                break;
+            };
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                mip_data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(mip_size.width * bytes_per_pixel),
+                    rows_per_image: Some(mip_size.height),
+                },
+                mip_size,
+            );
+            offset += mip_length;
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&self.sampler.descriptor());
+        GpuImage {
+            texture,
+            view,
+            sampler,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ImageFilterMode {
+    Nearest,
+    #[default]
+    Linear,
+}
+
+impl From<ImageFilterMode> for wgpu::FilterMode {
+    fn from(filter_mode: ImageFilterMode) -> Self {
+        match filter_mode {
+            ImageFilterMode::Nearest => wgpu::FilterMode::Nearest,
+            ImageFilterMode::Linear => wgpu::FilterMode::Linear,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ImageAddressMode {
+    ClampToEdge,
+    #[default]
+    Repeat,
+    MirrorRepeat,
+}
+
+impl From<ImageAddressMode> for wgpu::AddressMode {
+    fn from(address_mode: ImageAddressMode) -> Self {
+        match address_mode {
+            ImageAddressMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+            ImageAddressMode::Repeat => wgpu::AddressMode::Repeat,
+            ImageAddressMode::MirrorRepeat => wgpu::AddressMode::MirrorRepeat,
+        }
+    }
+}
+
+/// How an image is sampled. Defaults to trilinear filtering with repeating texture coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ImageSampler {
+    pub mag_filter: ImageFilterMode,
+    pub min_filter: ImageFilterMode,
+    pub mipmap_filter: ImageFilterMode,
+    pub address_mode_u: ImageAddressMode,
+    pub address_mode_v: ImageAddressMode,
+    pub address_mode_w: ImageAddressMode,
+    /// Values above 1 enable anisotropic filtering, which requires every filter to be linear
+    pub anisotropy_clamp: u16,
+}
+
+impl Default for ImageSampler {
+    fn default() -> Self {
+        Self {
+            mag_filter: ImageFilterMode::Linear,
+            min_filter: ImageFilterMode::Linear,
+            mipmap_filter: ImageFilterMode::Linear,
+            address_mode_u: ImageAddressMode::Repeat,
+            address_mode_v: ImageAddressMode::Repeat,
+            address_mode_w: ImageAddressMode::Repeat,
+            anisotropy_clamp: 1,
+        }
+    }
+}
+
+impl ImageSampler {
+    /// Nearest filtering everywhere, for pixel art
+    pub fn nearest() -> Self {
+        Self {
+            mag_filter: ImageFilterMode::Nearest,
+            min_filter: ImageFilterMode::Nearest,
+            mipmap_filter: ImageFilterMode::Nearest,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_address_mode(mut self, address_mode: ImageAddressMode) -> Self {
+        self.address_mode_u = address_mode;
+        self.address_mode_v = address_mode;
+        self.address_mode_w = address_mode;
+        self
+    }
+
+    pub fn descriptor(&self) -> wgpu::SamplerDescriptor<'static> {
+        wgpu::SamplerDescriptor {
+            label: Some("Image Sampler"),
+            address_mode_u: self.address_mode_u.into(),
+            address_mode_v: self.address_mode_v.into(),
+            address_mode_w: self.address_mode_w.into(),
+            mag_filter: self.mag_filter.into(),
+            min_filter: self.min_filter.into(),
+            mipmap_filter: self.mipmap_filter.into(),
+            anisotropy_clamp: self.anisotropy_clamp.max(1),
+            ..Default::default()
+        }
+    }
+}
+
+pub struct GpuImage {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+}
+
+/// The GPU textures of every loaded [`Image`]
+#[derive(Resource, Default)]
+pub struct GpuImages {
+    images: HashMap<AssetId<Image>, GpuImage>,
+}
+
+impl GpuImages {
+    pub fn get(&self, image: impl Into<AssetId<Image>>) -> Option<&GpuImage> {
+        self.images.get(&image.into())
+    }
+}
+
+/// Uploads images that were loaded or modified since the last frame and drops the textures of
+/// removed images
+pub fn prepare_images(
+    mut image_events: EventReader<AssetEvent<Image>>,
+    images: Res<Assets<Image>>,
+    mut gpu_images: ResMut<GpuImages>,
+    renderer_state: Res<RendererState>,
+) {
+    for event in image_events.read() {
+        match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                if let Some(image) = images.get(*id) {
+                    log::debug!("Uploading image={:?} | size={}x{} | mip_levels={}", id, image.width, image.height, image.mip_level_count);
+                    let gpu_image = image.create_gpu_image(&renderer_state.device, &renderer_state.queue);
+                    gpu_images.images.insert(*id, gpu_image);
+                }
+            }
+            AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+                gpu_images.images.remove(id);
+            }
+            AssetEvent::LoadedWithDependencies { .. } => {}
+        }
+    }
+}
+
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// The depth buffer used by 3D render passes. It must always match the size of the surface it is
+/// rendered alongside, so it is recreated whenever the surface is resized
+pub struct DepthTexture {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+}
+
+impl DepthTexture {
+    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Depth Texture"),
+            size: wgpu::Extent3d {
+                width: width.max(1),
+                height: height.max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self {
+            texture,
+            view,
+        }
+    }
 
-}
\ No newline at end of file
+    /// The depth state used by pipelines that render into a [`DepthTexture`]. Fragments closer to
+    /// the camera than what has already been drawn pass the depth test
+    pub fn depth_stencil_state() -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }
+    }
+}
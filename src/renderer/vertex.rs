@@ -1,4 +1,5 @@
 use bytemuck::{Pod, Zeroable};
+use crate::assets::shaders::preprocessor::ShaderDef;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -42,4 +43,180 @@ impl Vertex2D {
             attributes: &Self::ATTRIBUTES,
         }
     }
-}
\ No newline at end of file
+
+    pub fn mesh_vertex_buffer_layout() -> MeshVertexBufferLayout {
+        MeshVertexBufferLayout {
+            array_stride: size_of::<Vertex2D>() as wgpu::BufferAddress,
+            attributes: Self::ATTRIBUTES.to_vec(),
+            attribute_names: vec![MeshVertexAttribute::POSITION.name, MeshVertexAttribute::COLOR.name],
+        }
+    }
+}
+
+/// A named per-vertex attribute of a [`Mesh`](crate::renderer::mesh::Mesh), read by shaders from
+/// `@location(shader_location)`. Meshes with the attribute are drawn with the `VERTEX_<name>`
+/// shader def, e.g. `VERTEX_UV_0`. Custom attributes should use locations after the built-in ones
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MeshVertexAttribute {
+    pub name: &'static str,
+    pub shader_location: u32,
+    pub format: wgpu::VertexFormat,
+}
+
+impl MeshVertexAttribute {
+    pub const POSITION: Self = Self::new("POSITION", 0, wgpu::VertexFormat::Float32x3);
+    pub const COLOR: Self = Self::new("COLOR", 1, wgpu::VertexFormat::Float32x3);
+    pub const UV_0: Self = Self::new("UV_0", 2, wgpu::VertexFormat::Float32x2);
+    pub const NORMAL: Self = Self::new("NORMAL", 3, wgpu::VertexFormat::Float32x3);
+    /// xyz is the tangent and w the handedness of the bitangent
+    pub const TANGENT: Self = Self::new("TANGENT", 4, wgpu::VertexFormat::Float32x4);
+    pub const COLOR_1: Self = Self::new("COLOR_1", 5, wgpu::VertexFormat::Float32x4);
+    pub const UV_1: Self = Self::new("UV_1", 6, wgpu::VertexFormat::Float32x2);
+
+    pub const fn new(name: &'static str, shader_location: u32, format: wgpu::VertexFormat) -> Self {
+        Self {
+            name,
+            shader_location,
+            format,
+        }
+    }
+}
+
+/// The values of a [`MeshVertexAttribute`], one per vertex
+#[derive(Clone, Debug)]
+pub enum VertexAttributeValues {
+    Float32(Vec<f32>),
+    Float32x2(Vec<[f32; 2]>),
+    Float32x3(Vec<[f32; 3]>),
+    Float32x4(Vec<[f32; 4]>),
+    Uint32(Vec<u32>),
+    Uint32x4(Vec<[u32; 4]>),
+}
+
+impl VertexAttributeValues {
+    pub fn len(&self) -> usize {
+        match self {
+            VertexAttributeValues::Float32(values) => values.len(),
+            VertexAttributeValues::Float32x2(values) => values.len(),
+            VertexAttributeValues::Float32x3(values) => values.len(),
+            VertexAttributeValues::Float32x4(values) => values.len(),
+            VertexAttributeValues::Uint32(values) => values.len(),
+            VertexAttributeValues::Uint32x4(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn format(&self) -> wgpu::VertexFormat {
+        match self {
+            VertexAttributeValues::Float32(_) => wgpu::VertexFormat::Float32,
+            VertexAttributeValues::Float32x2(_) => wgpu::VertexFormat::Float32x2,
+            VertexAttributeValues::Float32x3(_) => wgpu::VertexFormat::Float32x3,
+            VertexAttributeValues::Float32x4(_) => wgpu::VertexFormat::Float32x4,
+            VertexAttributeValues::Uint32(_) => wgpu::VertexFormat::Uint32,
+            VertexAttributeValues::Uint32x4(_) => wgpu::VertexFormat::Uint32x4,
+        }
+    }
+
+    /// The bytes of the value of a single vertex
+    pub fn vertex_bytes(&self, index: usize) -> &[u8] {
+        match self {
+            VertexAttributeValues::Float32(values) => bytemuck::bytes_of(&values[index]),
+            VertexAttributeValues::Float32x2(values) => bytemuck::bytes_of(&values[index]),
+            VertexAttributeValues::Float32x3(values) => bytemuck::bytes_of(&values[index]),
+            VertexAttributeValues::Float32x4(values) => bytemuck::bytes_of(&values[index]),
+            VertexAttributeValues::Uint32(values) => bytemuck::bytes_of(&values[index]),
+            VertexAttributeValues::Uint32x4(values) => bytemuck::bytes_of(&values[index]),
+        }
+    }
+}
+
+impl From<Vec<f32>> for VertexAttributeValues {
+    fn from(values: Vec<f32>) -> Self {
+        VertexAttributeValues::Float32(values)
+    }
+}
+
+impl From<Vec<[f32; 2]>> for VertexAttributeValues {
+    fn from(values: Vec<[f32; 2]>) -> Self {
+        VertexAttributeValues::Float32x2(values)
+    }
+}
+
+impl From<Vec<[f32; 3]>> for VertexAttributeValues {
+    fn from(values: Vec<[f32; 3]>) -> Self {
+        VertexAttributeValues::Float32x3(values)
+    }
+}
+
+impl From<Vec<[f32; 4]>> for VertexAttributeValues {
+    fn from(values: Vec<[f32; 4]>) -> Self {
+        VertexAttributeValues::Float32x4(values)
+    }
+}
+
+impl From<Vec<u32>> for VertexAttributeValues {
+    fn from(values: Vec<u32>) -> Self {
+        VertexAttributeValues::Uint32(values)
+    }
+}
+
+impl From<Vec<[u32; 4]>> for VertexAttributeValues {
+    fn from(values: Vec<[u32; 4]>) -> Self {
+        VertexAttributeValues::Uint32x4(values)
+    }
+}
+
+/// The interleaved vertex buffer layout of a mesh, derived from the attributes it has. Pipelines
+/// are created for each layout a material is drawn with
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MeshVertexBufferLayout {
+    pub array_stride: wgpu::BufferAddress,
+    pub attributes: Vec<wgpu::VertexAttribute>,
+    /// Name of the [`MeshVertexAttribute`] of each entry in `attributes`
+    pub attribute_names: Vec<&'static str>,
+}
+
+impl MeshVertexBufferLayout {
+    /// Lays the attributes out one after the other in the order they are given
+    pub fn from_attributes<'a>(attributes: impl IntoIterator<Item = &'a MeshVertexAttribute>) -> Self {
+        let mut layout = Self::default();
+        for attribute in attributes {
+            layout.attributes.push(wgpu::VertexAttribute {
+                format: attribute.format,
+                offset: layout.array_stride,
+                shader_location: attribute.shader_location,
+            });
+            layout.attribute_names.push(attribute.name);
+            layout.array_stride += attribute.format.size();
+        }
+        layout
+    }
+
+    pub fn vertex_buf_layout(&self) -> wgpu::VertexBufferLayout<'_> {
+        wgpu::VertexBufferLayout {
+            array_stride: self.array_stride,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &self.attributes,
+        }
+    }
+
+    /// A `VERTEX_<NAME>` shader def for every attribute in the layout, which shaders can use to
+    /// only read the attributes a mesh has
+    pub fn shader_defs(&self) -> impl Iterator<Item = ShaderDef> + '_ {
+        self.attribute_names.iter().map(|name| ShaderDef::new(format!("VERTEX_{}", name)))
+    }
+}
+
+/// The kind of mesh a material draws, which decides the pipeline layout and so the bind groups its
+/// pipelines have access to. The vertex buffer layout itself is derived from each mesh
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum VertexLayout {
+    /// 3D [`Mesh`](crate::renderer::mesh::Mesh)es with any [`MeshVertexAttribute`]s
+    #[default]
+    Vertex3D,
+    /// [`Mesh2D`](crate::renderer::mesh::Mesh2D)es made of [`Vertex2D`]
+    Vertex2D,
+}
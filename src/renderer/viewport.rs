@@ -0,0 +1,143 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use crate::assets::shaders::preprocessor::ShaderSource;
+use crate::renderer::pipeline::Pipelines;
+use crate::renderer::texture::DepthTexture;
+
+/// The part of its target a [`Camera`](crate::renderer::camera::Camera) draws into, in fractions
+/// of the target's size with the origin at the top left, so it follows the target when it is
+/// resized. `Viewport::new(0.0, 0.0, 0.5, 1.0)` is the left half of the target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Viewport {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The viewport in pixels of a `target_width` by `target_height` target, rounded to whole
+    /// pixels and clamped to the target
+    pub fn to_pixels(&self, target_width: u32, target_height: u32) -> Rect {
+        let (target_width, target_height) = (target_width as f32, target_height as f32);
+        let min_x = (self.x * target_width).round().clamp(0.0, target_width);
+        let min_y = (self.y * target_height).round().clamp(0.0, target_height);
+        let max_x = ((self.x + self.width) * target_width).round().clamp(min_x, target_width);
+        let max_y = ((self.y + self.height) * target_height).round().clamp(min_y, target_height);
+        Rect::new(min_x, min_y, max_x, max_y)
+    }
+}
+
+/// What a [`ViewportClear`] pipeline writes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ViewportClearKey {
+    format: wgpu::TextureFormat,
+    color: bool,
+    depth: bool,
+}
+
+/// Pipelines clearing the viewport of a camera that does not cover its whole target. They draw a
+/// triangle over the viewport with the clear color as the blend constant and the far plane as its
+/// depth, since a load op clear always clears the whole attachment
+#[derive(Resource)]
+pub struct ViewportClear {
+    pipeline_layout: wgpu::PipelineLayout,
+    shader_module: wgpu::ShaderModule,
+    pipelines: HashMap<ViewportClearKey, wgpu::RenderPipeline>,
+}
+
+impl ViewportClear {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Viewport Clear Pipeline Layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+
+        let composed = ShaderSource::from_builtin("fathom::viewport_clear")
+            .expect("Built-in viewport clear shader should only import built-in modules")
+            .compose(&[])
+            .expect("Built-in viewport clear shader should be valid");
+        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("fathom::viewport_clear"),
+            source: wgpu::ShaderSource::Wgsl(composed.source.into()),
+        });
+
+        Self {
+            pipeline_layout,
+            shader_module,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// Creates the pipeline clearing the color and/or depth of a viewport into a `format` target,
+    /// unless it already exists
+    pub(crate) fn prepare(&mut self, device: &wgpu::Device, format: wgpu::TextureFormat, color: bool, depth: bool) {
+        let key = ViewportClearKey {
+            format,
+            color,
+            depth,
+        };
+        if !self.pipelines.contains_key(&key) {
+            log::debug!("Creating viewport clear pipeline | format={:?} | color={} | depth={}", format, color, depth);
+            let pipeline = self.create_pipeline(device, key);
+            self.pipelines.insert(key, pipeline);
+        }
+    }
+
+    fn create_pipeline(&self, device: &wgpu::Device, key: ViewportClearKey) -> wgpu::RenderPipeline {
+        let blend_constant = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::Constant,
+            dst_factor: wgpu::BlendFactor::Zero,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let color_targets = [Some(wgpu::ColorTargetState {
+            format: key.format,
+            blend: Some(wgpu::BlendState {
+                color: blend_constant,
+                alpha: blend_constant,
+            }),
+            write_mask: if key.color { wgpu::ColorWrites::ALL } else { wgpu::ColorWrites::empty() },
+        })];
+
+        Pipelines::pipeline_builder(device)
+            .with_label("Viewport Clear Render Pipeline")
+            .with_layout(&self.pipeline_layout)
+            .with_vertex_shader(&self.shader_module)
+            .with_vertex_entry_point("vertex_main")
+            .with_fragment_entry_point("fragment_main")
+            .with_color_state_targets(&color_targets)
+            .with_depth_stencil(wgpu::DepthStencilState {
+                depth_write_enabled: key.depth,
+                depth_compare: wgpu::CompareFunction::Always,
+                ..DepthTexture::depth_stencil_state()
+            })
+            .build()
+    }
+
+    /// Clears the viewport `render_pass` is set to, to `color` if it is given and to the far plane
+    /// if `depth` is set. The pipeline must have been created with [`ViewportClear::prepare`]
+    pub(crate) fn draw(&self, render_pass: &mut wgpu::RenderPass, format: wgpu::TextureFormat, color: Option<wgpu::Color>, depth: bool) {
+        let key = ViewportClearKey {
+            format,
+            color: color.is_some(),
+            depth,
+        };
+        let Some(pipeline) = self.pipelines.get(&key) else {
+            return;
+        };
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_blend_constant(color.unwrap_or(wgpu::Color::TRANSPARENT));
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,265 @@
+//! Golden image tests render a scene headless and compare the frame against a reference PNG, so
+//! changes to the renderer's output are caught by `cargo test`
+//!
+//! ```no_run
+//! use fathom::testing::{GoldenImageError, GoldenImageTest};
+//!
+//! GoldenImageTest::new_3d("empty_scene")
+//!     .with_frames(3)
+//!     .run(|_app| {
+//!         // Add the systems that spawn the scene
+//!     })
+//!     .or_else(GoldenImageError::skip_without_adapter)
+//!     .unwrap();
+//! ```
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use bevy::prelude::*;
+use thiserror::Error;
+use crate::app::{schedule, FathomApplication, HeadlessSettings};
+use crate::renderer::screenshot::{Screenshot, ScreenshotError, ScreenshotImage};
+use crate::renderer::RendererState;
+
+/// Directory the reference images are read from, relative to the working directory. Cargo runs
+/// tests from the package root
+pub const DEFAULT_REFERENCE_DIRECTORY: &str = "tests/golden_images";
+/// Set this environment variable to overwrite the reference images with what is rendered, e.g.
+/// after an intended change to the renderer's output
+pub const UPDATE_REFERENCES_ENV_VAR: &str = "FATHOM_UPDATE_GOLDEN_IMAGES";
+
+#[derive(Debug, Error)]
+pub enum GoldenImageError {
+    /// Neither a GPU nor a software adapter is available, so nothing could be rendered. Tests
+    /// usually skip instead of failing, see [`GoldenImageError::skip_without_adapter`]
+    #[error("No wgpu adapter is available to render with")]
+    NoAdapter,
+    #[error("No frame was captured, the app exited before rendering {0} frames")]
+    NotCaptured(u32),
+    #[error("Unable to read the reference image {0:?}: {1}")]
+    ReadReference(PathBuf, ::image::ImageError),
+    #[error("Unable to create the directory {0:?}: {1}")]
+    CreateDirectory(PathBuf, std::io::Error),
+    #[error("Unable to write {0:?}: {1}")]
+    Write(PathBuf, ScreenshotError),
+    #[error("The reference image {0:?} does not exist. Run the test with FATHOM_UPDATE_GOLDEN_IMAGES=1 to create it from the rendered frame, then review it and commit it")]
+    MissingReference(PathBuf),
+    #[error("The frame is {actual_width}x{actual_height} but the reference is {reference_width}x{reference_height}")]
+    SizeMismatch {
+        actual_width: u32,
+        actual_height: u32,
+        reference_width: u32,
+        reference_height: u32,
+    },
+    #[error("{differing_pixels} pixels differ from the reference by more than {tolerance}. See {actual:?} and {diff:?}")]
+    Mismatch {
+        differing_pixels: usize,
+        tolerance: u8,
+        actual: PathBuf,
+        diff: PathBuf,
+    },
+}
+
+impl GoldenImageError {
+    /// Turns [`GoldenImageError::NoAdapter`] into a pass, logging that the test was skipped, and
+    /// keeps every other error
+    pub fn skip_without_adapter(self) -> Result<(), Self> {
+        match self {
+            Self::NoAdapter => {
+                log::warn!("Skipping golden image test, no wgpu adapter is available");
+                Ok(())
+            }
+            error => Err(error),
+        }
+    }
+}
+
+enum TestRenderer {
+    Renderer3D,
+    Renderer2D,
+}
+
+/// Renders a headless app for a number of frames, captures the last one and compares it against
+/// `<reference directory>/<name>.png`. A missing reference fails the test unless
+/// [`UPDATE_REFERENCES_ENV_VAR`] is set, in which case it is written. On a mismatch the captured
+/// frame and an image highlighting the differing pixels in red are written to the `output`
+/// subdirectory of the reference directory
+pub struct GoldenImageTest {
+    name: String,
+    renderer: TestRenderer,
+    width: u32,
+    height: u32,
+    frames: u32,
+    tolerance: u8,
+    max_differing_pixels: usize,
+    reference_directory: PathBuf,
+}
+
+impl GoldenImageTest {
+    pub fn new_3d(name: impl Into<String>) -> Self {
+        Self::new(name.into(), TestRenderer::Renderer3D)
+    }
+
+    pub fn new_2d(name: impl Into<String>) -> Self {
+        Self::new(name.into(), TestRenderer::Renderer2D)
+    }
+
+    fn new(name: String, renderer: TestRenderer) -> Self {
+        Self {
+            name,
+            renderer,
+            width: 256,
+            height: 256,
+            // Shaders and meshes are loaded asynchronously, so the first frames may not draw anything
+            frames: 5,
+            tolerance: 2,
+            max_differing_pixels: 0,
+            reference_directory: PathBuf::from(DEFAULT_REFERENCE_DIRECTORY),
+        }
+    }
+
+    pub fn with_size(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Number of frames rendered before the last one is captured
+    pub fn with_frames(mut self, frames: u32) -> Self {
+        self.frames = frames.max(1);
+        self
+    }
+
+    /// Largest difference of any channel of a pixel, out of 255, for it to still match. Software
+    /// and GPU adapters do not rasterize identically, so some tolerance is usually needed
+    pub fn with_tolerance(mut self, tolerance: u8) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Number of pixels that may differ by more than the tolerance before the test fails
+    pub fn with_max_differing_pixels(mut self, max_differing_pixels: usize) -> Self {
+        self.max_differing_pixels = max_differing_pixels;
+        self
+    }
+
+    pub fn with_reference_directory(mut self, reference_directory: impl Into<PathBuf>) -> Self {
+        self.reference_directory = reference_directory.into();
+        self
+    }
+
+    /// Builds the app, lets `setup` add the scene to it, renders it and compares the last frame
+    /// against the reference image. Returns [`GoldenImageError::NoAdapter`] when there is no
+    /// adapter to render with
+    pub fn run(self, setup: impl FnOnce(&mut App)) -> Result<(), GoldenImageError> {
+        let image = self.render(setup)?;
+        self.compare(&image)
+    }
+
+    fn render(&self, setup: impl FnOnce(&mut App)) -> Result<ScreenshotImage, GoldenImageError> {
+        let settings = HeadlessSettings {
+            width: self.width,
+            height: self.height,
+            frame_count: Some(self.frames),
+            ..default()
+        };
+        let mut app = match self.renderer {
+            TestRenderer::Renderer3D => FathomApplication::headless_with_3d_renderer(settings),
+            TestRenderer::Renderer2D => FathomApplication::headless_with_2d_renderer(settings),
+        };
+        setup(&mut app);
+
+        // The renderer is only missing after initialization when no adapter could be created
+        let has_renderer = Arc::new(AtomicBool::new(false));
+        let has_renderer_in_app = has_renderer.clone();
+        app.add_systems(schedule::Startup, move |renderer_state: Option<Res<RendererState>>| {
+            has_renderer_in_app.store(renderer_state.is_some(), Ordering::Relaxed);
+        });
+
+        let captured = Arc::new(Mutex::new(None));
+        let captured_in_app = captured.clone();
+        let last_frame = self.frames - 1;
+        app.add_systems(schedule::Update, move |mut frame: Local<u32>, mut screenshots: EventWriter<Screenshot>| {
+            if *frame == last_frame {
+                let captured = captured_in_app.clone();
+                screenshots.send(Screenshot::with_callback(move |image| {
+                    *captured.lock().unwrap() = Some(image);
+                }));
+            }
+            *frame += 1;
+        });
+        let _ = app.run();
+        if !has_renderer.load(Ordering::Relaxed) {
+            return Err(GoldenImageError::NoAdapter);
+        }
+
+        let image = captured.lock().unwrap().take();
+        image.ok_or(GoldenImageError::NotCaptured(self.frames))
+    }
+
+    fn compare(&self, actual: &ScreenshotImage) -> Result<(), GoldenImageError> {
+        let reference_path = self.reference_directory.join(format!("{}.png", self.name));
+        if std::env::var_os(UPDATE_REFERENCES_ENV_VAR).is_some() {
+            log::info!("Updating the reference image {:?}", reference_path);
+            return save(actual, &reference_path);
+        }
+        if !reference_path.exists() {
+            return Err(GoldenImageError::MissingReference(reference_path));
+        }
+
+        let reference = ::image::open(&reference_path)
+            .map_err(|error| GoldenImageError::ReadReference(reference_path.clone(), error))?
+            .into_rgba8();
+        if reference.dimensions() != (actual.width, actual.height) {
+            return Err(GoldenImageError::SizeMismatch {
+                actual_width: actual.width,
+                actual_height: actual.height,
+                reference_width: reference.width(),
+                reference_height: reference.height(),
+            });
+        }
+
+        let mut diff = Vec::with_capacity(actual.data.len());
+        let mut differing_pixels = 0;
+        for (actual_pixel, reference_pixel) in actual.data.chunks_exact(4).zip(reference.as_raw().chunks_exact(4)) {
+            let differs = actual_pixel.iter().zip(reference_pixel)
+                .any(|(actual, reference)| actual.abs_diff(*reference) > self.tolerance);
+            if differs {
+                differing_pixels += 1;
+                diff.extend_from_slice(&[255, 0, 0, 255]);
+            } else {
+                // Matching pixels are dimmed so the differing ones stand out
+                diff.extend(actual_pixel[..3].iter().map(|channel| channel / 4));
+                diff.push(255);
+            }
+        }
+        if differing_pixels <= self.max_differing_pixels {
+            return Ok(());
+        }
+
+        let output_directory = self.reference_directory.join("output");
+        let actual_path = output_directory.join(format!("{}.actual.png", self.name));
+        let diff_path = output_directory.join(format!("{}.diff.png", self.name));
+        save(actual, &actual_path)?;
+        let diff = ScreenshotImage {
+            width: actual.width,
+            height: actual.height,
+            data: diff,
+        };
+        save(&diff, &diff_path)?;
+        Err(GoldenImageError::Mismatch {
+            differing_pixels,
+            tolerance: self.tolerance,
+            actual: actual_path,
+            diff: diff_path,
+        })
+    }
+}
+
+fn save(image: &ScreenshotImage, path: &Path) -> Result<(), GoldenImageError> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)
+            .map_err(|error| GoldenImageError::CreateDirectory(directory.to_path_buf(), error))?;
+    }
+    image.save_png(path).map_err(|error| GoldenImageError::Write(path.to_path_buf(), error))
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+use bevy::prelude::{ResMut, Resource, World};
+use crate::app::schedule;
+
+const DEFAULT_FIXED_TIMESTEP: Duration = Duration::from_micros(15625);
+const DEFAULT_MAX_FIXED_ACCUMULATION: Duration = Duration::from_millis(250);
+
+/// Frame timing, updated once at the start of every frame in the First schedule
+#[derive(Resource, Debug)]
+pub struct Time {
+    startup: Instant,
+    last_update: Option<Instant>,
+    delta: Duration,
+    elapsed: Duration,
+    frame_count: u64,
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Self {
+            startup: Instant::now(),
+            last_update: None,
+            delta: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            frame_count: 0,
+        }
+    }
+}
+
+impl Time {
+    /// Advances the clock to `now`. The first update has a delta of zero
+    pub fn update_with_instant(&mut self, now: Instant) {
+        self.delta = match self.last_update {
+            Some(last_update) => now.saturating_duration_since(last_update),
+            None => Duration::ZERO,
+        };
+        self.last_update = Some(now);
+        self.elapsed = now.saturating_duration_since(self.startup);
+        self.frame_count += 1;
+    }
+
+    /// Time between the start of the previous frame and the start of this one. While FixedUpdate
+    /// runs it is the fixed timestep instead, so fixed systems can use it like any other system
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    pub fn delta_secs(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+
+    /// Time since the application started
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn elapsed_secs(&self) -> f32 {
+        self.elapsed.as_secs_f32()
+    }
+
+    /// Number of frames that have been started, including the current one
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+}
+
+/// Controls how often the FixedUpdate schedule runs. Frame time is accumulated and FixedUpdate is
+/// run once for every whole `timestep` in the accumulator, so it runs zero or more times per frame
+#[derive(Resource, Debug)]
+pub struct FixedTime {
+    timestep: Duration,
+    accumulated: Duration,
+    /// Caps how much time can be accumulated so a long frame can not cause FixedUpdate to run
+    /// so many times that the next frame is even longer. A cap below the timestep is raised to it,
+    /// otherwise FixedUpdate could never run
+    pub max_accumulation: Duration,
+}
+
+impl Default for FixedTime {
+    fn default() -> Self {
+        Self::new(DEFAULT_FIXED_TIMESTEP)
+    }
+}
+
+impl FixedTime {
+    pub fn new(timestep: Duration) -> Self {
+        assert!(!timestep.is_zero(), "FixedTime timestep must be greater than zero");
+        Self {
+            timestep,
+            accumulated: Duration::ZERO,
+            max_accumulation: DEFAULT_MAX_FIXED_ACCUMULATION,
+        }
+    }
+
+    pub fn from_hz(hz: f64) -> Self {
+        assert!(hz > 0.0 && hz.is_finite(), "FixedTime rate must be a positive, finite number of hertz, got {}", hz);
+        Self::new(Duration::from_secs_f64(1.0 / hz))
+    }
+
+    pub fn timestep(&self) -> Duration {
+        self.timestep
+    }
+
+    pub fn timestep_secs(&self) -> f32 {
+        self.timestep.as_secs_f32()
+    }
+
+    pub fn set_timestep(&mut self, timestep: Duration) {
+        assert!(!timestep.is_zero(), "FixedTime timestep must be greater than zero");
+        self.timestep = timestep;
+    }
+
+    /// How far between the last and the next fixed step the current frame is, from 0.0 to 1.0.
+    /// Useful for interpolating rendered positions between fixed updates
+    pub fn overstep_fraction(&self) -> f32 {
+        self.accumulated.as_secs_f32() / self.timestep.as_secs_f32()
+    }
+
+    pub fn accumulate(&mut self, delta: Duration) {
+        self.accumulated = (self.accumulated + delta).min(self.max_accumulation.max(self.timestep));
+    }
+
+    /// Consumes one timestep from the accumulator, returning false if there is not enough time left
+    pub fn expend(&mut self) -> bool {
+        if self.accumulated >= self.timestep {
+            self.accumulated -= self.timestep;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub fn update_time(mut time: ResMut<Time>) {
+    time.update_with_instant(Instant::now());
+}
+
+/// Runs the FixedUpdate schedule once for each timestep accumulated since the last frame. The
+/// [`Time`] delta is swapped for the timestep while it runs and restored afterwards
+pub fn run_fixed_main(world: &mut World) {
+    let delta = world.resource::<Time>().delta();
+    world.resource_mut::<FixedTime>().accumulate(delta);
+
+    while world.resource_mut::<FixedTime>().expend() {
+        let timestep = world.resource::<FixedTime>().timestep();
+        world.resource_mut::<Time>().delta = timestep;
+        let _ = world.run_schedule(schedule::FixedUpdate);
+    }
+    world.resource_mut::<Time>().delta = delta;
+}
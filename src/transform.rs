@@ -0,0 +1,210 @@
+use bevy::hierarchy::{Children, Parent};
+use bevy::math::{Mat3, Mat4, Quat, Vec3};
+use bevy::prelude::{Component, Entity, Or, Query, With, Without};
+
+/// The position, rotation and scale of an entity relative to its parent, or to the world if it has
+/// no parent. The resulting world space transform is written to [`GlobalTransform`] in PreRender
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+#[require(GlobalTransform)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Transform {
+    pub const IDENTITY: Self = Self {
+        translation: Vec3::ZERO,
+        rotation: Quat::IDENTITY,
+        scale: Vec3::ONE,
+    };
+
+    pub fn from_xyz(x: f32, y: f32, z: f32) -> Self {
+        Self::from_translation(Vec3::new(x, y, z))
+    }
+
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self {
+            translation,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn from_rotation(rotation: Quat) -> Self {
+        Self {
+            rotation,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn from_scale(scale: Vec3) -> Self {
+        Self {
+            scale,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn from_matrix(matrix: Mat4) -> Self {
+        let (scale, rotation, translation) = matrix.to_scale_rotation_translation();
+        Self {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    pub fn with_translation(mut self, translation: Vec3) -> Self {
+        self.translation = translation;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Quat) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: Vec3) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Rotates this transform so its forward direction (-Z) points at `target`
+    pub fn looking_at(mut self, target: Vec3, up: Vec3) -> Self {
+        self.look_at(target, up);
+        self
+    }
+
+    pub fn look_at(&mut self, target: Vec3, up: Vec3) {
+        let view = Mat4::look_at_rh(self.translation, target, up);
+        self.rotation = Quat::from_mat4(&view.inverse());
+    }
+
+    pub fn compute_matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+
+    /// The local -Z direction
+    pub fn forward(&self) -> Vec3 {
+        self.rotation * Vec3::NEG_Z
+    }
+
+    /// The local +X direction
+    pub fn right(&self) -> Vec3 {
+        self.rotation * Vec3::X
+    }
+
+    /// The local +Y direction
+    pub fn up(&self) -> Vec3 {
+        self.rotation * Vec3::Y
+    }
+
+    pub fn translate(&mut self, offset: Vec3) {
+        self.translation += offset;
+    }
+
+    pub fn rotate(&mut self, rotation: Quat) {
+        self.rotation = rotation * self.rotation;
+    }
+}
+
+/// The world space transform of an entity. This is computed from the [`Transform`] of the entity
+/// and all of its ancestors by [`propagate_transforms`] and should not be modified directly
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct GlobalTransform(Mat4);
+
+impl Default for GlobalTransform {
+    fn default() -> Self {
+        Self(Mat4::IDENTITY)
+    }
+}
+
+impl GlobalTransform {
+    pub fn matrix(&self) -> Mat4 {
+        self.0
+    }
+
+    pub fn translation(&self) -> Vec3 {
+        self.0.w_axis.truncate()
+    }
+
+    /// The world space -Z direction
+    pub fn forward(&self) -> Vec3 {
+        self.0.transform_vector3(Vec3::NEG_Z).normalize_or_zero()
+    }
+
+    /// The matrix used to transform normals into world space. Unlike the model matrix it stays
+    /// correct when the transform has non-uniform scale
+    pub fn normal_matrix(&self) -> Mat3 {
+        Mat3::from_mat4(self.0).inverse().transpose()
+    }
+}
+
+/// Computes the [`GlobalTransform`] of every entity from its [`Transform`] and its parent's
+/// [`GlobalTransform`], starting from the entities that have no parent. An ancestor without a
+/// [`Transform`] counts as the identity, so its descendants are still updated
+pub fn propagate_transforms(
+    root_entities: Query<Entity, (Or<(With<Transform>, With<Children>)>, Without<Parent>)>,
+    transforms: Query<(Option<&Transform>, Option<&Children>)>,
+    mut global_transforms: Query<&mut GlobalTransform>,
+) {
+    for root_entity in &root_entities {
+        propagate_recursive(root_entity, Mat4::IDENTITY, &transforms, &mut global_transforms);
+    }
+}
+
+fn propagate_recursive(
+    entity: Entity,
+    parent_matrix: Mat4,
+    transforms: &Query<(Option<&Transform>, Option<&Children>)>,
+    global_transforms: &mut Query<&mut GlobalTransform>,
+) {
+    let Ok((transform, children)) = transforms.get(entity) else {
+        return;
+    };
+
+    let matrix = match transform {
+        Some(transform) => parent_matrix * transform.compute_matrix(),
+        None => parent_matrix,
+    };
+    if let Ok(mut global_transform) = global_transforms.get_mut(entity) {
+        // Only write when the value changes so change detection on GlobalTransform stays useful
+        if global_transform.0 != matrix {
+            global_transform.0 = matrix;
+        }
+    }
+
+    if let Some(children) = children {
+        for &child in children.iter() {
+            propagate_recursive(child, matrix, transforms, global_transforms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::schedule::Schedule;
+    use bevy::ecs::world::World;
+    use bevy::hierarchy::BuildChildren;
+
+    use super::*;
+
+    #[test]
+    fn propagates_through_parent_without_transform() {
+        let mut world = World::new();
+        let child = world.spawn(Transform::from_xyz(1.0, 2.0, 3.0)).id();
+        world.spawn_empty().add_child(child);
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(propagate_transforms);
+        schedule.run(&mut world);
+
+        let global_transform = world.get::<GlobalTransform>(child).unwrap();
+        assert_eq!(global_transform.translation(), Vec3::new(1.0, 2.0, 3.0));
+    }
+}
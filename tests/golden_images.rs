@@ -0,0 +1,53 @@
+//! Renders small scenes headless and compares them against the reference images in
+//! `tests/golden_images`. Run with `FATHOM_UPDATE_GOLDEN_IMAGES=1` to regenerate the references
+//! after an intended change to the renderer's output. They are skipped when there is no adapter to
+//! render with
+use bevy::prelude::*;
+use fathom::app::schedule;
+use fathom::renderer::camera::Camera;
+use fathom::renderer::mesh::{Mesh, Mesh2D};
+use fathom::renderer::vertex::{Vertex, Vertex2D};
+use fathom::testing::{GoldenImageError, GoldenImageTest};
+use fathom::transform::Transform;
+
+#[test]
+fn default_3d_render_pass() {
+    GoldenImageTest::new_3d("default_3d_render_pass")
+        .run(|app| {
+            app.add_systems(schedule::Startup, |mut commands: Commands| {
+                commands.spawn(Mesh::with_indices(
+                    vec![
+                        Vertex { position: [-1.0, -1.0, 0.0], color: [1.0, 0.0, 0.0] },
+                        Vertex { position: [ 1.0, -1.0, 0.0], color: [0.0, 1.0, 0.0] },
+                        Vertex { position: [ 1.0,  1.0, 0.0], color: [0.0, 0.0, 1.0] },
+                        Vertex { position: [-1.0,  1.0, 0.0], color: [1.0, 1.0, 1.0] },
+                    ],
+                    vec![0, 1, 2, 2, 3, 0],
+                ));
+                commands.spawn((
+                    Camera::default(),
+                    Transform::from_xyz(0.0, 0.0, 4.0).looking_at(Vec3::ZERO, Vec3::Y),
+                ));
+            });
+        })
+        .or_else(GoldenImageError::skip_without_adapter)
+        .unwrap();
+}
+
+#[test]
+fn render2d() {
+    GoldenImageTest::new_2d("render2d")
+        .run(|app| {
+            app.add_systems(schedule::Startup, |mut commands: Commands| {
+                commands.spawn(Mesh2D::new(
+                    vec![
+                        Vertex2D { position: [0.0, 0.5], color: [1.0, 0.0, 0.0] },
+                        Vertex2D { position: [-0.5, -0.5], color: [0.0, 1.0, 0.0] },
+                        Vertex2D { position: [0.5, -0.5], color: [0.0, 0.0, 1.0] },
+                    ],
+                ));
+            });
+        })
+        .or_else(GoldenImageError::skip_without_adapter)
+        .unwrap();
+}